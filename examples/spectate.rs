@@ -0,0 +1,49 @@
+// Read-only spectator client, prints the events streamed by a game with
+// "Allow local spectators" enabled:
+//
+//   cargo run --example spectate [port]
+
+use std::io::{BufRead, BufReader};
+use std::net::TcpStream;
+use serde_json::Value;
+
+fn describe(event: &Value) -> String {
+	match event["event"].as_str() {
+		Some("level_start") => {
+			let tubes = event["tubes"].as_array().map(|t| t.len()).unwrap_or(0);
+			format!("Level {} started with {} tubes", event["level"], tubes)
+		},
+		Some("pour") => format!(
+			"Player {} poured {} from tube {} to tube {} ({:.0}% completed)",
+			event["player"].as_u64().unwrap_or(0) + 1,
+			event["amount"],
+			event["from"].as_u64().unwrap_or(0) + 1,
+			event["to"].as_u64().unwrap_or(0) + 1,
+			event["complete_pct"].as_f64().unwrap_or(0.0) * 100.0,
+		),
+		Some("undo") => format!("Player {} undid a move", event["player"].as_u64().unwrap_or(0) + 1),
+		Some("level_complete") => format!(
+			"Player {} completed level {} in {} moves and {:.1} s",
+			event["player"].as_u64().unwrap_or(0) + 1,
+			event["level"],
+			event["moves"],
+			event["time"].as_f64().unwrap_or(0.0),
+		),
+		_ => format!("Unknown event: {}", event),
+	}
+}
+
+fn main() -> std::io::Result<()> {
+	let port = std::env::args().nth(1).and_then(|p| p.parse::<u16>().ok()).unwrap_or(7879);
+	let stream = TcpStream::connect(("127.0.0.1", port))?;
+	println!("Spectating on port {}", port);
+	for line in BufReader::new(stream).lines() {
+		let line = line?;
+		match serde_json::from_str::<Value>(&line) {
+			Ok(event) => println!("{}", describe(&event)),
+			Err(e) => eprintln!("Invalid event {:?}: {}", line, e),
+		}
+	}
+	println!("Game closed the connection");
+	Ok(())
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use ggez::{Context, GameResult};
+use ggez::audio::{SoundData, SoundSource, Source};
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+
+// Sound effects are synthesized at startup, so no audio files are needed
+const SAMPLE_RATE: u32 = 22050;
+
+// Length of the pour sound per unit of liquid, in seconds
+const POUR_UNIT_DURATION: f32 = 0.15;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sound {
+	// Amount poured and the fill level of the target tube afterwards,
+	// from 0.0 (empty) to 1.0 (full)
+	Pour { amount: f32, level: f32 },
+	Error,
+	TubeCompleted,
+	LevelCompleted,
+}
+
+// Encodes mono samples in -1.0 .. 1.0 as a 16-bit PCM WAV file
+fn wav(samples: &[f32]) -> Vec<u8> {
+	let data_len = samples.len() as u32 * 2;
+	let mut bytes = Vec::with_capacity(44 + data_len as usize);
+	bytes.extend_from_slice(b"RIFF");
+	bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+	bytes.extend_from_slice(b"WAVEfmt ");
+	bytes.extend_from_slice(&16u32.to_le_bytes());
+	bytes.extend_from_slice(&1u16.to_le_bytes()); // PCM
+	bytes.extend_from_slice(&1u16.to_le_bytes()); // mono
+	bytes.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+	bytes.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+	bytes.extend_from_slice(&2u16.to_le_bytes());
+	bytes.extend_from_slice(&16u16.to_le_bytes());
+	bytes.extend_from_slice(b"data");
+	bytes.extend_from_slice(&data_len.to_le_bytes());
+	for sample in samples {
+		bytes.extend_from_slice(&((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes());
+	}
+	bytes
+}
+
+// Sine tone with a short attack and an exponential decay
+fn tone(freq: f32, duration: f32, volume: f32) -> Vec<f32> {
+	let count = (duration * SAMPLE_RATE as f32) as usize;
+	(0..count).map(|i| {
+		let t = i as f32 / SAMPLE_RATE as f32;
+		let envelope = (t / 0.005).min(1.0) * (-4.0 * t / duration).exp();
+		(2.0 * PI * freq * t).sin() * envelope * volume
+	}).collect()
+}
+
+fn mix_at(samples: &mut Vec<f32>, other: &[f32], offset: f32) {
+	let start = (offset * SAMPLE_RATE as f32) as usize;
+	if samples.len() < start + other.len() {
+		samples.resize(start + other.len(), 0.0);
+	}
+	for (i, sample) in other.iter().enumerate() {
+		samples[start + i] += sample;
+	}
+}
+
+// A glug for every unit of liquid, each a falling tone with some noise
+fn pour_samples(amount: f32) -> Vec<f32> {
+	let mut rng = SmallRng::seed_from_u64(0);
+	let duration = (amount * POUR_UNIT_DURATION).max(0.08);
+	let count = (duration * SAMPLE_RATE as f32) as usize;
+	let glug_len = POUR_UNIT_DURATION / 2.0;
+	let mut phase = 0.0;
+	(0..count).map(|i| {
+		let t = i as f32 / SAMPLE_RATE as f32;
+		let glug_t = t % glug_len;
+		let freq = 520.0 - 240.0 * glug_t / glug_len;
+		phase += 2.0 * PI * freq / SAMPLE_RATE as f32;
+		let envelope = (glug_t / 0.004).min(1.0) * (1.0 - glug_t / glug_len) * (1.0 - t / duration).max(0.0).sqrt();
+		(phase.sin() * 0.8 + rng.gen_range(-0.2..0.2)) * envelope * 0.6
+	}).collect()
+}
+
+fn error_samples() -> Vec<f32> {
+	let mut samples = Vec::new();
+	for &offset in &[0.0, 0.12] {
+		let buzz: Vec<f32> = tone(140.0, 0.1, 0.5)
+			.into_iter()
+			.map(|s| s.signum() * s.abs().sqrt() * 0.4)
+			.collect();
+		mix_at(&mut samples, &buzz, offset);
+	}
+	samples
+}
+
+fn chime_samples(notes: &[f32], spacing: f32, note_duration: f32) -> Vec<f32> {
+	let mut samples = Vec::new();
+	for (i, &freq) in notes.iter().enumerate() {
+		mix_at(&mut samples, &tone(freq, note_duration, 0.35), i as f32 * spacing);
+		mix_at(&mut samples, &tone(freq * 2.0, note_duration, 0.1), i as f32 * spacing);
+	}
+	samples
+}
+
+pub struct Audio {
+	queue: Vec<Sound>,
+	// Pour sounds by amount in quarter units
+	pour: HashMap<u32, SoundData>,
+	error: SoundData,
+	tube_completed: SoundData,
+	level_completed: SoundData,
+}
+
+impl Audio {
+	pub fn new() -> Self {
+		Self {
+			queue: Vec::new(),
+			pour: HashMap::new(),
+			error: SoundData::from_bytes(&wav(&error_samples())),
+			tube_completed: SoundData::from_bytes(&wav(&chime_samples(&[1046.5, 1318.5], 0.09, 0.4))),
+			level_completed: SoundData::from_bytes(&wav(&chime_samples(&[523.3, 659.3, 784.0, 1046.5], 0.12, 0.6))),
+		}
+	}
+
+	// Queues a sound to be played on the next call to play_queued
+	pub fn play(&mut self, sound: Sound) {
+		self.queue.push(sound);
+	}
+
+	// Sound is optional, so errors from the audio device are ignored
+	pub fn play_queued(&mut self, ctx: &mut Context, volume: f32) {
+		let queue: Vec<Sound> = self.queue.drain(..).collect();
+		if volume <= 0.0 {
+			return;
+		}
+		for sound in queue {
+			let _ = self.play_now(ctx, sound, volume);
+		}
+	}
+
+	fn play_now(&mut self, ctx: &mut Context, sound: Sound, volume: f32) -> GameResult {
+		let (data, pitch) = match sound {
+			Sound::Pour { amount, level } => {
+				let quarters = (amount * 4.0).round().max(1.0) as u32;
+				let data = self.pour
+					.entry(quarters)
+					.or_insert_with(|| SoundData::from_bytes(&wav(&pour_samples(quarters as f32 / 4.0))))
+					.clone();
+				// Pitch rises as the tube fills up
+				(data, 0.8 + level.clamp(0.0, 1.0) * 0.6)
+			},
+			Sound::Error => (self.error.clone(), 1.0),
+			Sound::TubeCompleted => (self.tube_completed.clone(), 1.0),
+			Sound::LevelCompleted => (self.level_completed.clone(), 1.0),
+		};
+		let mut source = Source::from_data(ctx, data)?;
+		source.set_volume(volume);
+		source.set_pitch(pitch);
+		source.play_detached()
+	}
+}
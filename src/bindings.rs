@@ -0,0 +1,323 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use ggez::event::KeyCode;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+pub const TUBE_KEY_COLS: usize = 7;
+pub const TUBE_KEY_ROWS: usize = 4;
+
+// Keys which can be saved in the settings, only these can be bound
+const KEY_NAMES: [(KeyCode, &str); 92] = [
+	(KeyCode::Key1, "1"), (KeyCode::Key2, "2"), (KeyCode::Key3, "3"), (KeyCode::Key4, "4"), (KeyCode::Key5, "5"),
+	(KeyCode::Key6, "6"), (KeyCode::Key7, "7"), (KeyCode::Key8, "8"), (KeyCode::Key9, "9"), (KeyCode::Key0, "0"),
+	(KeyCode::A, "A"), (KeyCode::B, "B"), (KeyCode::C, "C"), (KeyCode::D, "D"), (KeyCode::E, "E"),
+	(KeyCode::F, "F"), (KeyCode::G, "G"), (KeyCode::H, "H"), (KeyCode::I, "I"), (KeyCode::J, "J"),
+	(KeyCode::K, "K"), (KeyCode::L, "L"), (KeyCode::M, "M"), (KeyCode::N, "N"), (KeyCode::O, "O"),
+	(KeyCode::P, "P"), (KeyCode::Q, "Q"), (KeyCode::R, "R"), (KeyCode::S, "S"), (KeyCode::T, "T"),
+	(KeyCode::U, "U"), (KeyCode::V, "V"), (KeyCode::W, "W"), (KeyCode::X, "X"), (KeyCode::Y, "Y"),
+	(KeyCode::Z, "Z"),
+	(KeyCode::Apostrophe, "'"), (KeyCode::Comma, ","), (KeyCode::Period, "."), (KeyCode::Semicolon, ";"),
+	(KeyCode::Slash, "/"), (KeyCode::Backslash, "\\"), (KeyCode::Minus, "-"), (KeyCode::Equals, "="),
+	(KeyCode::LBracket, "["), (KeyCode::RBracket, "]"), (KeyCode::Grave, "`"),
+	(KeyCode::F1, "F1"), (KeyCode::F2, "F2"), (KeyCode::F3, "F3"), (KeyCode::F4, "F4"), (KeyCode::F5, "F5"),
+	(KeyCode::F6, "F6"), (KeyCode::F7, "F7"), (KeyCode::F8, "F8"), (KeyCode::F9, "F9"), (KeyCode::F10, "F10"),
+	(KeyCode::F11, "F11"), (KeyCode::F12, "F12"),
+	(KeyCode::Space, "Space"), (KeyCode::Return, "Enter"), (KeyCode::Tab, "Tab"), (KeyCode::Back, "Backspace"),
+	(KeyCode::Escape, "Esc"), (KeyCode::Insert, "Insert"), (KeyCode::Delete, "Delete"),
+	(KeyCode::Left, "Left"), (KeyCode::Right, "Right"), (KeyCode::Up, "Up"), (KeyCode::Down, "Down"),
+	(KeyCode::Home, "Home"), (KeyCode::End, "End"), (KeyCode::PageUp, "PageUp"), (KeyCode::PageDown, "PageDown"),
+	(KeyCode::Numpad1, "Num1"), (KeyCode::Numpad2, "Num2"), (KeyCode::Numpad3, "Num3"), (KeyCode::Numpad4, "Num4"), (KeyCode::Numpad5, "Num5"),
+	(KeyCode::Numpad6, "Num6"), (KeyCode::Numpad7, "Num7"), (KeyCode::Numpad8, "Num8"), (KeyCode::Numpad9, "Num9"), (KeyCode::Numpad0, "Num0"),
+	(KeyCode::Add, "Num+"), (KeyCode::Subtract, "Num-"), (KeyCode::Multiply, "Num*"), (KeyCode::Divide, "Num/"),
+	(KeyCode::Decimal, "Num."), (KeyCode::NumpadComma, "Num,"), (KeyCode::NumpadEquals, "Num="), (KeyCode::NumpadEnter, "NumEnter"),
+];
+
+pub fn key_name(keycode: KeyCode) -> &'static str {
+	KEY_NAMES.iter().find(|&&(k, _)| k == keycode).map(|&(_, name)| name).unwrap_or("")
+}
+
+fn key_by_name(name: &str) -> Option<KeyCode> {
+	KEY_NAMES.iter().find(|&&(_, n)| n.eq_ignore_ascii_case(name)).map(|&(k, _)| k)
+}
+
+pub fn is_bindable_key(keycode: KeyCode) -> bool {
+	KEY_NAMES.iter().any(|&(k, _)| k == keycode)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyboardLayout {
+	Qwerty,
+	Azerty,
+	Qwertz,
+	Dvorak,
+}
+
+pub const KEYBOARD_LAYOUTS: [KeyboardLayout; 4] = [
+	KeyboardLayout::Qwerty,
+	KeyboardLayout::Azerty,
+	KeyboardLayout::Qwertz,
+	KeyboardLayout::Dvorak,
+];
+
+impl KeyboardLayout {
+	pub fn name(&self) -> &'static str {
+		match self {
+			KeyboardLayout::Qwerty => "QWERTY",
+			KeyboardLayout::Azerty => "AZERTY",
+			KeyboardLayout::Qwertz => "QWERTZ",
+			KeyboardLayout::Dvorak => "Dvorak",
+		}
+	}
+
+	fn tube_keys(&self) -> [KeyCode; TUBE_KEY_COLS * TUBE_KEY_ROWS] {
+		match self {
+			KeyboardLayout::Qwerty => [
+				KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4, KeyCode::Key5, KeyCode::Key6, KeyCode::Key7,
+				KeyCode::Q, KeyCode::W, KeyCode::E, KeyCode::R, KeyCode::T, KeyCode::Y, KeyCode::U,
+				KeyCode::A, KeyCode::S, KeyCode::D, KeyCode::F, KeyCode::G, KeyCode::H, KeyCode::J,
+				KeyCode::Z, KeyCode::X, KeyCode::C, KeyCode::V, KeyCode::B, KeyCode::N, KeyCode::M
+			],
+			KeyboardLayout::Azerty => [
+				KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4, KeyCode::Key5, KeyCode::Key6, KeyCode::Key7,
+				KeyCode::A, KeyCode::Z, KeyCode::E, KeyCode::R, KeyCode::T, KeyCode::Y, KeyCode::U,
+				KeyCode::Q, KeyCode::S, KeyCode::D, KeyCode::F, KeyCode::G, KeyCode::H, KeyCode::J,
+				KeyCode::W, KeyCode::X, KeyCode::C, KeyCode::V, KeyCode::B, KeyCode::N, KeyCode::Comma
+			],
+			KeyboardLayout::Qwertz => [
+				KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4, KeyCode::Key5, KeyCode::Key6, KeyCode::Key7,
+				KeyCode::Q, KeyCode::W, KeyCode::E, KeyCode::R, KeyCode::T, KeyCode::Z, KeyCode::U,
+				KeyCode::A, KeyCode::S, KeyCode::D, KeyCode::F, KeyCode::G, KeyCode::H, KeyCode::J,
+				KeyCode::Y, KeyCode::X, KeyCode::C, KeyCode::V, KeyCode::B, KeyCode::N, KeyCode::M
+			],
+			KeyboardLayout::Dvorak => [
+				KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4, KeyCode::Key5, KeyCode::Key6, KeyCode::Key7,
+				KeyCode::Apostrophe, KeyCode::Comma, KeyCode::Period, KeyCode::P, KeyCode::Y, KeyCode::F, KeyCode::G,
+				KeyCode::A, KeyCode::O, KeyCode::E, KeyCode::U, KeyCode::I, KeyCode::D, KeyCode::H,
+				KeyCode::Semicolon, KeyCode::Q, KeyCode::J, KeyCode::K, KeyCode::X, KeyCode::B, KeyCode::M
+			],
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+	Tube(usize),
+	CursorLeft,
+	CursorRight,
+	CursorUp,
+	CursorDown,
+	CursorSelect,
+	CursorSelectAlt,
+	Undo,
+	Quit,
+	RestartLevel,
+	NextLevel,
+	ToggleDebug,
+}
+
+impl Action {
+	pub fn label(&self) -> String {
+		match self {
+			Action::Tube(index) => format!("Tube {}", index + 1),
+			Action::CursorLeft => "Move cursor left".to_string(),
+			Action::CursorRight => "Move cursor right".to_string(),
+			Action::CursorUp => "Move cursor up".to_string(),
+			Action::CursorDown => "Move cursor down".to_string(),
+			Action::CursorSelect => "Select at cursor".to_string(),
+			Action::CursorSelectAlt => "Select at cursor (alt)".to_string(),
+			Action::Undo => "Undo".to_string(),
+			Action::Quit => "Exit game".to_string(),
+			Action::RestartLevel => "Restart level".to_string(),
+			Action::NextLevel => "Next level".to_string(),
+			Action::ToggleDebug => "Toggle debug window".to_string(),
+		}
+	}
+
+	fn from_name(name: &str) -> Option<Self> {
+		match name {
+			"cursor_left" => Some(Action::CursorLeft),
+			"cursor_right" => Some(Action::CursorRight),
+			"cursor_up" => Some(Action::CursorUp),
+			"cursor_down" => Some(Action::CursorDown),
+			"cursor_select" => Some(Action::CursorSelect),
+			"cursor_select_alt" => Some(Action::CursorSelectAlt),
+			"undo" => Some(Action::Undo),
+			"quit" => Some(Action::Quit),
+			"restart_level" => Some(Action::RestartLevel),
+			"next_level" => Some(Action::NextLevel),
+			"toggle_debug" => Some(Action::ToggleDebug),
+			_ if name.starts_with("tube_") => name["tube_".len()..]
+				.parse::<usize>()
+				.ok()
+				.filter(|n| (1..=TUBE_KEY_COLS * TUBE_KEY_ROWS).contains(n))
+				.map(|n| Action::Tube(n - 1)),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for Action {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Action::Tube(index) => write!(f, "tube_{}", index + 1),
+			Action::CursorLeft => write!(f, "cursor_left"),
+			Action::CursorRight => write!(f, "cursor_right"),
+			Action::CursorUp => write!(f, "cursor_up"),
+			Action::CursorDown => write!(f, "cursor_down"),
+			Action::CursorSelect => write!(f, "cursor_select"),
+			Action::CursorSelectAlt => write!(f, "cursor_select_alt"),
+			Action::Undo => write!(f, "undo"),
+			Action::Quit => write!(f, "quit"),
+			Action::RestartLevel => write!(f, "restart_level"),
+			Action::NextLevel => write!(f, "next_level"),
+			Action::ToggleDebug => write!(f, "toggle_debug"),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyBinding {
+	pub keycode: KeyCode,
+	pub ctrl: bool,
+}
+
+impl KeyBinding {
+	pub fn new(keycode: KeyCode, ctrl: bool) -> Self {
+		Self { keycode, ctrl }
+	}
+
+	fn parse(s: &str) -> Option<Self> {
+		let mut ctrl = false;
+		let mut name = s.trim();
+		if name.len() > "Ctrl + ".len() && name.starts_with("Ctrl + ") {
+			ctrl = true;
+			name = &name["Ctrl + ".len()..];
+		}
+		key_by_name(name).map(|keycode| Self::new(keycode, ctrl))
+	}
+}
+
+impl fmt::Display for KeyBinding {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if self.ctrl {
+			write!(f, "Ctrl + ")?;
+		}
+		write!(f, "{}", key_name(self.keycode))
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bindings {
+	keys: Vec<(Action, KeyBinding)>,
+}
+
+impl Bindings {
+	pub fn preset(layout: KeyboardLayout) -> Self {
+		let mut keys: Vec<(Action, KeyBinding)> = layout.tube_keys()
+			.iter()
+			.enumerate()
+			.map(|(i, &keycode)| (Action::Tube(i), KeyBinding::new(keycode, false)))
+			.collect();
+		keys.push((Action::CursorLeft, KeyBinding::new(KeyCode::Left, false)));
+		keys.push((Action::CursorRight, KeyBinding::new(KeyCode::Right, false)));
+		keys.push((Action::CursorUp, KeyBinding::new(KeyCode::Up, false)));
+		keys.push((Action::CursorDown, KeyBinding::new(KeyCode::Down, false)));
+		keys.push((Action::CursorSelect, KeyBinding::new(KeyCode::Space, false)));
+		keys.push((Action::CursorSelectAlt, KeyBinding::new(KeyCode::Return, false)));
+		keys.push((Action::Undo, KeyBinding::new(KeyCode::Z, true)));
+		keys.push((Action::Quit, KeyBinding::new(KeyCode::Q, true)));
+		keys.push((Action::RestartLevel, KeyBinding::new(KeyCode::R, true)));
+		keys.push((Action::NextLevel, KeyBinding::new(KeyCode::N, true)));
+		keys.push((Action::ToggleDebug, KeyBinding::new(KeyCode::F3, false)));
+		Self { keys }
+	}
+
+	// No keys bound, see fill_missing
+	pub fn empty() -> Self {
+		Self { keys: Vec::new() }
+	}
+
+	// Binds every action without a key to its key in the layout's preset
+	pub fn fill_missing(&mut self, layout: KeyboardLayout) {
+		self.keys = Self::preset(layout).keys
+			.into_iter()
+			.map(|(action, binding)| (action, self.get(action).unwrap_or(binding)))
+			.collect();
+	}
+
+	pub fn actions(&self) -> Vec<Action> {
+		self.keys.iter().map(|&(action, _)| action).collect()
+	}
+
+	pub fn get(&self, action: Action) -> Option<KeyBinding> {
+		self.keys.iter().find(|&&(a, _)| a == action).map(|&(_, binding)| binding)
+	}
+
+	pub fn set(&mut self, action: Action, binding: KeyBinding) {
+		if let Some(entry) = self.keys.iter_mut().find(|(a, _)| *a == action) {
+			entry.1 = binding;
+		}
+	}
+
+	pub fn action(&self, binding: KeyBinding) -> Option<Action> {
+		self.keys.iter().find(|&&(_, b)| b == binding).map(|&(action, _)| action)
+	}
+
+	pub fn tube_key(&self, index: usize) -> Option<KeyCode> {
+		self.get(Action::Tube(index)).map(|binding| binding.keycode)
+	}
+
+	// returns every action which shares its binding with another action
+	pub fn conflicts(&self) -> Vec<Action> {
+		self.keys
+			.iter()
+			.filter(|&&(action, binding)| self.keys.iter().any(|&(a, b)| a != action && b == binding))
+			.map(|&(action, _)| action)
+			.collect()
+	}
+}
+
+impl Serialize for Bindings {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		self.keys
+			.iter()
+			.map(|(action, binding)| (action.to_string(), binding.to_string()))
+			.collect::<BTreeMap<String, String>>()
+			.serialize(serializer)
+	}
+}
+
+impl<'de> Deserialize<'de> for Bindings {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		// Actions missing from the file are bound once the keyboard layout is known
+		let mut bindings = Bindings::empty();
+		for (action, binding) in BTreeMap::<String, String>::deserialize(deserializer)? {
+			if let (Some(action), Some(binding)) = (Action::from_name(&action), KeyBinding::parse(&binding)) {
+				bindings.keys.push((action, binding));
+			}
+		}
+		Ok(bindings)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn named_keys_round_trip() {
+		for &(keycode, _) in KEY_NAMES.iter() {
+			for &ctrl in &[false, true] {
+				let binding = KeyBinding::new(keycode, ctrl);
+				assert_eq!(KeyBinding::parse(&binding.to_string()), Some(binding));
+			}
+		}
+	}
+
+	#[test]
+	fn unnamed_keys_can_not_be_bound() {
+		assert!(is_bindable_key(KeyCode::Numpad5));
+		assert!(!is_bindable_key(KeyCode::LShift));
+		assert!(!is_bindable_key(KeyCode::Mute));
+	}
+}
@@ -0,0 +1,231 @@
+use ggez::graphics::Rect;
+use crate::bindings::Action;
+use crate::color_tube::{self, ColorTube, PourError};
+use crate::layout::Layout;
+use crate::rules::Rules;
+use crate::smallest_factor;
+
+// Outcome of selecting a tube
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Activation {
+	Selected(usize),
+	Deselected,
+	Poured { from: usize, to: usize, amount: f32 },
+	// The pour or selection was not allowed, the error is shown on the tube
+	Rejected { tube: usize, error: PourError },
+}
+
+// Decision of a mod's fill rule about a pour
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PourLimit {
+	Unlimited,
+	AtMost(f32),
+	Vetoed,
+}
+
+// Tubes and selection state of one player
+#[derive(Debug, Clone)]
+pub struct Board {
+	pub tube_capacity: f32,
+	pub tubes: Vec<ColorTube>,
+	pub tubes_factor: usize,
+	pub layout: Layout,
+	pub selected_tube: Option<usize>,
+	pub cursor: Option<usize>,
+	pub history: Vec<Vec<ColorTube>>,
+}
+
+impl Board {
+	pub fn new(tube_capacity: f32, tubes: Vec<ColorTube>) -> Self {
+		let mut board = Self {
+			tube_capacity,
+			tubes_factor: smallest_factor(tubes.len()).max(1),
+			layout: Layout::compute(Rect::new(0.0, 0.0, 1.0, 1.0), tubes.len().max(1), tube_capacity, 1, 1.0),
+			tubes,
+			selected_tube: None,
+			cursor: None,
+			history: Vec::new(),
+		};
+		board.update_locks();
+		board
+	}
+
+	// Replaces the tubes, keeping the cursor position
+	pub fn set_tubes(&mut self, tube_capacity: f32, tubes: Vec<ColorTube>) {
+		let cursor = self.cursor;
+		*self = Self::new(tube_capacity, tubes);
+		self.cursor = cursor.map(|i| i.min(self.tubes.len().max(1) - 1));
+	}
+
+	pub fn update_locks(&mut self) {
+		color_tube::update_locks(&mut self.tubes);
+	}
+
+	// returns 0.0 (0%) .. 1.0 (100%)
+	pub fn complete_pct(&self) -> f32 {
+		let mut empty_tubes = 0;
+		self.tubes
+			.iter()
+			.map(|t| if t.remaining_capacity() == t.capacity {
+				empty_tubes += 1;
+				0.0
+			} else {
+				t.complete_pct()
+			})
+			.sum::<f32>() / (self.tubes.len() - empty_tubes) as f32
+	}
+
+	// Selects the tube, or pours into it from the selected tube,
+	// the tube index must be valid
+	pub fn activate(&mut self, tube_index: usize, single_unit: bool, rules: Rules, keep_selection_on_error: bool, limit: PourLimit) -> Activation {
+		match self.selected_tube {
+			Some(selected_index) if selected_index == tube_index => {
+				self.selected_tube = None;
+				Activation::Deselected
+			},
+			Some(selected_index) => {
+				let result = self.pour(selected_index, tube_index, single_unit, rules, limit);
+				// Keep the source selected after a failed pour if wanted
+				if result.is_ok() || !keep_selection_on_error {
+					self.selected_tube = None;
+				}
+				match result {
+					Ok(amount) => Activation::Poured { from: selected_index, to: tube_index, amount },
+					Err((tube, error)) => Activation::Rejected { tube, error },
+				}
+			},
+			None => {
+				let tube = &mut self.tubes[tube_index];
+				if rules.lock_completed_tubes && tube.is_completed() {
+					tube.show_error(PourError::Completed);
+					Activation::Rejected { tube: tube_index, error: PourError::Completed }
+				} else {
+					self.selected_tube = Some(tube_index);
+					Activation::Selected(tube_index)
+				}
+			},
+		}
+	}
+
+	// Moves color between two different valid tubes, saving the previous
+	// tubes for undo or showing the error on the tube at fault
+	pub fn pour(&mut self, from: usize, to: usize, single_unit: bool, rules: Rules, limit: PourLimit) -> Result<f32, (usize, PourError)> {
+		if rules.lock_completed_tubes {
+			if let Some(index) = [from, to].iter().copied().find(|&i| self.tubes[i].is_completed()) {
+				self.tubes[index].show_error(PourError::Completed);
+				return Err((index, PourError::Completed));
+			}
+		}
+		let unit_limit = if single_unit || rules.partial_pour { Some(1.0) } else { None };
+		let max_amount = match limit {
+			PourLimit::Unlimited => unit_limit,
+			PourLimit::AtMost(amount) => Some(unit_limit.unwrap_or(amount).min(amount)),
+			PourLimit::Vetoed => {
+				self.tubes[to].show_error(PourError::Vetoed);
+				return Err((to, PourError::Vetoed));
+			},
+		};
+		let snapshot = self.tubes.clone();
+		let result = color_tube::pour_at_most(&mut self.tubes, from, to, max_amount);
+		match result {
+			Ok(_) => self.history.push(snapshot),
+			Err((index, error)) => self.tubes[index].show_error(error),
+		}
+		self.update_locks();
+		result
+	}
+
+	// returns whether there was anything to undo
+	pub fn undo(&mut self) -> bool {
+		match self.history.pop() {
+			Some(tubes) => {
+				self.tubes = tubes;
+				self.selected_tube = None;
+				true
+			},
+			None => false,
+		}
+	}
+
+	// Tubes with a smaller capacity than the tallest tube
+	// are shorter and stand on the same line
+	pub fn update_layout(&mut self, area: Rect, hidpi_factor: f32) {
+		let tube_count = self.tubes.len();
+		let preferred_cols = (tube_count as f32 / self.tubes_factor as f32).ceil() as usize;
+		let capacity = self.tubes.iter().map(|t| t.capacity).fold(self.tube_capacity, f32::max);
+		self.layout = Layout::compute(area, tube_count, capacity, preferred_cols, hidpi_factor);
+		for (i, tube) in self.tubes.iter_mut().enumerate() {
+			let mut rect = self.layout.tube_rect(i);
+			let h = rect.w * tube.capacity;
+			rect.y += rect.h - h;
+			rect.h = h;
+			tube.dimensions = rect;
+		}
+	}
+
+	pub fn cols(&self) -> usize {
+		self.layout.cols
+	}
+
+	pub fn rows(&self) -> usize {
+		self.layout.rows
+	}
+
+	pub fn move_cursor(&mut self, action: Action) {
+		let (cols, count) = (self.cols(), self.tubes.len());
+		let index = match self.cursor {
+			Some(index) => index,
+			None => {
+				// Show the cursor on the first press
+				self.cursor = Some(self.selected_tube.unwrap_or(0));
+				return;
+			}
+		};
+		let col = index % cols;
+		self.cursor = Some(match action {
+			Action::CursorLeft if col > 0 => index - 1,
+			Action::CursorRight if col + 1 < cols && index + 1 < count => index + 1,
+			Action::CursorUp if index >= cols => index - cols,
+			Action::CursorDown if index + cols < count => index + cols,
+			_ => index,
+		});
+	}
+
+	// Finds the tube at the given position, using the surrounding
+	// margins and labels as part of the hit target for fingers
+	pub fn touch_hit_tube(&self, x: f32, y: f32) -> Option<usize> {
+		let (label_h, margin) = (self.layout.label_h, self.layout.tube_margin);
+		self.tubes.iter().position(|tube| {
+			x >= tube.dimensions.x - margin / 2.0 &&
+			x <= tube.dimensions.x + tube.dimensions.w + margin / 2.0 &&
+			y >= tube.dimensions.y - label_h - margin / 2.0 &&
+			y <= tube.dimensions.y + tube.dimensions.h + margin / 2.0
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::rc::Rc;
+	use ggez::graphics::Font;
+	use super::*;
+	use crate::level::Level;
+	use crate::theme::Theme;
+
+	fn board(level: &str) -> Board {
+		let level = Level::parse(level).unwrap();
+		Board::new(level.capacity, level.to_tubes(Font::default(), &Rc::new(Theme::default())))
+	}
+
+	#[test]
+	fn locked_completed_tubes_reject_pours() {
+		let rules = Rules { lock_completed_tubes: true, ..Rules::new() };
+		let mut locked = board("tube red*4\ntube\ntube blue");
+		assert_eq!(locked.pour(0, 1, false, rules, PourLimit::Unlimited), Err((0, PourError::Completed)));
+		assert_eq!(locked.pour(2, 0, false, rules, PourLimit::Unlimited), Err((0, PourError::Completed)));
+		assert!(locked.history.is_empty());
+
+		let mut unlocked = board("tube red*4\ntube\ntube blue");
+		assert_eq!(unlocked.pour(0, 1, false, Rules::new(), PourLimit::Unlimited), Ok(4.0));
+	}
+}
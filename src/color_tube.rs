@@ -1,19 +1,125 @@
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use ggez::{nalgebra, Context, GameResult};
-use ggez::graphics::{self, Drawable, Font, Color, Scale, Mesh, DrawMode, DrawParam, BlendMode, Rect, Text};
+use ggez::graphics::{self, Color, Drawable, Font, Scale, Mesh, MeshBuilder, DrawMode, DrawParam, BlendMode, Rect, Text};
 use ggez::event::KeyCode;
 use nalgebra::Point2;
-use crate::colors::*;
+use crate::bindings::key_name;
+use crate::colors::color_name;
+use crate::theme::Theme;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ColorTubeContent {
-	color: Color,
+	// Index into the palette of the active theme
+	color: usize,
 	amount: f32,
 }
 
 impl ColorTubeContent {
-	pub fn new(color: Color, amount: f32) -> Self {
+	pub fn new(color: usize, amount: f32) -> Self {
 		Self{ color, amount }
 	}
+
+	pub fn color(&self) -> usize {
+		self.color
+	}
+
+	pub fn amount(&self) -> f32 {
+		self.amount
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorTubeKind {
+	Normal,
+	// Can not be filled or drained until a tube of the given color is completed
+	Locked(usize),
+	// Can only be filled
+	PourIn,
+	// Can only be drained
+	PourOut,
+	// Only accepts the given color
+	SingleColor(usize),
+}
+
+// Tube geometry, in screen coordinates
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shape {
+	Fill(Vec<Point2<f32>>, Color),
+	// Closed outline of the given width
+	Stroke(Vec<Point2<f32>>, f32, Color),
+	// Open polyline of the given width
+	Line(Vec<Point2<f32>>, f32, Color),
+}
+
+fn rect_points(rect: Rect) -> Vec<Point2<f32>> {
+	vec![
+		Point2::new(rect.x, rect.y),
+		Point2::new(rect.x + rect.w, rect.y),
+		Point2::new(rect.x + rect.w, rect.y + rect.h),
+		Point2::new(rect.x, rect.y + rect.h),
+	]
+}
+
+// How long a tube shakes and flashes after an invalid pour
+const ERROR_ANIMATION_DURATION: Duration = Duration::from_millis(400);
+// How long the reason for an invalid pour is shown
+pub const ERROR_TIP_DURATION: Duration = Duration::from_millis(1500);
+
+// Reason a pour between two tubes is not possible
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PourError {
+	Empty,
+	Locked,
+	FillOnly,
+	DrainOnly,
+	TubeFull,
+	// Single color tube which accepts only the given color
+	WrongColor(usize),
+	ColorMismatch,
+	// Completed tubes are locked by the rules
+	Completed,
+	// Refused by a fill rule of a mod
+	Vetoed,
+}
+
+impl PourError {
+	pub fn description(&self) -> String {
+		match self {
+			PourError::Empty => "tube is empty".to_string(),
+			PourError::Locked => "tube is locked".to_string(),
+			PourError::FillOnly => "tube can only be filled".to_string(),
+			PourError::DrainOnly => "tube can only be drained".to_string(),
+			PourError::TubeFull => "tube full".to_string(),
+			PourError::WrongColor(color) => format!("tube only accepts {}", color_name(*color)),
+			PourError::ColorMismatch => "color mismatch".to_string(),
+			PourError::Completed => "tube is completed".to_string(),
+			PourError::Vetoed => "not allowed by a mod".to_string(),
+		}
+	}
+}
+
+// Everything the tube geometry depends on. The theme is kept alive by
+// the key, so a new theme can never reuse the address of a cached one
+#[derive(Debug, Clone)]
+struct MeshKey {
+	dimensions: Rect,
+	contents: Vec<ColorTubeContent>,
+	color_border: Color,
+	focused: bool,
+	kind: ColorTubeKind,
+	locked: bool,
+	theme: Rc<Theme>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct MeshCache(Option<(MeshKey, Mesh)>);
+
+// The cache is derived from the other fields, so it never makes tubes unequal
+impl PartialEq for MeshCache {
+	fn eq(&self, _other: &Self) -> bool {
+		true
+	}
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -21,27 +127,123 @@ pub struct ColorTube {
 	pub hovered: bool,
 	pub mousedown: bool,
 	pub clicked: bool,
+	pub focused: bool,
 	pub dimensions: Rect,
 	pub capacity: f32,
 	pub keycode: Option<KeyCode>,
+	pub kind: ColorTubeKind,
+	pub locked: bool,
+	pub theme: Rc<Theme>,
 	contents: Vec<ColorTubeContent>,
 	font: Font,
+	mesh: MeshCache,
+	// Last invalid pour involving this tube
+	error: Option<(PourError, Instant)>,
 }
 
 impl ColorTube {
-	pub fn new(capacity: f32, contents: Vec<ColorTubeContent>, font: Font) -> Self {
+	pub fn new(capacity: f32, contents: Vec<ColorTubeContent>, font: Font, theme: Rc<Theme>) -> Self {
 		Self {
 			hovered: false,
 			mousedown: false,
 			clicked: false,
+			focused: false,
 			dimensions: Rect::new(0.0, 0.0, 50.0, 50.0 * capacity),
 			capacity,
 			keycode: None,
+			kind: ColorTubeKind::Normal,
+			locked: false,
+			theme,
 			contents,
-			font
+			font,
+			mesh: MeshCache::default(),
+			error: None,
 		}
 	}
 
+	pub fn with_kind(mut self, kind: ColorTubeKind) -> Self {
+		self.locked = matches!(kind, ColorTubeKind::Locked(_));
+		self.kind = kind;
+		self
+	}
+
+	pub fn contents(&self) -> &[ColorTubeContent] {
+		&self.contents
+	}
+
+	pub fn is_completed(&self) -> bool {
+		self.remaining_capacity() <= 0.0 && self.complete_pct() == 1.0
+	}
+
+	pub fn can_fill(&self, color: usize) -> bool {
+		match self.kind {
+			_ if self.locked => false,
+			ColorTubeKind::PourOut => false,
+			ColorTubeKind::SingleColor(only_color) => only_color == color,
+			_ => true,
+		}
+	}
+
+	pub fn can_drain(&self) -> bool {
+		match self.kind {
+			_ if self.locked => false,
+			ColorTubeKind::PourIn => false,
+			_ => true,
+		}
+	}
+
+	pub fn check_drain(&self) -> Result<(), PourError> {
+		match self.kind {
+			_ if self.locked => Err(PourError::Locked),
+			ColorTubeKind::PourIn => Err(PourError::FillOnly),
+			_ if self.contents.is_empty() => Err(PourError::Empty),
+			_ => Ok(()),
+		}
+	}
+
+	pub fn check_fill(&self, color: usize) -> Result<(), PourError> {
+		match self.kind {
+			_ if self.locked => Err(PourError::Locked),
+			ColorTubeKind::PourOut => Err(PourError::DrainOnly),
+			_ if self.remaining_capacity() <= 0.0 => Err(PourError::TubeFull),
+			ColorTubeKind::SingleColor(only_color) if only_color != color => Err(PourError::WrongColor(only_color)),
+			_ => match self.contents.last() {
+				Some(top) if top.color != color => Err(PourError::ColorMismatch),
+				_ => Ok(()),
+			},
+		}
+	}
+
+	// Starts the shake and flash animation
+	pub fn show_error(&mut self, error: PourError) {
+		self.error = Some((error, Instant::now()));
+	}
+
+	pub fn recent_error(&self) -> Option<PourError> {
+		match self.error {
+			Some((error, time)) if time.elapsed() < ERROR_TIP_DURATION => Some(error),
+			_ => None,
+		}
+	}
+
+	// returns 1.0 right after an invalid pour, fading to 0.0
+	fn error_animation(&self) -> f32 {
+		match self.error {
+			Some((_, time)) if time.elapsed() < ERROR_ANIMATION_DURATION => {
+				1.0 - time.elapsed().as_secs_f32() / ERROR_ANIMATION_DURATION.as_secs_f32()
+			},
+			_ => 0.0,
+		}
+	}
+
+	fn shake_offset(&self) -> f32 {
+		let t = self.error_animation();
+		if t <= 0.0 {
+			return 0.0;
+		}
+		(t * 40.0).sin() * 5.0 * (self.dimensions.w / 50.0) * t
+	}
+
 	pub fn amount(&self) -> f32 {
 		self.contents.iter().map(|c| c.amount).sum()
 	}
@@ -50,26 +252,26 @@ impl ColorTube {
 		self.capacity - self.amount()
 	}
 
-	pub fn main_color(&self) -> Option<Color> {
+	pub fn main_color(&self) -> Option<usize> {
 		let mut occurrences = std::collections::HashMap::new();
 		for content in &self.contents {
-			*occurrences.entry(content.color.to_rgb_u32()).or_insert(0) += content.amount.floor() as u32;
+			*occurrences.entry(content.color).or_insert(0) += content.amount.floor() as u32;
 		}
 		occurrences
 			.into_iter()
 			.max_by_key(|&(_, count)| count)
-			.map(|(val, _)| Color::from_rgb_u32(val))
+			.map(|(color, _)| color)
 	}
 
 	// returns 0.0 (0%) .. 1.0 (100%)
-	pub fn color_pct(&self, color: Color) -> f32 {
+	pub fn color_pct(&self, color: usize) -> f32 {
 		let mut amount = 0.0;
 		for content in &self.contents {
 			if content.color == color {
 				amount += content.amount;
 			}
 		}
-		(amount / self.capacity - self.remaining_capacity() / self.capacity - (self.amount() - self.remaining_capacity() - amount) / self.capacity).max(0.0).min(1.0)
+		(amount / self.capacity - self.remaining_capacity() / self.capacity - (self.amount() - self.remaining_capacity() - amount) / self.capacity).clamp(0.0, 1.0)
 	}
 
 	// returns 0.0 (0%) .. 1.0 (100%)
@@ -95,7 +297,7 @@ impl ColorTube {
 	}
 
 	pub fn fill(&mut self, content: ColorTubeContent) -> Option<ColorTubeContent> {
-		if self.remaining_capacity() < content.amount {
+		if self.remaining_capacity() < content.amount || !self.can_fill(content.color) {
 			return Some(content);
 		}
 		let count = self.contents.len();
@@ -110,6 +312,9 @@ impl ColorTube {
 	}
 
 	pub fn drain(&mut self, mut amount: f32) -> Option<ColorTubeContent> {
+		if !self.can_drain() {
+			return None;
+		}
 		if amount > self.amount() {
 			amount = self.amount();
 		}
@@ -137,25 +342,111 @@ impl ColorTube {
 	}
 }
 
-impl Drawable for ColorTube {
-	fn draw(&self, ctx: &mut Context, param: DrawParam) -> GameResult {
+impl ColorTube {
+	fn border_color(&self) -> Color {
+		let mut color_border = if self.clicked {
+			self.theme.tube_border_focus
+		} else if self.hovered || self.mousedown {
+			self.theme.tube_border_hover
+		} else {
+			self.theme.tube_border
+		};
+		if self.mousedown {
+			color_border.a = 0.5;
+		}
+		let flash = self.error_animation();
+		if flash > 0.0 {
+			let error = self.theme.tube_border_error;
+			color_border = Color::new(
+				color_border.r + (error.r - color_border.r) * flash,
+				color_border.g + (error.g - color_border.g) * flash,
+				color_border.b + (error.b - color_border.b) * flash,
+				color_border.a + (error.a - color_border.a) * flash
+			);
+		}
+		color_border
+	}
+
+	fn mesh_key(&self) -> MeshKey {
+		MeshKey {
+			dimensions: self.dimensions,
+			contents: self.contents.clone(),
+			color_border: self.border_color(),
+			focused: self.focused,
+			kind: self.kind,
+			locked: self.locked,
+			theme: self.theme.clone(),
+		}
+	}
+
+	// Compares without building a key, as this runs on every draw
+	fn mesh_key_matches(&self, key: &MeshKey) -> bool {
+		key.dimensions == self.dimensions
+			&& key.contents == self.contents
+			&& key.color_border == self.border_color()
+			&& key.focused == self.focused
+			&& key.kind == self.kind
+			&& key.locked == self.locked
+			&& Rc::ptr_eq(&key.theme, &self.theme)
+	}
+
+	// Rebuilds the cached geometry if anything it depends on changed,
+	// returns whether it was rebuilt
+	pub fn prepare(&mut self, ctx: &mut Context) -> GameResult<bool> {
+		if let Some((cached_key, _)) = &self.mesh.0 {
+			if self.mesh_key_matches(cached_key) {
+				return Ok(false);
+			}
+		}
+		let mesh = self.build_mesh(ctx)?;
+		self.mesh.0 = Some((self.mesh_key(), mesh));
+		Ok(true)
+	}
+
+	// Tube outline with a rounded bottom, grown outwards by the given amount
+	fn outline_points(&self, grow: f32) -> Vec<Point2<f32>> {
+		let w_half = (self.dimensions.w / 2.0).floor();
+		let center_y = self.dimensions.y + self.dimensions.h - w_half;
+		let x = self.dimensions.x - grow;
+		let w = self.dimensions.w + grow * 2.0;
+		let radius = w_half + grow;
+		let steps = (self.dimensions.w / 4.0).floor() as u32;
+		let mut points = Vec::new();
+		points.push(Point2::new(x, self.dimensions.y));
+		for i in 0..=steps {
+			points.push(Point2::new(x + (i as f32 / steps as f32) * w, center_y + (i as f32 * std::f32::consts::PI / steps as f32).sin() * radius));
+		}
+		points.push(Point2::new(x + w, self.dimensions.y));
+		points
+	}
+
+	// Geometry of the tube, shared by the mesh and the software renderer
+	pub fn shapes(&self) -> Vec<Shape> {
 		let scale = 1.0;
 		let w_scaled = self.dimensions.w * scale;
 		let w_inner_scaled = (self.dimensions.w - 1.0) * scale;
 		let w_half = (w_scaled / 2.0).floor();
 		let h_scaled = self.dimensions.h * scale;
-		let mut color_border = if self.clicked {
-			COLOR_TUBE_BORDER_FOCUS
-		} else if self.hovered || self.mousedown {
-			COLOR_TUBE_BORDER_HOVER
+		// Decorations were designed for 50 pixel wide tubes
+		let s = w_scaled / 50.0;
+		let color_border = self.border_color();
+		let mut shapes = Vec::new();
+
+		// Glow around completed tubes
+		let completed_color = if self.is_completed() {
+			self.main_color().map(|color| self.theme.liquid(color))
 		} else {
-			COLOR_TUBE_BORDER
+			None
 		};
-		if self.mousedown {
-			color_border.a = 0.5;
+		if let Some(color) = completed_color {
+			for i in 1..=3 {
+				let mut glow = color;
+				glow.a = 0.35 / i as f32;
+				shapes.push(Shape::Stroke(self.outline_points(i as f32 * 3.0 * s), 3.0 * s, glow));
+			}
 		}
 
-		// Draw fill
+		// Fill
 		let mut filled_amount = 0.0;
 		for content in &self.contents {
 			let total_amount = filled_amount + content.amount;
@@ -163,7 +454,7 @@ impl Drawable for ColorTube {
 			let fill_starty = self.dimensions.y + h_scaled - w_scaled * total_amount;
 			let fill_h = w_scaled * content.amount;
 			if filled_amount < 0.5 {
-				// Draw fill with rounded bottom
+				// Fill with rounded bottom
 				let mut fill_points = Vec::new();
 				if content.amount >= 0.5 {
 					fill_points.push(Point2::new(fill_startx, fill_starty));
@@ -182,77 +473,145 @@ impl Drawable for ColorTube {
 					fill_points.push(Point2::new(self.dimensions.x + w_inner_scaled, fill_starty));
 				}
 				if fill_points.len() >= 3 {
-					Mesh::new_polygon(ctx, DrawMode::fill(), &fill_points, content.color)?.draw(ctx, param)?;
+					shapes.push(Shape::Fill(fill_points, self.theme.liquid(content.color)));
 				}
 			} else {
-				// Draw normal square fill
-				Mesh::new_rectangle(ctx, DrawMode::fill(), Rect{
+				// Normal square fill
+				shapes.push(Shape::Fill(rect_points(Rect{
 					x: fill_startx,
 					y: fill_starty,
 					w: w_inner_scaled,
 					h: fill_h
-				}, content.color)?.draw(ctx, param)?;
+				}), self.theme.liquid(content.color)));
 			}
 			filled_amount = total_amount;
 		}
 
-		// Draw border
-		let mut border_points = Vec::new();
-		let steps = (w_scaled / 4.0).floor() as u32;
-		border_points.push(Point2::new(self.dimensions.x, self.dimensions.y));
-		for i in 0..=steps {
-			border_points.push(Point2::new(self.dimensions.x + (i as f32 / steps as f32) * w_scaled, self.dimensions.y + h_scaled - w_half + (i as f32 * std::f32::consts::PI / steps as f32).sin() * w_half));
+		// Border
+		shapes.push(Shape::Stroke(self.outline_points(0.0), 2.0, color_border));
+
+		// Cap on completed tubes
+		if completed_color.is_some() {
+			shapes.push(Shape::Fill(rect_points(Rect{
+				x: self.dimensions.x - 3.0 * s,
+				y: self.dimensions.y - 3.0 * s,
+				w: w_scaled + 6.0 * s,
+				h: 6.0 * s
+			}), color_border));
+		}
+
+		// Keyboard cursor around the tube
+		if self.focused {
+			let cursor_margin = (5.0 * s).max(3.0);
+			shapes.push(Shape::Stroke(rect_points(Rect{
+				x: self.dimensions.x - cursor_margin,
+				y: self.dimensions.y - cursor_margin,
+				w: w_scaled + cursor_margin * 2.0,
+				h: h_scaled + cursor_margin * 2.0
+			}), 2.0, self.theme.tube_border_cursor));
 		}
-		border_points.push(Point2::new(self.dimensions.x + w_scaled, self.dimensions.y));
-		Mesh::new_polygon(ctx, DrawMode::stroke(2.0), &border_points, color_border)?.draw(ctx, param)?;
+
+		// Kind marker
+		let marker_x = self.dimensions.x + w_half;
+		let marker_y = self.dimensions.y - 4.0 * s;
+		match self.kind {
+			ColorTubeKind::Normal => {},
+			ColorTubeKind::Locked(color) => if self.locked {
+				let color = self.theme.liquid(color);
+				// Bars across the tube with a lock in the middle
+				for i in 1..=3 {
+					let bar_y = self.dimensions.y + (i as f32 / 4.0) * (h_scaled - w_half);
+					shapes.push(Shape::Line(vec![
+						Point2::new(self.dimensions.x, bar_y),
+						Point2::new(self.dimensions.x + w_scaled, bar_y),
+					], (3.0 * s).max(1.0), color));
+				}
+				let lock_w = (w_scaled / 3.0).floor();
+				let lock_y = self.dimensions.y + (h_scaled - w_half) / 2.0;
+				let lock_rect = Rect{
+					x: marker_x - lock_w / 2.0,
+					y: lock_y - lock_w / 2.0,
+					w: lock_w,
+					h: lock_w
+				};
+				shapes.push(Shape::Fill(rect_points(lock_rect), color));
+				shapes.push(Shape::Stroke(rect_points(lock_rect), 2.0, color_border));
+			},
+			ColorTubeKind::PourIn => {
+				// Arrow pointing into the tube
+				shapes.push(Shape::Fill(vec![
+					Point2::new(marker_x - 5.0 * s, marker_y - 8.0 * s),
+					Point2::new(marker_x + 5.0 * s, marker_y - 8.0 * s),
+					Point2::new(marker_x, marker_y),
+				], color_border));
+			},
+			ColorTubeKind::PourOut => {
+				// Arrow pointing out of the tube
+				shapes.push(Shape::Fill(vec![
+					Point2::new(marker_x - 5.0 * s, marker_y),
+					Point2::new(marker_x, marker_y - 8.0 * s),
+					Point2::new(marker_x + 5.0 * s, marker_y),
+				], color_border));
+			},
+			ColorTubeKind::SingleColor(color) => {
+				// Rim in the only accepted color
+				shapes.push(Shape::Fill(rect_points(Rect{
+					x: self.dimensions.x,
+					y: marker_y - 2.0 * s,
+					w: w_scaled,
+					h: 4.0 * s
+				}), self.theme.liquid(color)));
+			},
+		}
+
+		shapes
+	}
+
+	fn build_mesh(&self, ctx: &mut Context) -> GameResult<Mesh> {
+		let mut mb = MeshBuilder::new();
+		for shape in self.shapes() {
+			match shape {
+				Shape::Fill(points, color) => mb.polygon(DrawMode::fill(), &points, color)?,
+				Shape::Stroke(points, width, color) => mb.polygon(DrawMode::stroke(width), &points, color)?,
+				Shape::Line(points, width, color) => mb.line(&points, width, color)?,
+			};
+		}
+		mb.build(ctx)
+	}
+}
+
+impl Drawable for ColorTube {
+	fn draw(&self, ctx: &mut Context, param: DrawParam) -> GameResult {
+		// Shake after an invalid pour
+		let dx = self.shake_offset();
+		let param = param.dest(Point2::new(param.dest.x + dx, param.dest.y));
+
+		// Draw geometry, building it for this frame only if it was not prepared
+		match &self.mesh.0 {
+			Some((key, mesh)) if self.mesh_key_matches(key) => mesh.draw(ctx, param)?,
+			_ => self.build_mesh(ctx)?.draw(ctx, param)?,
+		}
+
+		let text_scale = Scale::uniform((18.0 * self.dimensions.w / 50.0).max(11.0));
+		let color_border = self.border_color();
 
 		// Draw keycode text
 		if let Some(keycode) = self.keycode {
-			let keystr = match keycode {
-				KeyCode::Key1 => "1",
-				KeyCode::Key2 => "2",
-				KeyCode::Key3 => "3",
-				KeyCode::Key4 => "4",
-				KeyCode::Key5 => "5",
-				KeyCode::Key6 => "6",
-				KeyCode::Key7 => "7",
-				KeyCode::Q => "Q",
-				KeyCode::W => "W",
-				KeyCode::E => "E",
-				KeyCode::R => "R",
-				KeyCode::T => "T",
-				KeyCode::Y => "Y",
-				KeyCode::U => "U",
-				KeyCode::A => "A",
-				KeyCode::S => "S",
-				KeyCode::D => "D",
-				KeyCode::F => "F",
-				KeyCode::G => "G",
-				KeyCode::H => "H",
-				KeyCode::J => "J",
-				KeyCode::Z => "Z",
-				KeyCode::X => "X",
-				KeyCode::C => "C",
-				KeyCode::V => "V",
-				KeyCode::B => "B",
-				KeyCode::N => "N",
-				KeyCode::M => "M",
-				_ => "",
-			};
-			if keystr.len() > 0 {
+			let keystr = key_name(keycode);
+			if !keystr.is_empty() {
 				let mut keytext = Text::new(keystr);
-				keytext.set_font(self.font, Scale::uniform(18.0));
+				keytext.set_font(self.font, text_scale);
 				let keytext_h = keytext.height(ctx) as f32;
-				graphics::queue_text(ctx, &keytext, Point2::new(self.dimensions.x, self.dimensions.y - keytext_h), Some(COLOR_YELLOW));
+				graphics::queue_text(ctx, &keytext, Point2::new(self.dimensions.x + dx, self.dimensions.y - keytext_h), Some(self.theme.key_label));
 			}
 		}
 
 		// Draw completed text
 		let mut pcttext = Text::new(format!("{}%", (self.complete_pct() * 100.0).floor()));
-		pcttext.set_font(self.font, Scale::uniform(18.0));
+		pcttext.set_font(self.font, text_scale);
 		let pcttext_h = pcttext.height(ctx) as f32;
 		let pcttext_w = pcttext.width(ctx) as f32;
-		graphics::queue_text(ctx, &pcttext, Point2::new(self.dimensions.x + (self.dimensions.w - pcttext_w), self.dimensions.y - pcttext_h), Some(color_border));
+		graphics::queue_text(ctx, &pcttext, Point2::new(self.dimensions.x + dx + (self.dimensions.w - pcttext_w), self.dimensions.y - pcttext_h), Some(color_border));
 
 		Ok(())
 	}
@@ -260,4 +619,56 @@ impl Drawable for ColorTube {
 	fn dimensions(&self, _ctx: &mut Context) -> Option<Rect> { Some(self.dimensions) }
 	fn set_blend_mode(&mut self, _mode: Option<BlendMode>) {}
 	fn blend_mode(&self) -> Option<BlendMode> { None }
-}
\ No newline at end of file
+}
+
+// Moves the top color of one tube into another, either as much as fits
+// or a single unit. Returns the amount poured, or the tube which
+// prevented the pour and why
+pub fn pour(tubes: &mut [ColorTube], from: usize, to: usize, single_unit: bool) -> Result<f32, (usize, PourError)> {
+	pour_at_most(tubes, from, to, if single_unit { Some(1.0) } else { None })
+}
+
+// Same as pour, moving no more than max_amount if given
+pub fn pour_at_most(tubes: &mut [ColorTube], from: usize, to: usize, max_amount: Option<f32>) -> Result<f32, (usize, PourError)> {
+	let (tubes_before, tubes_after) = tubes.split_at_mut(to);
+	let (tube, tubes_after) = tubes_after.split_first_mut().unwrap();
+	let prev_tube = if from < to {
+		&mut tubes_before[from]
+	} else {
+		&mut tubes_after[from - to - 1]
+	};
+
+	prev_tube.check_drain().map_err(|error| (from, error))?;
+	if let Some(top) = prev_tube.contents.last() {
+		tube.check_fill(top.color).map_err(|error| (to, error))?;
+	}
+
+	let amount = match max_amount {
+		Some(max_amount) => tube.remaining_capacity().min(max_amount),
+		None => tube.remaining_capacity(),
+	};
+	let content = prev_tube.drain(amount).ok_or((from, PourError::Empty))?;
+	let amount = content.amount;
+	if let Some(content) = tube.fill(content) {
+		// Color doesn't match, put the color back into the previous tube
+		prev_tube.fill_unchecked(content);
+		return Err((to, PourError::ColorMismatch));
+	}
+	Ok(amount)
+}
+
+// Unlocks locked tubes whose color has been completed
+pub fn update_locks(tubes: &mut [ColorTube]) {
+	let completed_colors: Vec<usize> = tubes
+		.iter()
+		.filter(|t| t.is_completed())
+		.filter_map(|t| t.main_color())
+		.collect();
+	for tube in tubes.iter_mut() {
+		if let ColorTubeKind::Locked(color) = tube.kind {
+			if tube.locked && completed_colors.contains(&color) {
+				tube.locked = false;
+			}
+		}
+	}
+}
@@ -1,19 +1,130 @@
 use ggez::graphics::Color;
 
-pub const COLOR_PINK: Color = Color::new(0.8823529411764706, 0.12941176470588237, 0.7098039215686275, 1.0);
-pub const COLOR_PURPLE: Color = Color::new(0.6549019607843137, 0.17647058823529413, 0.8666666666666667, 1.0);
-pub const COLOR_VIOLET: Color = Color::new(0.3843137254901961, 0.2, 0.8274509803921568, 1.0);
-pub const COLOR_BLUE: Color = Color::new(0.050980392156862744, 0.08627450980392157, 0.49019607843137253, 1.0);
-pub const COLOR_LIGHTBLUE: Color = Color::new(0.2235294117647059, 0.27058823529411763, 0.8313725490196079, 1.0);
-pub const COLOR_CYAN: Color = Color::new(0.1411764705882353, 0.8705882352941177, 0.8705882352941177, 1.0);
-pub const COLOR_GREEN: Color = Color::new(0.043137254901960784, 0.396078431372549, 0.07058823529411765, 1.0);
-pub const COLOR_LIGHTGREEN: Color = Color::new(0.1803921568627451, 0.7803921568627451, 0.2235294117647059, 1.0);
-pub const COLOR_OLIVE: Color = Color::new(0.5686274509803921, 0.7254901960784313, 0.08235294117647059, 1.0);
-pub const COLOR_YELLOW: Color = Color::new(0.8235294117647058, 0.803921568627451, 0.16470588235294117, 1.0);
-pub const COLOR_ORANGE: Color = Color::new(0.8117647058823529, 0.44313725490196076, 0.17647058823529413, 1.0);
-pub const COLOR_RED: Color = Color::new(0.796078431372549, 0.1568627450980392, 0.1450980392156863, 1.0);
-
-pub const COLOR_BG: Color = Color::new(0.125, 0.125, 0.125, 1.0);
-pub const COLOR_TUBE_BORDER: Color = Color::new(0.5, 0.5, 0.5, 1.0);
-pub const COLOR_TUBE_BORDER_HOVER: Color = Color::new(1.0, 1.0, 1.0, 1.0);
-pub const COLOR_TUBE_BORDER_FOCUS: Color = COLOR_LIGHTBLUE;
\ No newline at end of file
+// Liquid colors are stored as indices into the palette of the active theme
+pub const LIQUID_COLOR_NAMES: [&str; 12] = [
+	"pink",
+	"purple",
+	"violet",
+	"blue",
+	"lightblue",
+	"cyan",
+	"green",
+	"lightgreen",
+	"olive",
+	"yellow",
+	"orange",
+	"red",
+];
+
+pub fn color_name(index: usize) -> String {
+	match LIQUID_COLOR_NAMES.get(index) {
+		Some(name) => name.to_string(),
+		None => format!("color{}", index + 1),
+	}
+}
+
+pub fn color_by_name(name: &str) -> Option<usize> {
+	if let Some(index) = LIQUID_COLOR_NAMES.iter().position(|&n| n == name) {
+		return Some(index);
+	}
+	if let Some(number) = name.strip_prefix("color") {
+		return number.parse::<usize>().ok().filter(|&n| n >= 1).map(|n| n - 1);
+	}
+	None
+}
+
+pub fn color_to_hex(color: Color) -> String {
+	let (r, g, b, a) = color.to_rgba();
+	if a == 255 {
+		format!("#{:02x}{:02x}{:02x}", r, g, b)
+	} else {
+		format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+	}
+}
+
+pub fn color_from_hex(s: &str) -> Option<Color> {
+	if !s.starts_with('#') || !s.is_ascii() || (s.len() != 7 && s.len() != 9) {
+		return None;
+	}
+	let channel = |i: usize| u8::from_str_radix(&s[1 + i * 2..3 + i * 2], 16).ok();
+	let a = if s.len() == 9 { channel(3)? } else { 255 };
+	Some(Color::from_rgba(channel(0)?, channel(1)?, channel(2)?, a))
+}
+
+// Two palette colors closer than this are hard to tell apart
+pub const MIN_COLOR_DELTA_E: f32 = 10.0;
+
+fn color_to_lab(color: Color) -> (f32, f32, f32) {
+	// sRGB to linear RGB
+	let linear = |c: f32| if c <= 0.04045 {
+		c / 12.92
+	} else {
+		((c + 0.055) / 1.055).powf(2.4)
+	};
+	let (r, g, b) = (linear(color.r), linear(color.g), linear(color.b));
+
+	// Linear RGB to CIE XYZ, relative to the D65 white point
+	let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+	let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+	let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+	// CIE XYZ to CIELAB
+	let f = |t: f32| if t > 216.0 / 24389.0 {
+		t.cbrt()
+	} else {
+		(24389.0 / 27.0 * t + 16.0) / 116.0
+	};
+	let (fx, fy, fz) = (f(x), f(y), f(z));
+	(116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+// CIE76 color difference
+pub fn delta_e(a: Color, b: Color) -> f32 {
+	let (l1, a1, b1) = color_to_lab(a);
+	let (l2, a2, b2) = color_to_lab(b);
+	((l1 - l2).powi(2) + (a1 - a2).powi(2) + (b1 - b2).powi(2)).sqrt()
+}
+
+// Earlier palette colors closer than MIN_COLOR_DELTA_E to the color
+// at the index, with their delta E
+pub fn similar_colors(palette: &[Color], index: usize) -> Vec<(usize, f32)> {
+	(0..index)
+		.map(|j| (j, delta_e(palette[index], palette[j])))
+		.filter(|&(_, delta)| delta < MIN_COLOR_DELTA_E)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn delta_e_of_known_colors() {
+		let black = Color::new(0.0, 0.0, 0.0, 1.0);
+		let white = Color::new(1.0, 1.0, 1.0, 1.0);
+		assert!(delta_e(black, black).abs() < 0.01);
+		// L* runs from 0 for black to 100 for white
+		assert!((delta_e(black, white) - 100.0).abs() < 0.1);
+		let red = Color::from_rgb(255, 0, 0);
+		let (l, a, b) = color_to_lab(red);
+		assert!((l - 53.24).abs() < 0.1 && (a - 80.09).abs() < 0.2 && (b - 67.20).abs() < 0.2, "red is {} {} {}", l, a, b);
+		assert!((delta_e(red, white) - delta_e(white, red)).abs() < 0.001);
+	}
+
+	#[test]
+	fn flags_colors_hard_to_tell_apart() {
+		let palette = [
+			Color::from_rgb(255, 0, 0),
+			Color::from_rgb(0, 0, 255),
+			Color::from_rgb(250, 5, 5),
+			Color::from_rgb(0, 255, 0),
+		];
+		assert!(similar_colors(&palette, 0).is_empty());
+		assert!(similar_colors(&palette, 1).is_empty());
+		let similar = similar_colors(&palette, 2);
+		assert_eq!(similar.len(), 1);
+		assert_eq!(similar[0].0, 0);
+		assert!(similar[0].1 < MIN_COLOR_DELTA_E);
+		assert!(similar_colors(&palette, 3).is_empty());
+	}
+}
@@ -0,0 +1,214 @@
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use serde::Serialize;
+use crate::board::{Activation, Board};
+use crate::generator::ShareCode;
+use crate::level::Level;
+use crate::spectator::TubeState;
+
+// Control commands, one per line, tubes are numbered from 1:
+//
+//   pour 1 3                              select tube 1, then tube 3
+//   pour 1 3 single                       pour a single unit
+//   undo
+//   restart
+//   load 57                               generated level 57
+//   load 1-57-12-4-2                      level from a share code
+//   load capacity 4|tube red blue|tube    level file with '|' for newlines
+//   state
+//
+// Every command is answered with one JSON line, the resulting state
+//
+//   {"ok":true,"level":1,"complete_pct":0.25,"moves":3,"selected":null,"tubes":[...]}
+//
+// or the reason the command failed
+//
+//   {"ok":false,"error":"tube 3: tube full"}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LevelCode {
+	Number(usize),
+	Share(ShareCode),
+	Level(Level),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+	Pour { from: usize, to: usize, single_unit: bool },
+	Undo,
+	Restart,
+	Load(LevelCode),
+	State,
+}
+
+fn parse_tube(s: Option<&str>) -> Result<usize, String> {
+	s.and_then(|s| s.parse::<usize>().ok())
+		.filter(|&n| n >= 1)
+		.map(|n| n - 1)
+		.ok_or_else(|| "expected a tube number from 1".to_string())
+}
+
+impl Command {
+	pub fn parse(line: &str) -> Result<Self, String> {
+		let line = line.trim();
+		let mut words = line.split_whitespace();
+		match words.next() {
+			Some("pour") => {
+				let from = parse_tube(words.next())?;
+				let to = parse_tube(words.next())?;
+				let single_unit = match words.next() {
+					Some("single") => true,
+					None => false,
+					Some(word) => return Err(format!("unknown pour attribute {:?}", word)),
+				};
+				Ok(Command::Pour { from, to, single_unit })
+			},
+			Some("undo") => Ok(Command::Undo),
+			Some("restart") => Ok(Command::Restart),
+			Some("state") => Ok(Command::State),
+			Some("load") => {
+				let code = line["load".len()..].trim();
+				if let Ok(number) = code.parse::<usize>() {
+					if number == 0 {
+						return Err("levels are numbered from 1".to_string());
+					}
+					return Ok(Command::Load(LevelCode::Number(number)));
+				}
+				if code.contains('-') && !code.contains(char::is_whitespace) {
+					return ShareCode::parse(code).map(|code| Command::Load(LevelCode::Share(code)));
+				}
+				Level::parse(&code.replace('|', "\n"))
+					.map(|level| Command::Load(LevelCode::Level(level)))
+					.map_err(|e| e.to_string())
+			},
+			Some(word) => Err(format!("unknown command {:?}", word)),
+			None => Err("empty command".to_string()),
+		}
+	}
+}
+
+// Pours by selecting both tubes in turn, the same way as with the
+// mouse or keyboard, activate returns None while input is disabled
+pub fn pour_with<F: FnMut(usize) -> Option<Activation>>(from: usize, to: usize, tube_count: usize, mut activate: F) -> Result<(), String> {
+	if let Some(&tube) = [from, to].iter().find(|&&t| t >= tube_count) {
+		return Err(format!("there is no tube {}", tube + 1));
+	}
+	if from == to {
+		return Err("can not pour a tube into itself".to_string());
+	}
+	let disabled = || "the board does not accept input right now".to_string();
+	match activate(from).ok_or_else(disabled)? {
+		Activation::Selected(_) => {},
+		Activation::Rejected { tube, error } => return Err(format!("tube {}: {}", tube + 1, error.description())),
+		_ => return Err(disabled()),
+	}
+	match activate(to).ok_or_else(disabled)? {
+		Activation::Poured { .. } => Ok(()),
+		Activation::Rejected { tube, error } => Err(format!("tube {}: {}", tube + 1, error.description())),
+		_ => Err(disabled()),
+	}
+}
+
+#[derive(Serialize)]
+struct StateResponse {
+	ok: bool,
+	level: usize,
+	complete_pct: f32,
+	moves: usize,
+	// Numbered from 1 like in commands
+	selected: Option<usize>,
+	tubes: Vec<TubeState>,
+}
+
+pub fn state_response(level: usize, board: &Board) -> String {
+	let response = StateResponse {
+		ok: true,
+		level,
+		complete_pct: board.complete_pct(),
+		moves: board.history.len(),
+		selected: board.selected_tube.map(|i| i + 1),
+		tubes: board.tubes.iter().map(TubeState::from_tube).collect(),
+	};
+	serde_json::to_string(&response).unwrap_or_default()
+}
+
+pub fn error_response(error: &str) -> String {
+	serde_json::json!({ "ok": false, "error": error }).to_string()
+}
+
+// Reads command lines from stdin on a separate thread
+pub fn read_stdin() -> Receiver<String> {
+	let (sender, receiver) = mpsc::channel();
+	thread::spawn(move || {
+		let stdin = io::stdin();
+		for line in stdin.lock().lines() {
+			match line {
+				Ok(line) => if sender.send(line).is_err() {
+					break;
+				},
+				Err(_) => break,
+			}
+		}
+	});
+	receiver
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::color_tube::PourError;
+
+	#[test]
+	fn parses_commands() {
+		assert_eq!(Command::parse(" pour 1 3 "), Ok(Command::Pour { from: 0, to: 2, single_unit: false }));
+		assert_eq!(Command::parse("pour 2 1 single"), Ok(Command::Pour { from: 1, to: 0, single_unit: true }));
+		assert_eq!(Command::parse("undo"), Ok(Command::Undo));
+		assert_eq!(Command::parse("restart"), Ok(Command::Restart));
+		assert_eq!(Command::parse("state"), Ok(Command::State));
+		assert_eq!(Command::parse("load 57"), Ok(Command::Load(LevelCode::Number(57))));
+		assert_eq!(Command::parse("load 1-57-12-4-2"), Ok(Command::Load(LevelCode::Share(ShareCode::parse("1-57-12-4-2").unwrap()))));
+		assert_eq!(
+			Command::parse("load capacity 2|tube red blue|tube"),
+			Ok(Command::Load(LevelCode::Level(Level::parse("capacity 2\ntube red blue\ntube").unwrap()))),
+		);
+	}
+
+	#[test]
+	fn rejects_invalid_commands() {
+		assert_eq!(Command::parse(""), Err("empty command".to_string()));
+		assert_eq!(Command::parse("jump"), Err("unknown command \"jump\"".to_string()));
+		assert_eq!(Command::parse("pour 0 1"), Err("expected a tube number from 1".to_string()));
+		assert_eq!(Command::parse("pour 1"), Err("expected a tube number from 1".to_string()));
+		assert_eq!(Command::parse("pour 1 2 twice"), Err("unknown pour attribute \"twice\"".to_string()));
+		assert_eq!(Command::parse("load 0"), Err("levels are numbered from 1".to_string()));
+		assert!(Command::parse("load capacity 2|tube purple-ish").is_err());
+	}
+
+	#[test]
+	fn pours_by_selecting_both_tubes() {
+		let mut activated = Vec::new();
+		let result = pour_with(0, 2, 3, |tube| {
+			activated.push(tube);
+			Some(if tube == 0 { Activation::Selected(0) } else { Activation::Poured { from: 0, to: tube, amount: 1.0 } })
+		});
+		assert_eq!(result, Ok(()));
+		assert_eq!(activated, vec![0, 2]);
+	}
+
+	#[test]
+	fn reports_why_a_pour_failed() {
+		let never = |_| -> Option<Activation> { panic!("no tube should be activated") };
+		assert_eq!(pour_with(0, 3, 3, never), Err("there is no tube 4".to_string()));
+		assert_eq!(pour_with(1, 1, 3, never), Err("can not pour a tube into itself".to_string()));
+
+		let rejected = Activation::Rejected { tube: 0, error: PourError::Empty };
+		assert_eq!(pour_with(0, 1, 3, |_| Some(rejected)), Err("tube 1: tube is empty".to_string()));
+		let result = pour_with(0, 1, 3, |tube| Some(match tube {
+			0 => Activation::Selected(0),
+			_ => Activation::Rejected { tube, error: PourError::TubeFull },
+		}));
+		assert_eq!(result, Err("tube 2: tube full".to_string()));
+		assert_eq!(pour_with(0, 1, 3, |_| None), Err("the board does not accept input right now".to_string()));
+	}
+}
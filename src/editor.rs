@@ -0,0 +1,231 @@
+use std::rc::Rc;
+use ggez::graphics::Font;
+use crate::colors::color_name;
+use crate::color_tube::{ColorTube, ColorTubeContent, ColorTubeKind};
+use crate::level::{Level, LevelTube};
+use crate::rules::Rules;
+use crate::solver::{self, SolveError};
+use crate::theme::Theme;
+
+pub const MAX_TUBE_CAPACITY: f32 = 8.0;
+
+pub const KIND_NAMES: [&str; 5] = ["Normal", "Fill only", "Drain only", "Single color", "Locked by color"];
+
+// Changes made in the editor window, applied in update
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EditorAction {
+	AddTube,
+	RemoveTube,
+	SetCapacity(f32),
+	// Index into KIND_NAMES, colored kinds use the paint color
+	SetKind(usize),
+	// None erases
+	SetPaint(Option<usize>),
+	Check,
+	Save,
+	Play,
+	Close,
+}
+
+pub fn kind_index(kind: ColorTubeKind) -> usize {
+	match kind {
+		ColorTubeKind::Normal => 0,
+		ColorTubeKind::PourIn => 1,
+		ColorTubeKind::PourOut => 2,
+		ColorTubeKind::SingleColor(_) => 3,
+		ColorTubeKind::Locked(_) => 4,
+	}
+}
+
+// Single color and locked tubes take the paint color
+pub fn kind_uses_color(kind_index: usize) -> bool {
+	kind_index == 3 || kind_index == 4
+}
+
+// Unit slot of a drawn tube at the given height, counted from the bottom
+pub fn slot_at(tube: &ColorTube, y: f32) -> usize {
+	let unit_h = tube.dimensions.w.max(1.0);
+	let slot = ((tube.dimensions.y + tube.dimensions.h - y) / unit_h).floor().max(0.0) as usize;
+	slot.min((tube.capacity.ceil() as usize).max(1) - 1)
+}
+
+// Colors of a tube's contents, one per unit from the bottom,
+// the editor works in whole units
+fn units(tube: &LevelTube) -> Vec<usize> {
+	tube.contents
+		.iter()
+		.flat_map(|c| std::iter::repeat_n(c.color(), (c.amount().round() as usize).max(1)))
+		.collect()
+}
+
+fn set_units(tube: &mut LevelTube, units: &[usize]) {
+	tube.contents.clear();
+	for &color in units {
+		match tube.contents.last_mut() {
+			Some(last) if last.color() == color => *last = ColorTubeContent::new(color, last.amount() + 1.0),
+			_ => tube.contents.push(ColorTubeContent::new(color, 1.0)),
+		}
+	}
+}
+
+// Level being built, the board shows it with the same
+// tubes and layout as in play
+pub struct Editor {
+	pub level: Level,
+	pub selected_tube: Option<usize>,
+	// Palette color painted by clicks, None erases
+	pub paint: Option<usize>,
+	// Result of the last check or save
+	pub status: Option<String>,
+}
+
+impl Editor {
+	pub fn new(level: Level) -> Self {
+		Self {
+			level,
+			selected_tube: None,
+			paint: Some(0),
+			status: None,
+		}
+	}
+
+	fn tube_capacity(&self, index: usize) -> f32 {
+		self.level.tubes[index].capacity.unwrap_or(self.level.capacity)
+	}
+
+	pub fn add_tube(&mut self) {
+		self.level.tubes.push(LevelTube {
+			kind: ColorTubeKind::Normal,
+			capacity: None,
+			contents: Vec::new(),
+		});
+		self.selected_tube = Some(self.level.tubes.len() - 1);
+	}
+
+	// Levels keep at least one tube
+	pub fn remove_tube(&mut self) {
+		if let Some(index) = self.selected_tube {
+			if self.level.tubes.len() > 1 {
+				self.level.tubes.remove(index);
+				self.selected_tube = Some(index.min(self.level.tubes.len() - 1));
+			}
+		}
+	}
+
+	// Contents above the new capacity are removed
+	pub fn set_capacity(&mut self, capacity: f32) {
+		let index = match self.selected_tube {
+			Some(index) => index,
+			None => return,
+		};
+		let capacity = capacity.clamp(1.0, MAX_TUBE_CAPACITY);
+		let tube = &mut self.level.tubes[index];
+		tube.capacity = if capacity == self.level.capacity { None } else { Some(capacity) };
+		let mut tube_units = units(tube);
+		tube_units.truncate(capacity as usize);
+		set_units(tube, &tube_units);
+	}
+
+	// Colored kinds are not set while erasing, as there is no paint color
+	pub fn set_kind(&mut self, kind_index: usize) {
+		let (index, color) = match (self.selected_tube, self.paint) {
+			(Some(index), _) if !kind_uses_color(kind_index) => (index, 0),
+			(Some(index), Some(color)) => (index, color),
+			_ => return,
+		};
+		self.level.tubes[index].kind = match kind_index {
+			1 => ColorTubeKind::PourIn,
+			2 => ColorTubeKind::PourOut,
+			3 => ColorTubeKind::SingleColor(color),
+			4 => ColorTubeKind::Locked(color),
+			_ => ColorTubeKind::Normal,
+		};
+	}
+
+	// Paints the unit at the slot, filling empty units below it with the
+	// same color, or erases the unit and everything above it
+	pub fn paint(&mut self, index: usize, slot: usize) {
+		self.selected_tube = Some(index);
+		let capacity = self.tube_capacity(index) as usize;
+		let tube = &mut self.level.tubes[index];
+		let mut tube_units = units(tube);
+		match self.paint {
+			Some(color) if slot < capacity => {
+				if slot < tube_units.len() {
+					tube_units[slot] = color;
+				} else {
+					tube_units.resize(slot + 1, color);
+				}
+			},
+			Some(_) => {},
+			None => tube_units.truncate(slot),
+		}
+		set_units(tube, &tube_units);
+	}
+
+	// Runs the solver on the level as it would be played
+	pub fn check(&mut self, rules: Rules) {
+		let tubes = self.level.to_tubes(Font::default(), &Rc::new(Theme::default()));
+		let mut totals: Vec<(usize, f32)> = Vec::new();
+		for content in tubes.iter().flat_map(|t| t.contents()) {
+			match totals.iter_mut().find(|(color, _)| *color == content.color()) {
+				Some((_, amount)) => *amount += content.amount(),
+				None => totals.push((content.color(), content.amount())),
+			}
+		}
+		// Compared in whole units, as sums of partial amounts are inexact
+		let totals: Vec<(usize, usize)> = totals.into_iter().map(|(color, amount)| (color, amount.round() as usize)).collect();
+		self.status = Some(if totals.is_empty() {
+			"The level has no colors".to_string()
+		} else if let Some((color, amount)) = totals.iter().find(|(_, amount)| !tubes.iter().any(|t| t.capacity.round() as usize == *amount)) {
+			format!("There is {} {} which fills no tube exactly", amount, color_name(*color))
+		} else {
+			match solver::solve(&tubes, rules) {
+				Ok(solution) if solution.optimal => format!("Solvable in {} moves ({} states explored)", solution.moves.len(), solution.states_explored),
+				Ok(solution) => format!("Solvable in at most {} moves, search limit reached ({} states explored)", solution.moves.len(), solution.states_explored),
+				Err(SolveError::LimitReached) => "Search limit reached without finding a solution".to_string(),
+				Err(SolveError::Unsolvable) => "No solution exists".to_string(),
+			}
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn editor(board: &str) -> Editor {
+		Editor::new(Level::parse(board).unwrap())
+	}
+
+	fn check(board: &str) -> String {
+		let mut editor = editor(board);
+		editor.check(Rules::new());
+		editor.status.unwrap()
+	}
+
+	#[test]
+	fn checks_levels() {
+		assert_eq!(check("capacity 2\ntube\ntube"), "The level has no colors");
+		assert_eq!(check("capacity 4\ntube red*3\ntube"), "There is 3 red which fills no tube exactly");
+		assert_eq!(check("capacity 2\ntube red blue\ntube blue red\ntube"), "Solvable in 3 moves (6 states explored)");
+		assert_eq!(check("capacity 2\ntube red blue\ntube blue red"), "No solution exists");
+		// Partial amounts which add up to whole units
+		let board = format!("capacity 1\n{}tube capacity:9\ntube\n", "tube red*0.1 blue*0.9\n".repeat(10));
+		assert!(!check(&board).starts_with("There is"));
+	}
+
+	#[test]
+	fn eraser_sets_only_uncolored_kinds() {
+		let mut editor = editor("capacity 2\ntube red\ntube");
+		editor.selected_tube = Some(1);
+		editor.paint = None;
+		editor.set_kind(4);
+		assert_eq!(editor.level.tubes[1].kind, ColorTubeKind::Normal);
+		editor.set_kind(2);
+		assert_eq!(editor.level.tubes[1].kind, ColorTubeKind::PourOut);
+		editor.paint = Some(5);
+		editor.set_kind(3);
+		assert_eq!(editor.level.tubes[1].kind, ColorTubeKind::SingleColor(5));
+	}
+}
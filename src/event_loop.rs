@@ -0,0 +1,102 @@
+use ggez::{input, Context, GameResult};
+use ggez::event::{self, Axis, Button, EventHandler, EventsLoop};
+use winit::{ElementState, Event, KeyboardInput, MouseScrollDelta, TouchPhase, WindowEvent};
+
+// Events which ggez does not forward to EventHandler
+pub trait ExtraEventHandler {
+	fn touch_event(&mut self, _ctx: &mut Context, _phase: TouchPhase, _id: u64, _x: f32, _y: f32) {}
+	fn gamepad_button_event(&mut self, _ctx: &mut Context, _btn: Button, _pressed: bool, _id: usize) {}
+	fn gamepad_axis_changed_event(&mut self, _ctx: &mut Context, _axis: Axis, _value: f32, _id: usize) {}
+}
+
+// Copy of ggez::event::run from ggez 0.5.1, compare with it when
+// updating ggez. It is needed because ggez drops WindowEvent::Touch,
+// which goes to touch_event here. Only ggez can create the GamepadId
+// of EventHandler's gamepad events, so gilrs events go to the
+// ExtraEventHandler gamepad events with raw gamepad IDs instead.
+// gamepad is the gamepad module setting the Context was built with,
+// which ggez keeps private, reading events with it off panics.
+// The timer tick and process_event calls keep ggez's internal state
+// updated, as in the original.
+pub fn run<S>(ctx: &mut Context, events_loop: &mut EventsLoop, state: &mut S, gamepad: bool) -> GameResult
+where
+	S: EventHandler + ExtraEventHandler,
+{
+	while ctx.continuing {
+		ctx.timer_context.tick();
+		events_loop.poll_events(|event| {
+			ctx.process_event(&event);
+			if let Event::WindowEvent { event, .. } = event {
+				match event {
+					WindowEvent::Resized(logical_size) => {
+						state.resize_event(ctx, logical_size.width as f32, logical_size.height as f32);
+					},
+					WindowEvent::CloseRequested if !state.quit_event(ctx) => {
+						event::quit(ctx);
+					},
+					WindowEvent::Focused(gained) => {
+						state.focus_event(ctx, gained);
+					},
+					WindowEvent::ReceivedCharacter(ch) => {
+						state.text_input_event(ctx, ch);
+					},
+					WindowEvent::KeyboardInput {
+						input: KeyboardInput {
+							state: element_state,
+							virtual_keycode: Some(keycode),
+							modifiers,
+							..
+						},
+						..
+					} => match element_state {
+						ElementState::Pressed => {
+							let repeat = input::keyboard::is_key_repeated(ctx);
+							state.key_down_event(ctx, keycode, modifiers.into(), repeat);
+						},
+						ElementState::Released => {
+							state.key_up_event(ctx, keycode, modifiers.into());
+						},
+					},
+					WindowEvent::MouseWheel { delta, .. } => {
+						let (x, y) = match delta {
+							MouseScrollDelta::LineDelta(x, y) => (x, y),
+							MouseScrollDelta::PixelDelta(pos) => (pos.x as f32, pos.y as f32),
+						};
+						state.mouse_wheel_event(ctx, x, y);
+					},
+					WindowEvent::MouseInput { state: element_state, button, .. } => {
+						let position = input::mouse::position(ctx);
+						match element_state {
+							ElementState::Pressed => state.mouse_button_down_event(ctx, button, position.x, position.y),
+							ElementState::Released => state.mouse_button_up_event(ctx, button, position.x, position.y),
+						}
+					},
+					WindowEvent::CursorMoved { .. } => {
+						let position = input::mouse::position(ctx);
+						let delta = input::mouse::delta(ctx);
+						state.mouse_motion_event(ctx, position.x, position.y, delta.x, delta.y);
+					},
+					WindowEvent::Touch(touch) => {
+						state.touch_event(ctx, touch.phase, touch.id, touch.location.x as f32, touch.location.y as f32);
+					},
+					_ => {},
+				}
+			}
+		});
+
+		if gamepad {
+			while let Some(gilrs::Event { id, event, .. }) = ctx.gamepad_context.next_event() {
+				match event {
+					gilrs::EventType::ButtonPressed(button, _) => state.gamepad_button_event(ctx, button, true, id.into()),
+					gilrs::EventType::ButtonReleased(button, _) => state.gamepad_button_event(ctx, button, false, id.into()),
+					gilrs::EventType::AxisChanged(axis, value, _) => state.gamepad_axis_changed_event(ctx, axis, value, id.into()),
+					_ => {},
+				}
+			}
+		}
+
+		state.update(ctx)?;
+		state.draw(ctx)?;
+	}
+	Ok(())
+}
@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::rc::Rc;
+use ggez::{GameError, GameResult};
+use ggez::graphics::{Font, Rect};
+use crate::color_tube::{self, ColorTube};
+use crate::layout::Layout;
+use crate::render::Canvas;
+use crate::replay::{Replay, ReplayMove};
+use crate::solver;
+use crate::theme::Theme;
+use crate::smallest_factor;
+
+const EXPORT_WIDTH: u32 = 700;
+const EXPORT_HEIGHT: u32 = 650;
+const EXPORT_MARGIN: f32 = 40.0;
+
+// Time each frame is shown in a GIF, in hundredths of a second
+const GIF_FRAME_DELAY: u16 = 50;
+
+// Board to render, with the tube being poured from highlighted
+struct Frame {
+	tubes: Vec<ColorTube>,
+	source: Option<usize>,
+}
+
+fn render(frame: &Frame, theme: &Theme) -> Canvas {
+	let mut tubes = frame.tubes.clone();
+	let area = Rect::new(EXPORT_MARGIN, EXPORT_MARGIN, EXPORT_WIDTH as f32 - EXPORT_MARGIN * 2.0, EXPORT_HEIGHT as f32 - EXPORT_MARGIN * 2.0);
+	let capacity = tubes.iter().map(|t| t.capacity).fold(1.0, f32::max);
+	let preferred_cols = (tubes.len() as f32 / smallest_factor(tubes.len()) as f32).ceil() as usize;
+	let layout = Layout::compute(area, tubes.len(), capacity, preferred_cols, 1.0);
+
+	let mut canvas = Canvas::new(EXPORT_WIDTH, EXPORT_HEIGHT, theme.background);
+	for (i, tube) in tubes.iter_mut().enumerate() {
+		tube.dimensions = layout.tube_rect(i);
+		tube.clicked = frame.source == Some(i);
+		canvas.draw_tube(tube);
+	}
+	canvas
+}
+
+// Steps through the replay, or a solution found by the solver
+// if the replay has no moves
+fn frames(replay: &Replay, theme: &Rc<Theme>) -> GameResult<Vec<Frame>> {
+	let mut tubes = replay.level.to_tubes(Font::default(), theme);
+	color_tube::update_locks(&mut tubes);
+
+	let moves: Vec<ReplayMove> = if replay.events.is_empty() {
+		let solution = solver::solve(&tubes, replay.rules)
+			.map_err(|e| GameError::ResourceLoadError(format!("no solution found: {}", e.description())))?;
+		solution.moves
+			.into_iter()
+			.map(|(from, to)| ReplayMove::Pour { from, to, single_unit: false })
+			.collect()
+	} else {
+		replay.events.iter().map(|e| e.action).collect()
+	};
+
+	let mut frames = vec![Frame { tubes: tubes.clone(), source: None }];
+	let mut history = Vec::new();
+	for action in moves {
+		match action {
+			ReplayMove::Pour { from, to, single_unit } => {
+				frames.push(Frame { tubes: tubes.clone(), source: Some(from) });
+				let snapshot = tubes.clone();
+				if color_tube::pour(&mut tubes, from, to, single_unit || replay.rules.partial_pour).is_ok() {
+					history.push(snapshot);
+				}
+				color_tube::update_locks(&mut tubes);
+			},
+			ReplayMove::Undo => if let Some(previous) = history.pop() {
+				tubes = previous;
+			},
+		}
+		frames.push(Frame { tubes: tubes.clone(), source: None });
+	}
+	Ok(frames)
+}
+
+fn write_gif(canvases: &mut [Canvas], path: &str) -> GameResult {
+	let encode_error = |e: gif::EncodingError| GameError::RenderError(e.to_string());
+	let file = BufWriter::new(File::create(path)?);
+	let mut encoder = gif::Encoder::new(file, EXPORT_WIDTH as u16, EXPORT_HEIGHT as u16, &[]).map_err(encode_error)?;
+	encoder.set_repeat(gif::Repeat::Infinite).map_err(encode_error)?;
+	for canvas in canvases {
+		let mut frame = gif::Frame::from_rgba_speed(canvas.width as u16, canvas.height as u16, &mut canvas.pixels, 10);
+		frame.delay = GIF_FRAME_DELAY;
+		encoder.write_frame(&frame).map_err(encode_error)?;
+	}
+	Ok(())
+}
+
+fn write_pngs(canvases: &[Canvas], prefix: &str) -> GameResult {
+	let encode_error = |e: png::EncodingError| GameError::RenderError(e.to_string());
+	for (i, canvas) in canvases.iter().enumerate() {
+		let file = BufWriter::new(File::create(format!("{}-{:04}.png", prefix, i))?);
+		let mut encoder = png::Encoder::new(file, canvas.width, canvas.height);
+		encoder.set_color(png::ColorType::RGBA);
+		encoder.set_depth(png::BitDepth::Eight);
+		let mut writer = encoder.write_header().map_err(encode_error)?;
+		writer.write_image_data(&canvas.pixels).map_err(encode_error)?;
+	}
+	Ok(())
+}
+
+// Renders a level or replay file to an animated GIF if the output ends
+// in .gif, otherwise to a sequence of PNG files named <output>-NNNN.png
+pub fn export(input: &str, output: &str) -> GameResult {
+	let replay = Replay::parse(&std::fs::read_to_string(input)?)?;
+	let theme = Rc::new(Theme::default());
+	let mut canvases: Vec<Canvas> = frames(&replay, &theme)?
+		.iter()
+		.map(|frame| render(frame, &theme))
+		.collect();
+	if output.ends_with(".gif") {
+		write_gif(&mut canvases, output)
+	} else {
+		write_pngs(&canvases, output)
+	}
+}
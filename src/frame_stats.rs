@@ -0,0 +1,49 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// Number of recent frames the stats are computed over
+const SAMPLE_COUNT: usize = 120;
+
+#[derive(Debug, Clone)]
+pub struct FrameStats {
+	// Frame times in milliseconds, oldest first
+	frame_times: VecDeque<f32>,
+	// Tube meshes rebuilt during the last frame
+	pub mesh_rebuilds: usize,
+}
+
+impl FrameStats {
+	pub fn new() -> Self {
+		Self {
+			frame_times: VecDeque::with_capacity(SAMPLE_COUNT),
+			mesh_rebuilds: 0,
+		}
+	}
+
+	pub fn record_frame(&mut self, delta: Duration) {
+		if self.frame_times.len() == SAMPLE_COUNT {
+			self.frame_times.pop_front();
+		}
+		self.frame_times.push_back(delta.as_secs_f32() * 1000.0);
+	}
+
+	pub fn frame_times(&self) -> Vec<f32> {
+		self.frame_times.iter().cloned().collect()
+	}
+
+	pub fn avg_ms(&self) -> f32 {
+		if self.frame_times.is_empty() {
+			return 0.0;
+		}
+		self.frame_times.iter().sum::<f32>() / self.frame_times.len() as f32
+	}
+
+	pub fn max_ms(&self) -> f32 {
+		self.frame_times.iter().cloned().fold(0.0, f32::max)
+	}
+
+	pub fn fps(&self) -> f32 {
+		let avg_ms = self.avg_ms();
+		if avg_ms > 0.0 { 1000.0 / avg_ms } else { 0.0 }
+	}
+}
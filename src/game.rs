@@ -0,0 +1,85 @@
+use crate::board::{Activation, Board, PourLimit};
+use crate::generator::ShareCode;
+use crate::level::Level;
+use crate::level_pack::{PackLevel, PackLevelSource};
+use crate::progression::{Grader, LevelParams, Progression};
+use crate::rules::Rules;
+use crate::scripting::Mods;
+use crate::settings::Settings;
+
+// Level starts and pours shared by the window and the headless game
+
+// Level to play, and the share code and shortest solution it has
+pub struct LevelStart {
+	pub level: Level,
+	pub share_code: Option<ShareCode>,
+	pub solution_moves: Option<usize>,
+}
+
+// Level size from the progression, with the colors limited to max_colors
+pub fn level_params(progression: &Progression, level: usize, settings: &Settings, max_colors: usize) -> LevelParams {
+	let mut params = progression.params(level, settings);
+	params.colors = params.colors.min(max_colors).max(1);
+	params
+}
+
+// Picks the custom level if any, then the pack level, then the level
+// generated by the mods or the progression, None while it is graded
+#[allow(clippy::too_many_arguments)]
+pub fn start_level(
+	number: usize,
+	custom_level: Option<&Level>,
+	share_code: Option<ShareCode>,
+	pack_level: Option<&PackLevel>,
+	params: &LevelParams,
+	rules: Rules,
+	mods: &mut Mods,
+	grader: &mut Grader,
+) -> Option<LevelStart> {
+	if let Some(level) = custom_level {
+		// Keep the share code of a level loaded from one
+		let share_code = share_code.filter(|c| c.level() == *level);
+		return Some(LevelStart { level: level.clone(), share_code, solution_moves: None });
+	}
+
+	if let Some(pack_level) = pack_level {
+		let share_code = match pack_level.source {
+			PackLevelSource::Seed { seed, colors, capacity, spare_tubes, special_tubes } => Some(ShareCode::new(seed, colors, capacity, spare_tubes, special_tubes)),
+			PackLevelSource::Board(_) => None,
+		};
+		return Some(LevelStart { level: pack_level.level(), share_code, solution_moves: None });
+	}
+
+	if let Some(level) = mods.generate_level(number, params.colors, params.capacity) {
+		return Some(LevelStart { level, share_code: None, solution_moves: None });
+	}
+	let generated = grader.generate(number, params, rules)?;
+	Some(LevelStart {
+		level: generated.level,
+		share_code: Some(ShareCode::new(generated.seed, params.colors, params.capacity, params.spare_tubes, params.special_tubes)),
+		solution_moves: generated.moves,
+	})
+}
+
+// Asks the mods about the pour which activating the tube would make
+fn fill_rule(board: &Board, tube_index: usize, single_unit: bool, mods: &mut Mods) -> PourLimit {
+	match board.selected_tube {
+		Some(from) if from != tube_index => mods.fill_rule(&board.tubes, from, tube_index, single_unit),
+		_ => PourLimit::Unlimited,
+	}
+}
+
+// Selects the tube or pours into it, within the limit set by the mods
+pub fn activate(board: &mut Board, tube_index: usize, single_unit: bool, rules: Rules, keep_selection_on_error: bool, mods: &mut Mods) -> Activation {
+	let limit = fill_rule(board, tube_index, single_unit || rules.partial_pour, mods);
+	board.activate(tube_index, single_unit, rules, keep_selection_on_error, limit)
+}
+
+// Pours directly from one tube to another, within the limit set by the mods
+pub fn pour(board: &mut Board, from: usize, to: usize, single_unit: bool, rules: Rules, mods: &mut Mods) -> Activation {
+	let limit = mods.fill_rule(&board.tubes, from, to, single_unit || rules.partial_pour);
+	match board.pour(from, to, single_unit, rules, limit) {
+		Ok(amount) => Activation::Poured { from, to, amount },
+		Err((tube, error)) => Activation::Rejected { tube, error },
+	}
+}
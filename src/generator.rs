@@ -0,0 +1,260 @@
+use std::fmt;
+use std::rc::Rc;
+use ggez::graphics::Font;
+use crate::colors::LIQUID_COLOR_NAMES;
+use crate::color_tube::{ColorTubeContent, ColorTubeKind};
+use crate::editor::MAX_TUBE_CAPACITY;
+use crate::level::{Level, LevelTube};
+use crate::rng::LevelRng;
+use crate::rules::Rules;
+use crate::solver;
+use crate::theme::Theme;
+
+// Version of the level generator and of the random numbers it uses,
+// levels from the same seed are only the same within a version.
+// Stored in share codes, saved levels and replays, and level packs
+pub const GENERATOR_VERSION: u32 = 1;
+
+// Most empty tubes a generated level can have
+pub const MAX_SPARE_TUBES: usize = 8;
+
+pub fn level_seed(level: usize) -> u64 {
+	level as u64
+}
+
+// Seeds of alternative boards for a level, the first candidate
+// is the level's own seed
+pub fn candidate_seed(level: usize, candidate: usize) -> u64 {
+	level_seed(level) ^ ((candidate as u64) << 32)
+}
+
+// Shuffles of a board with special tubes tried before giving up on them
+const MAX_SPECIAL_SHUFFLES: usize = 10;
+
+// Generates the shuffled level for a seed, with empty spare tubes
+// and optionally one of each special tube kind
+pub fn generate(seed: u64, color_count: usize, tube_capacity: f32, spare_tubes: usize, special_tubes: bool) -> Level {
+	let mut rng = LevelRng::new(seed);
+	if special_tubes {
+		// Special tubes can make a board unsolvable, those are shuffled
+		// again until the solver finds a solution
+		for _ in 0..MAX_SPECIAL_SHUFFLES {
+			let level = shuffle_level(&mut rng, color_count, tube_capacity, spare_tubes, true);
+			let tubes = level.to_tubes(Font::default(), &Rc::new(Theme::default()));
+			if solver::find_solution(&tubes, Rules::new()).is_ok() {
+				return level;
+			}
+		}
+	}
+	// Without special tubes, or when no shuffle with them was solved
+	shuffle_level(&mut rng, color_count, tube_capacity, spare_tubes, false)
+}
+
+fn shuffle_level(rng: &mut LevelRng, color_count: usize, tube_capacity: f32, spare_tubes: usize, special_tubes: bool) -> Level {
+	// Full tubes of every color are drained a unit at a time, the n-th
+	// unit of each going to the n-th tube, which are shuffled after
+	// every round
+	let mut colors: Vec<usize> = (0..color_count).collect();
+	rng.shuffle(&mut colors);
+	let mut tubes: Vec<(ColorTubeKind, Vec<usize>)> = vec![(ColorTubeKind::Normal, Vec::new()); color_count];
+	for _ in 0..tube_capacity.ceil() as usize {
+		for (tube, &color) in tubes.iter_mut().zip(&colors) {
+			tube.1.push(color);
+		}
+		rng.shuffle(&mut tubes);
+	}
+
+	if special_tubes && color_count >= 2 {
+		let indices: Vec<usize> = (0..tubes.len()).collect();
+		let special_indices = rng.choose_multiple(&indices, 2);
+
+		// Drain-only tube, which has to be emptied
+		tubes[special_indices[0]].0 = ColorTubeKind::PourOut;
+
+		// Tube locked by a color it does not contain,
+		// so that the color can be completed elsewhere
+		let locked_index = special_indices[1];
+		let lock_colors: Vec<usize> = (0..color_count)
+			.filter(|color| !tubes[locked_index].1.contains(color))
+			.collect();
+		if let Some(&lock_color) = rng.choose(&lock_colors) {
+			tubes[locked_index].0 = ColorTubeKind::Locked(lock_color);
+		}
+	}
+
+	for _ in 0..spare_tubes {
+		tubes.push((ColorTubeKind::Normal, Vec::new()));
+	}
+
+	if special_tubes {
+		// Fill-only and single color spare tubes
+		let only_color = rng.below(color_count.max(1));
+		tubes.push((ColorTubeKind::PourIn, Vec::new()));
+		tubes.push((ColorTubeKind::SingleColor(only_color), Vec::new()));
+	}
+
+	Level {
+		capacity: tube_capacity,
+		tubes: tubes.into_iter().map(|(kind, units)| {
+			let mut contents: Vec<ColorTubeContent> = Vec::new();
+			for color in units {
+				match contents.last_mut() {
+					Some(last) if last.color() == color => *last = ColorTubeContent::new(color, last.amount() + 1.0),
+					_ => contents.push(ColorTubeContent::new(color, 1.0)),
+				}
+			}
+			LevelTube { kind, capacity: None, contents }
+		}).collect(),
+	}
+}
+
+// Identifies a generated level in any build with the same generator version:
+//
+//   <generator version>-<seed>-<colors>-<capacity>-<spare tubes>[-s]
+//
+// ending in -s when the level has special tubes, e.g. 1-57-12-4-2
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShareCode {
+	pub version: u32,
+	pub seed: u64,
+	pub colors: usize,
+	pub capacity: usize,
+	pub spare_tubes: usize,
+	pub special_tubes: bool,
+}
+
+impl ShareCode {
+	pub fn new(seed: u64, colors: usize, capacity: f32, spare_tubes: usize, special_tubes: bool) -> Self {
+		Self {
+			version: GENERATOR_VERSION,
+			seed,
+			colors,
+			capacity: capacity as usize,
+			spare_tubes,
+			special_tubes,
+		}
+	}
+
+	pub fn parse(s: &str) -> Result<Self, String> {
+		let parts: Vec<&str> = s.trim().split('-').collect();
+		let special_tubes = match parts.len() {
+			5 => false,
+			6 if parts[5] == "s" => true,
+			_ => return Err(format!("invalid share code {:?}", s)),
+		};
+		let invalid = || format!("invalid share code {:?}", s);
+		let number = |i: usize| parts[i].parse::<usize>().map_err(|_| invalid());
+		let code = Self {
+			version: parts[0].parse::<u32>().map_err(|_| invalid())?,
+			seed: parts[1].parse::<u64>().map_err(|_| invalid())?,
+			colors: number(2)?,
+			capacity: number(3)?,
+			spare_tubes: number(4)?,
+			special_tubes,
+		};
+		if code.version != GENERATOR_VERSION {
+			return Err(format!("share code is for level generator version {}, this game has version {}", code.version, GENERATOR_VERSION));
+		}
+		if code.colors < 1 || code.colors > LIQUID_COLOR_NAMES.len()
+			|| code.capacity < 1 || code.capacity as f32 > MAX_TUBE_CAPACITY
+			|| code.spare_tubes > MAX_SPARE_TUBES {
+			return Err(format!("share code {:?} has an invalid level size", s));
+		}
+		Ok(code)
+	}
+
+	pub fn level(&self) -> Level {
+		generate(self.seed, self.colors, self.capacity as f32, self.spare_tubes, self.special_tubes)
+	}
+}
+
+impl fmt::Display for ShareCode {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}-{}-{}-{}-{}", self.version, self.seed, self.colors, self.capacity, self.spare_tubes)?;
+		if self.special_tubes {
+			write!(f, "-s")?;
+		}
+		Ok(())
+	}
+}
+
+// Compares generated levels with golden boards, each listed after the
+// share code generating it:
+//
+//   code 1-57-12-4-2
+//   capacity 4
+//   tube ...
+//
+// returns the number of boards checked
+pub fn check_golden(s: &str) -> Result<usize, String> {
+	let mut cases: Vec<(ShareCode, String)> = Vec::new();
+	for line in s.lines() {
+		let content = line.split('#').next().unwrap_or("").trim();
+		if let Some(code) = content.strip_prefix("code ") {
+			cases.push((ShareCode::parse(code)?, String::new()));
+		} else if let Some((_, board)) = cases.last_mut() {
+			board.push_str(line);
+			board.push('\n');
+		}
+	}
+	for (code, board) in &cases {
+		let expected = Level::parse(board).map_err(|e| format!("{}: {}", code, e))?;
+		let generated = code.level();
+		if generated != expected {
+			return Err(format!("{} generated a different board:\n{}", code, generated));
+		}
+	}
+	Ok(cases.len())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn generator_matches_golden_boards() {
+		assert_eq!(check_golden(include_str!("../generator_golden.txt")), Ok(32));
+	}
+
+	#[test]
+	fn share_codes_round_trip() {
+		for s in &["1-57-12-4-2", "1-18446744073709551615-1-8-0-s", "1-0-3-1-8"] {
+			let code = ShareCode::parse(s).unwrap();
+			assert_eq!(code.to_string(), *s);
+			assert_eq!(ShareCode::parse(&code.to_string()), Ok(code));
+		}
+		let code = ShareCode::new(57, 12, 4.0, 2, true);
+		assert_eq!(code.to_string(), "1-57-12-4-2-s");
+		assert_eq!(code.level(), generate(57, 12, 4.0, 2, true));
+	}
+
+	#[test]
+	fn rejects_invalid_share_codes() {
+		for s in &["", "1-57-12-4", "1-57-12-4-2-x", "1-57-12-4-2-s-s", "1--12-4-2", "1-57-twelve-4-2", "1-57-12-4--2"] {
+			assert_eq!(ShareCode::parse(s), Err(format!("invalid share code {:?}", s)));
+		}
+		// Versions are not truncated to fit
+		assert!(ShareCode::parse("4294967297-57-12-4-2").unwrap_err().starts_with("invalid share code"));
+		assert_eq!(ShareCode::parse("2-57-12-4-2"), Err("share code is for level generator version 2, this game has version 1".to_string()));
+		for s in &["1-57-0-4-2", "1-57-13-4-2", "1-57-12-0-2", "1-57-12-9-2", "1-57-12-4-9", "1-57-12-4-18446744073709551615"] {
+			assert_eq!(ShareCode::parse(s), Err(format!("share code {:?} has an invalid level size", s)));
+		}
+	}
+
+	#[test]
+	fn special_boards_are_solvable() {
+		for seed in 0..20 {
+			let level = generate(seed, 6, 4.0, 2, true);
+			let tubes = level.to_tubes(Font::default(), &Rc::new(Theme::default()));
+			assert!(solver::find_solution(&tubes, Rules::new()).is_ok(), "seed {} is unsolvable:\n{}", seed, level);
+		}
+	}
+
+	#[test]
+	fn gives_up_on_special_tubes_without_a_solution() {
+		// No shuffle of this board with special tubes is solved
+		let level = generate(1, 12, 4.0, 0, true);
+		assert_eq!(level.tubes.len(), 12);
+		assert!(level.tubes.iter().all(|t| t.kind == ColorTubeKind::Normal));
+	}
+}
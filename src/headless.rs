@@ -0,0 +1,138 @@
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+use ggez::GameResult;
+use ggez::graphics::Font;
+use crate::board::Board;
+use crate::command::{self, Command, LevelCode};
+use crate::game;
+use crate::generator::ShareCode;
+use crate::level::Level;
+use crate::progression::{Grader, Progression};
+use crate::scripting::Mods;
+use crate::settings::Settings;
+use crate::theme::Theme;
+
+// Game without a window, driven by commands on stdin. Mods are read
+// from a folder given on the command line, as the window's filesystem
+// context is not available
+struct Headless {
+	board: Board,
+	level: usize,
+	custom_level: Option<Level>,
+	share_code: Option<ShareCode>,
+	// Default settings, as there is no filesystem context to load them from
+	settings: Settings,
+	// Built-in progression, for the same reason
+	progression: Progression,
+	// Grades levels before answering, there is no window to keep drawing
+	grader: Grader,
+	mods: Mods,
+	theme: Rc<Theme>,
+}
+
+impl Headless {
+	fn new(custom_level: Option<Level>, mods_folder: Option<&Path>) -> Self {
+		let mut mods = Mods::new();
+		if let Some(folder) = mods_folder {
+			mods.load_dir(folder);
+		}
+		let mut game = Self {
+			board: Board::new(4.0, Vec::new()),
+			level: 1,
+			custom_level,
+			share_code: None,
+			settings: Settings::new(),
+			progression: Progression::builtin(),
+			grader: Grader::blocking(),
+			mods,
+			theme: Rc::new(Theme::default()),
+		};
+		game.new_tubes();
+		game
+	}
+
+	fn new_tubes(&mut self) {
+		let params = game::level_params(&self.progression, self.level, &self.settings, self.theme.palette.len());
+		let start = game::start_level(self.level, self.custom_level.as_ref(), self.share_code, None, &params, self.settings.rules, &mut self.mods, &mut self.grader)
+			.expect("blocking grader returned no level");
+		self.share_code = start.share_code;
+		self.board.set_tubes(start.level.capacity, start.level.to_tubes(Font::default(), &self.theme));
+	}
+
+	// Mod messages go to stderr, as stdout only has responses
+	fn print_mods_log(&mut self) {
+		for line in self.mods.log.drain(..) {
+			eprintln!("{}", line);
+		}
+	}
+
+	fn run(&mut self, command: Command) -> Result<(), String> {
+		match command {
+			Command::Pour { from, to, single_unit } => {
+				let (rules, keep_selection_on_error) = (self.settings.rules, self.settings.keep_selection_on_error);
+				let (board, mods) = (&mut self.board, &mut self.mods);
+				board.selected_tube = None;
+				let tube_count = board.tubes.len();
+				command::pour_with(from, to, tube_count, |tube| Some(game::activate(board, tube, single_unit, rules, keep_selection_on_error, mods)))
+			},
+			Command::Undo => if self.board.undo() {
+				Ok(())
+			} else {
+				Err("nothing to undo".to_string())
+			},
+			Command::Restart => {
+				self.new_tubes();
+				Ok(())
+			},
+			Command::Load(LevelCode::Number(number)) => {
+				self.level = number;
+				self.custom_level = None;
+				self.new_tubes();
+				Ok(())
+			},
+			Command::Load(LevelCode::Share(code)) => {
+				self.share_code = Some(code);
+				self.custom_level = Some(code.level());
+				self.new_tubes();
+				Ok(())
+			},
+			Command::Load(LevelCode::Level(level)) => {
+				self.custom_level = Some(level);
+				self.new_tubes();
+				Ok(())
+			},
+			Command::State => Ok(()),
+		}
+	}
+}
+
+// Answers each command on stdin with a JSON line on stdout,
+// starting with the state of the first level, until stdin is
+// closed or "quit" is received
+pub fn run(custom_level: Option<Level>, mods_folder: Option<&Path>) -> GameResult {
+	let mut game = Headless::new(custom_level, mods_folder);
+	game.print_mods_log();
+	let stdin = io::stdin();
+	let stdout = io::stdout();
+	let mut out = stdout.lock();
+	writeln!(out, "{}", command::state_response(game.level, &game.board))?;
+	out.flush()?;
+
+	for line in stdin.lock().lines() {
+		let line = line?;
+		match line.trim() {
+			"" => continue,
+			"quit" => break,
+			_ => {},
+		}
+		let response = match Command::parse(&line).and_then(|c| game.run(c)) {
+			Ok(()) => command::state_response(game.level, &game.board),
+			Err(error) => command::error_response(&error),
+		};
+		game.print_mods_log();
+		writeln!(out, "{}", response)?;
+		out.flush()?;
+	}
+	Ok(())
+}
@@ -1,16 +1,16 @@
-use ggez::event::{KeyCode, KeyMods, MouseButton};
+use ggez::event::{Axis, Button, KeyCode, KeyMods, MouseButton};
 use ggez::graphics;
 use ggez::Context;
 
 use gfx_core::{handle::RenderTargetView, memory::Typed};
-use gfx_device_gl;
 
-use imgui::{Key, FontSource, FontConfig, Ui};
+use imgui::{BackendFlags, ConfigFlags, Key, FontSource, FontConfig, NavInput, Ui};
 use imgui_gfx_renderer::{Renderer, RendererError, Shaders};
 
 use std::time::Instant;
 
 use crate::MenuState;
+use crate::theme::Theme;
 
 #[derive(Copy, Clone, PartialEq, Debug, Default)]
 struct MouseState {
@@ -26,6 +26,7 @@ pub struct ImGuiWrapper {
 	pub renderer: Renderer<gfx_core::format::Srgba8, gfx_device_gl::Resources>,
 	last_frame: Instant,
 	mouse_state: MouseState,
+	nav_inputs: [f32; NavInput::COUNT],
 }
 
 impl ImGuiWrapper {
@@ -94,18 +95,31 @@ impl ImGuiWrapper {
 			io[Key::Z] = KeyCode::Z as _;
 		}
 
+		// Gamepad navigation
+		{
+			let io = imgui.io_mut();
+			io.config_flags |= ConfigFlags::NAV_ENABLE_GAMEPAD;
+			io.backend_flags |= BackendFlags::HAS_GAMEPAD;
+		}
+
 		// Create instance
 		Self {
 			imgui,
 			renderer,
 			last_frame: Instant::now(),
 			mouse_state: MouseState::default(),
+			nav_inputs: [0.0; NavInput::COUNT],
 		}
 	}
 
+	pub fn set_theme(&mut self, theme: &Theme) {
+		theme.imgui.apply(self.imgui.style_mut());
+	}
+
 	pub fn render<'a, F: FnMut(&mut Ui, &mut MenuState) + 'a>(&mut self, ctx: &mut Context, hidpi_factor: f32, state: &mut MenuState, mut run_ui: F) -> Result<(), RendererError> {
-		// Update mouse
+		// Update mouse and gamepad
 		self.update_mouse();
+		self.imgui.io_mut().nav_inputs[..NavInput::COUNT].copy_from_slice(&self.nav_inputs);
 
 		// Create new frame
 		let now = Instant::now();
@@ -117,7 +131,7 @@ impl ImGuiWrapper {
 		self.imgui.io_mut().display_size = [draw_width, draw_height];
 		self.imgui.io_mut().display_framebuffer_scale = [hidpi_factor, hidpi_factor];
 		self.imgui.io_mut().delta_time = delta_s;
-		self.imgui.io_mut().font_global_scale = (1.0 / hidpi_factor) as f32;
+		self.imgui.io_mut().font_global_scale = 1.0 / hidpi_factor;
 
 		let mut ui = self.imgui.frame();
 		run_ui(&mut ui, state);
@@ -193,6 +207,40 @@ impl ImGuiWrapper {
 		self.imgui.io_mut().keys_down[key as usize] = false;
 	}
 
+	pub fn update_gamepad_button(&mut self, button: Button, pressed: bool) {
+		let nav_input = match button {
+			Button::South => NavInput::Activate,
+			Button::East => NavInput::Cancel,
+			Button::North => NavInput::Input,
+			Button::West => NavInput::Menu,
+			Button::DPadLeft => NavInput::DpadLeft,
+			Button::DPadRight => NavInput::DpadRight,
+			Button::DPadUp => NavInput::DpadUp,
+			Button::DPadDown => NavInput::DpadDown,
+			Button::LeftTrigger => NavInput::FocusPrev,
+			Button::RightTrigger => NavInput::FocusNext,
+			Button::LeftTrigger2 => NavInput::TweakSlow,
+			Button::RightTrigger2 => NavInput::TweakFast,
+			_ => return,
+		};
+		self.nav_inputs[nav_input as usize] = if pressed { 1.0 } else { 0.0 };
+	}
+
+	pub fn update_gamepad_axis(&mut self, axis: Axis, value: f32) {
+		let (negative, positive) = match axis {
+			Axis::LeftStickX => (NavInput::LStickLeft, NavInput::LStickRight),
+			Axis::LeftStickY => (NavInput::LStickDown, NavInput::LStickUp),
+			_ => return,
+		};
+		self.nav_inputs[negative as usize] = (-value).max(0.0);
+		self.nav_inputs[positive as usize] = value.max(0.0);
+	}
+
+	// Whether a text field has keyboard focus
+	pub fn wants_text_input(&self) -> bool {
+		self.imgui.io().want_text_input
+	}
+
 	pub fn update_text(&mut self, val: char) {
 		self.imgui.io_mut().add_input_character(val);
 	}
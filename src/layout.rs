@@ -0,0 +1,84 @@
+use ggez::graphics::Rect;
+
+// Tube widths, in logical pixels
+pub const MIN_TUBE_WIDTH: f32 = 24.0;
+pub const MAX_TUBE_WIDTH: f32 = 80.0;
+
+// Margin between tubes and height of the labels above tubes,
+// relative to the tube width
+const TUBE_MARGIN_RATIO: f32 = 0.5;
+const LABEL_HEIGHT_RATIO: f32 = 0.4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layout {
+	pub cols: usize,
+	pub rows: usize,
+	pub tube_w: f32,
+	pub tube_h: f32,
+	pub tube_margin: f32,
+	pub label_h: f32,
+	x: f32,
+	y: f32,
+}
+
+fn snap(value: f32, hidpi_factor: f32) -> f32 {
+	(value * hidpi_factor).floor() / hidpi_factor
+}
+
+impl Layout {
+	// Picks the column count giving the largest tubes which fit in the area,
+	// preferring rows of equal length and then columns close to preferred_cols
+	pub fn compute(area: Rect, tube_count: usize, capacity: f32, preferred_cols: usize, hidpi_factor: f32) -> Self {
+		let tube_count = tube_count.max(1);
+		let tie_key = |cols: usize| (!tube_count.is_multiple_of(cols), (cols as isize - preferred_cols as isize).abs());
+
+		let mut best_cols = 1;
+		let mut best_w = 0.0;
+		for cols in 1..=tube_count {
+			let rows = tube_count.div_ceil(cols);
+			let max_w = area.w / (cols as f32 + (cols - 1) as f32 * TUBE_MARGIN_RATIO);
+			let max_h = area.h / (rows as f32 * (LABEL_HEIGHT_RATIO + capacity) + (rows - 1) as f32 * TUBE_MARGIN_RATIO);
+			let w = max_w.min(max_h).min(MAX_TUBE_WIDTH);
+			let better = if (w - best_w).abs() < 0.5 {
+				tie_key(cols) < tie_key(best_cols)
+			} else {
+				w > best_w
+			};
+			if better {
+				best_cols = cols;
+				best_w = w;
+			}
+		}
+
+		let cols = best_cols;
+		let rows = tube_count.div_ceil(cols);
+		let tube_w = snap(best_w.max(MIN_TUBE_WIDTH), hidpi_factor);
+		let tube_h = tube_w * capacity;
+		let tube_margin = snap(tube_w * TUBE_MARGIN_RATIO, hidpi_factor);
+		let label_h = snap(tube_w * LABEL_HEIGHT_RATIO, hidpi_factor);
+		let total_w = cols as f32 * (tube_w + tube_margin) - tube_margin;
+		let total_h = rows as f32 * (label_h + tube_h + tube_margin) - tube_margin;
+
+		Self {
+			cols,
+			rows,
+			tube_w,
+			tube_h,
+			tube_margin,
+			label_h,
+			x: snap((area.x + (area.w - total_w) / 2.0).max(area.x), hidpi_factor),
+			y: snap((area.y + (area.h - total_h) / 2.0).max(area.y), hidpi_factor),
+		}
+	}
+
+	pub fn tube_rect(&self, index: usize) -> Rect {
+		let col = index % self.cols;
+		let row = index / self.cols;
+		Rect::new(
+			self.x + col as f32 * (self.tube_w + self.tube_margin),
+			self.y + row as f32 * (self.label_h + self.tube_h + self.tube_margin) + self.label_h,
+			self.tube_w,
+			self.tube_h
+		)
+	}
+}
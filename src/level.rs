@@ -0,0 +1,197 @@
+use std::fmt;
+use std::rc::Rc;
+use ggez::{GameError, GameResult};
+use ggez::graphics::Font;
+use crate::colors::*;
+use crate::color_tube::{ColorTube, ColorTubeContent, ColorTubeKind};
+use crate::theme::Theme;
+
+// Level file format, one directive per line, contents listed bottom to top:
+//
+//   # comment
+//   capacity 4
+//   tube pink pink*2 blue
+//   tube locked:green red red red red
+//   tube only:red
+//   tube in
+//   tube out cyan yellow
+//   tube capacity:2 red
+//   tube
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelTube {
+	pub kind: ColorTubeKind,
+	// Overrides the capacity of the level
+	pub capacity: Option<f32>,
+	pub contents: Vec<ColorTubeContent>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Level {
+	pub capacity: f32,
+	pub tubes: Vec<LevelTube>,
+}
+
+fn parse_color(s: &str, line_no: usize) -> GameResult<usize> {
+	color_by_name(s).ok_or_else(|| GameError::ResourceLoadError(format!("line {}: unknown color {:?}", line_no, s)))
+}
+
+impl Level {
+	pub fn from_tubes(capacity: f32, tubes: &[ColorTube]) -> Self {
+		Self {
+			capacity,
+			tubes: tubes.iter().map(|tube| LevelTube {
+				kind: tube.kind,
+				capacity: if tube.capacity == capacity { None } else { Some(tube.capacity) },
+				contents: tube.contents().to_vec(),
+			}).collect(),
+		}
+	}
+
+	pub fn to_tubes(&self, font: Font, theme: &Rc<Theme>) -> Vec<ColorTube> {
+		self.tubes.iter().map(|tube| {
+			ColorTube::new(tube.capacity.unwrap_or(self.capacity), tube.contents.clone(), font, theme.clone()).with_kind(tube.kind)
+		}).collect()
+	}
+
+	pub fn parse(s: &str) -> GameResult<Self> {
+		let mut capacity = None;
+		let mut tubes = Vec::new();
+
+		for (i, line) in s.lines().enumerate() {
+			let line_no = i + 1;
+			let line = line.split('#').next().unwrap_or("").trim();
+			let mut words = line.split_whitespace();
+			match words.next() {
+				None => continue,
+				Some("capacity") => {
+					let value = words.next()
+						.and_then(|v| v.parse::<f32>().ok())
+						.filter(|&v| v > 0.0)
+						.ok_or_else(|| GameError::ResourceLoadError(format!("line {}: invalid capacity", line_no)))?;
+					capacity = Some(value);
+				},
+				Some("tube") => {
+					let mut tube = LevelTube {
+						kind: ColorTubeKind::Normal,
+						capacity: None,
+						contents: Vec::new(),
+					};
+					for word in words {
+						let mut parts = word.splitn(2, ':');
+						match (parts.next().unwrap_or(""), parts.next()) {
+							("in", None) => tube.kind = ColorTubeKind::PourIn,
+							("out", None) => tube.kind = ColorTubeKind::PourOut,
+							("only", Some(color)) => tube.kind = ColorTubeKind::SingleColor(parse_color(color, line_no)?),
+							("locked", Some(color)) => tube.kind = ColorTubeKind::Locked(parse_color(color, line_no)?),
+							("capacity", Some(value)) => tube.capacity = Some(value.parse::<f32>().ok()
+								.filter(|&v| v > 0.0)
+								.ok_or_else(|| GameError::ResourceLoadError(format!("line {}: invalid tube capacity {:?}", line_no, value)))?),
+							(content, None) => {
+								let mut parts = content.splitn(2, '*');
+								let color = parse_color(parts.next().unwrap_or(""), line_no)?;
+								let amount = match parts.next() {
+									Some(amount) => amount.parse::<f32>().ok()
+										.filter(|&v| v > 0.0)
+										.ok_or_else(|| GameError::ResourceLoadError(format!("line {}: invalid amount in {:?}", line_no, word)))?,
+									None => 1.0,
+								};
+								let count = tube.contents.len();
+								if count > 0 && tube.contents[count - 1].color() == color {
+									let amount = tube.contents[count - 1].amount() + amount;
+									tube.contents[count - 1] = ColorTubeContent::new(color, amount);
+								} else {
+									tube.contents.push(ColorTubeContent::new(color, amount));
+								}
+							},
+							_ => return Err(GameError::ResourceLoadError(format!("line {}: unknown tube attribute {:?}", line_no, word))),
+						}
+					}
+					tubes.push(tube);
+				},
+				Some(directive) => return Err(GameError::ResourceLoadError(format!("line {}: unknown directive {:?}", line_no, directive))),
+			}
+		}
+
+		let capacity = capacity.unwrap_or(4.0);
+		for (i, tube) in tubes.iter().enumerate() {
+			let amount: f32 = tube.contents.iter().map(|c| c.amount()).sum();
+			let tube_capacity = tube.capacity.unwrap_or(capacity);
+			if amount > tube_capacity {
+				return Err(GameError::ResourceLoadError(format!("tube {} holds {} which exceeds capacity {}", i + 1, amount, tube_capacity)));
+			}
+		}
+		if tubes.is_empty() {
+			return Err(GameError::ResourceLoadError("level has no tubes".to_string()));
+		}
+
+		Ok(Self { capacity, tubes })
+	}
+}
+
+impl fmt::Display for Level {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "capacity {}", self.capacity)?;
+		for tube in &self.tubes {
+			write!(f, "tube")?;
+			match tube.kind {
+				ColorTubeKind::Normal => {},
+				ColorTubeKind::Locked(color) => write!(f, " locked:{}", color_name(color))?,
+				ColorTubeKind::PourIn => write!(f, " in")?,
+				ColorTubeKind::PourOut => write!(f, " out")?,
+				ColorTubeKind::SingleColor(color) => write!(f, " only:{}", color_name(color))?,
+			}
+			if let Some(capacity) = tube.capacity {
+				write!(f, " capacity:{}", capacity)?;
+			}
+			for content in &tube.contents {
+				write!(f, " {}", color_name(content.color()))?;
+				if content.amount() != 1.0 {
+					write!(f, "*{}", content.amount())?;
+				}
+			}
+			writeln!(f)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const BOARD: &str = "capacity 4
+tube pink*2 blue
+tube locked:green red*4
+tube only:red
+tube in
+tube out cyan yellow
+tube capacity:2 red
+tube
+";
+
+	#[test]
+	fn display_round_trips() {
+		let level = Level::parse(BOARD).unwrap();
+		assert_eq!(level.to_string(), BOARD);
+		assert_eq!(Level::parse(&level.to_string()).unwrap(), level);
+	}
+
+	#[test]
+	fn parses_tube_attributes() {
+		let level = Level::parse("# comment\ncapacity 3\ntube red red*1.5 # merged\ntube only:blue capacity:5").unwrap();
+		assert_eq!(level.capacity, 3.0);
+		assert_eq!(level.tubes[0].contents, vec![ColorTubeContent::new(color_by_name("red").unwrap(), 2.5)]);
+		assert_eq!(level.tubes[1].kind, ColorTubeKind::SingleColor(color_by_name("blue").unwrap()));
+		assert_eq!(level.tubes[1].capacity, Some(5.0));
+	}
+
+	#[test]
+	fn rejects_invalid_levels() {
+		assert!(Level::parse("").is_err());
+		assert!(Level::parse("tube mauve").is_err());
+		assert!(Level::parse("capacity 2\ntube red*3").is_err());
+		assert!(Level::parse("tube capacity:0").is_err());
+		assert!(Level::parse("pipe red").is_err());
+	}
+}
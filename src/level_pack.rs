@@ -0,0 +1,413 @@
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use ggez::{filesystem, Context, GameError, GameResult};
+use ggez::graphics::Font;
+use serde::{Serialize, Deserialize};
+use crate::colors::LIQUID_COLOR_NAMES;
+use crate::editor::MAX_TUBE_CAPACITY;
+use crate::generator::{self, MAX_SPARE_TUBES};
+use crate::level::Level;
+use crate::rules::Rules;
+use crate::solver;
+use crate::theme::Theme;
+
+// Built-in packs as (id, pack file), the id names the progress file
+const BUILTIN_PACKS: [(&str, &str); 1] = [
+	("starter", include_str!("../packs/starter.toml")),
+];
+
+// Directory of additional pack files in the ggez filesystem
+const PACKS_DIR: &str = "/packs";
+// Directory of per-pack progress files in the user data directory
+const PROGRESS_DIR: &str = "/progress";
+
+// Level pack file format, levels are numbered from 1 and are either
+// generated from a seed or given as a board in the level file format:
+//
+//   name = "Starter"
+//   author = "alexrsagen"
+//   generator = 1           # level generator version of the seeds
+//
+//   [[levels]]
+//   name = "First pour"
+//   board = """
+//   capacity 4
+//   tube pink pink blue blue
+//   tube blue blue pink pink
+//   tube
+//   """
+//   par = 3
+//
+//   [[levels]]
+//   seed = 57
+//   colors = 5              # 12 if not set
+//   capacity = 4            # 4 if not set
+//   spare_tubes = 1         # 2 if not set
+//   special_tubes = true
+//   rules = { partial_pour = true }
+//   requires = [1]          # the previous level if not set
+//
+// Without a par, the shortest solution found by the solver is used.
+// The ids of built-in packs and the names of pack files identify packs,
+// a pack file named after a built-in pack id replaces it.
+// Packs with seeds are only loaded by builds with the same generator
+// version, assumed to be the current one if not set.
+#[derive(Debug, Clone, Deserialize)]
+struct PackFile {
+	name: String,
+	#[serde(default)]
+	author: String,
+	generator: Option<u32>,
+	levels: Vec<PackLevelFile>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct PackLevelFile {
+	name: Option<String>,
+	seed: Option<u64>,
+	colors: Option<usize>,
+	capacity: Option<f32>,
+	spare_tubes: Option<usize>,
+	#[serde(default)]
+	special_tubes: bool,
+	board: Option<String>,
+	#[serde(default)]
+	rules: Rules,
+	par: Option<usize>,
+	requires: Option<Vec<usize>>,
+}
+
+#[derive(Debug, Clone)]
+pub enum PackLevelSource {
+	Seed { seed: u64, colors: usize, capacity: f32, spare_tubes: usize, special_tubes: bool },
+	Board(Level),
+}
+
+#[derive(Debug, Clone)]
+pub struct PackLevel {
+	pub name: Option<String>,
+	pub source: PackLevelSource,
+	pub rules: Rules,
+	// None until the solver has finished, for levels without a par
+	pub par: Option<Option<usize>>,
+	// Levels which have to be completed first, numbered from 0
+	pub requires: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LevelPack {
+	pub id: String,
+	pub name: String,
+	pub author: String,
+	pub levels: Vec<PackLevel>,
+}
+
+impl PackLevel {
+	pub fn title(&self, index: usize) -> String {
+		match &self.name {
+			Some(name) => format!("{}. {}", index + 1, name),
+			None => format!("Level {}", index + 1),
+		}
+	}
+
+	pub fn level(&self) -> Level {
+		match &self.source {
+			PackLevelSource::Seed { seed, colors, capacity, spare_tubes, special_tubes } => generator::generate(*seed, *colors, *capacity, *spare_tubes, *special_tubes),
+			PackLevelSource::Board(level) => level.clone(),
+		}
+	}
+
+	// Solves a level without a par on another thread, sending the
+	// length of the shortest solution once found
+	pub fn solve_par(&self) -> Option<Receiver<Option<usize>>> {
+		if self.par.is_some() {
+			return None;
+		}
+		let (level, rules) = (self.level(), self.rules);
+		let (sender, receiver) = mpsc::channel();
+		thread::spawn(move || {
+			let tubes = level.to_tubes(Font::default(), &Rc::new(Theme::default()));
+			let _ = sender.send(solver::solve(&tubes, rules).ok().map(|s| s.moves.len()));
+		});
+		Some(receiver)
+	}
+}
+
+// First level whose requirements lead to a cycle, if any
+fn requires_cycle(levels: &[PackLevel]) -> Option<usize> {
+	// 0 unvisited, 1 being visited, 2 done
+	fn visit(levels: &[PackLevel], index: usize, state: &mut Vec<u8>) -> bool {
+		match state[index] {
+			1 => return true,
+			2 => return false,
+			_ => {},
+		}
+		state[index] = 1;
+		if levels[index].requires.iter().any(|&r| visit(levels, r, state)) {
+			return true;
+		}
+		state[index] = 2;
+		false
+	}
+	let mut state = vec![0; levels.len()];
+	(0..levels.len()).find(|&i| visit(levels, i, &mut state))
+}
+
+impl LevelPack {
+	pub fn parse(id: &str, s: &str) -> GameResult<Self> {
+		let PackFile { name, author, generator: version, levels: level_files } = toml::from_str(s).map_err(|e| GameError::ResourceLoadError(e.to_string()))?;
+		if level_files.is_empty() {
+			return Err(GameError::ResourceLoadError(format!("pack {:?} has no levels", name)));
+		}
+		let version = version.unwrap_or(generator::GENERATOR_VERSION);
+		if version != generator::GENERATOR_VERSION && level_files.iter().any(|l| l.seed.is_some()) {
+			return Err(GameError::ResourceLoadError(format!("pack {:?} is for level generator version {}, this game has version {}", name, version, generator::GENERATOR_VERSION)));
+		}
+		let level_count = level_files.len();
+		let mut levels = Vec::with_capacity(level_count);
+		for (i, level) in level_files.into_iter().enumerate() {
+			let error = |message: String| GameError::ResourceLoadError(format!("pack {:?} level {}: {}", name, i + 1, message));
+			let source = match (level.seed, level.board) {
+				(Some(seed), None) => {
+					let colors = level.colors.unwrap_or(LIQUID_COLOR_NAMES.len());
+					if colors < 1 || colors > LIQUID_COLOR_NAMES.len() {
+						return Err(error(format!("colors must be from 1 to {}", LIQUID_COLOR_NAMES.len())));
+					}
+					let capacity = level.capacity.unwrap_or(4.0);
+					if capacity < 1.0 || capacity.fract() != 0.0 {
+						return Err(error("capacity must be a whole number of units".to_string()));
+					}
+					if capacity > MAX_TUBE_CAPACITY {
+						return Err(error(format!("capacity must be at most {}", MAX_TUBE_CAPACITY)));
+					}
+					let spare_tubes = level.spare_tubes.unwrap_or(2);
+					if spare_tubes > MAX_SPARE_TUBES {
+						return Err(error(format!("spare_tubes must be at most {}", MAX_SPARE_TUBES)));
+					}
+					PackLevelSource::Seed { seed, colors, capacity, spare_tubes, special_tubes: level.special_tubes }
+				},
+				(None, Some(board)) => PackLevelSource::Board(Level::parse(&board).map_err(|e| error(e.to_string()))?),
+				_ => return Err(error("needs either a seed or a board".to_string())),
+			};
+			let requires = match level.requires {
+				Some(requires) => {
+					if let Some(&r) = requires.iter().find(|&&r| r < 1 || r > level_count || r == i + 1) {
+						return Err(error(format!("can not require level {}", r)));
+					}
+					requires.into_iter().map(|r| r - 1).collect()
+				},
+				None if i > 0 => vec![i - 1],
+				None => Vec::new(),
+			};
+			levels.push(PackLevel {
+				name: level.name,
+				source,
+				rules: level.rules,
+				par: level.par.map(Some),
+				requires,
+			});
+		}
+		if let Some(i) = requires_cycle(&levels) {
+			return Err(GameError::ResourceLoadError(format!("pack {:?} level {}: required levels form a cycle", name, i + 1)));
+		}
+		Ok(Self {
+			id: id.to_string(),
+			name,
+			author,
+			levels,
+		})
+	}
+
+	pub fn is_unlocked(&self, index: usize, progress: &PackProgress) -> bool {
+		self.levels[index].requires.iter().all(|&r| progress.is_completed(r))
+	}
+
+	// First unlocked level after the given one
+	pub fn next_unlocked(&self, index: usize, progress: &PackProgress) -> Option<usize> {
+		(index + 1..self.levels.len()).find(|&i| self.is_unlocked(i, progress))
+	}
+
+	// Built-in packs followed by any valid pack files in the packs
+	// directory, a file replaces the built-in pack with the same id
+	pub fn load_all(ctx: &mut Context) -> Vec<Self> {
+		let mut packs: Vec<Self> = BUILTIN_PACKS
+			.iter()
+			.map(|(id, s)| Self::parse(id, s).expect("invalid builtin level pack"))
+			.collect();
+		if let Ok(paths) = filesystem::read_dir(ctx, PACKS_DIR) {
+			let mut paths: Vec<_> = paths
+				.filter(|p| p.extension().map(|e| e == "toml").unwrap_or(false))
+				.collect();
+			paths.sort();
+			for path in paths {
+				let mut s = String::new();
+				if filesystem::open(ctx, &path).and_then(|mut file| Ok(file.read_to_string(&mut s)?)).is_err() {
+					continue;
+				}
+				let id = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+				if let Ok(pack) = Self::parse(&id, &s) {
+					packs.retain(|p| p.id != pack.id);
+					packs.push(pack);
+				}
+			}
+		}
+		packs
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletedLevel {
+	// Numbered from 1 like in pack files
+	pub level: usize,
+	pub moves: usize,
+}
+
+// Completed levels of a pack with the fewest moves used
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PackProgress {
+	pub completed: Vec<CompletedLevel>,
+}
+
+impl PackProgress {
+	fn path(pack_id: &str) -> String {
+		format!("{}/{}.toml", PROGRESS_DIR, pack_id)
+	}
+
+	// Starts over if the progress file is missing or invalid
+	pub fn load(ctx: &mut Context, pack_id: &str) -> Self {
+		let mut s = String::new();
+		match filesystem::open(ctx, Self::path(pack_id)).and_then(|mut file| Ok(file.read_to_string(&mut s)?)) {
+			Ok(_) => toml::from_str(&s).unwrap_or_default(),
+			Err(_) => Self::default(),
+		}
+	}
+
+	pub fn save(&self, ctx: &mut Context, pack_id: &str) -> GameResult {
+		let s = toml::to_string(self).map_err(|e| GameError::ConfigError(e.to_string()))?;
+		if !filesystem::exists(ctx, PROGRESS_DIR) {
+			filesystem::create_dir(ctx, PROGRESS_DIR)?;
+		}
+		let mut file = filesystem::create(ctx, Self::path(pack_id))?;
+		file.write_all(s.as_bytes())?;
+		Ok(())
+	}
+
+	pub fn best_moves(&self, index: usize) -> Option<usize> {
+		self.completed.iter().find(|c| c.level == index + 1).map(|c| c.moves)
+	}
+
+	pub fn is_completed(&self, index: usize) -> bool {
+		self.best_moves(index).is_some()
+	}
+
+	// Records a completion, returns whether the progress changed
+	pub fn complete(&mut self, index: usize, moves: usize) -> bool {
+		match self.completed.iter_mut().find(|c| c.level == index + 1) {
+			Some(completed) if completed.moves <= moves => false,
+			Some(completed) => {
+				completed.moves = moves;
+				true
+			},
+			None => {
+				self.completed.push(CompletedLevel { level: index + 1, moves });
+				self.completed.sort_by_key(|c| c.level);
+				true
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const PACK: &str = r#"
+name = "Test"
+generator = 1
+
+[[levels]]
+name = "Swap"
+board = "capacity 2\ntube red blue\ntube blue red\ntube"
+par = 3
+
+[[levels]]
+seed = 57
+colors = 4
+spare_tubes = 1
+rules = { partial_pour = true }
+
+[[levels]]
+board = "capacity 2\ntube red blue\ntube blue red\ntube"
+requires = [1, 2]
+"#;
+
+	fn pack_error(levels: &str) -> String {
+		match LevelPack::parse("test", &format!("name = \"Test\"\n{}", levels)) {
+			Ok(_) => panic!("pack should be invalid"),
+			Err(e) => e.to_string(),
+		}
+	}
+
+	#[test]
+	fn parses_packs() {
+		let pack = LevelPack::parse("test", PACK).unwrap();
+		assert_eq!((pack.id.as_str(), pack.name.as_str(), pack.levels.len()), ("test", "Test", 3));
+		assert_eq!(pack.levels[0].title(0), "1. Swap");
+		assert_eq!(pack.levels[0].par, Some(Some(3)));
+		assert_eq!(pack.levels[0].requires, Vec::<usize>::new());
+		assert_eq!(pack.levels[1].title(1), "Level 2");
+		assert_eq!(pack.levels[1].par, None);
+		assert_eq!(pack.levels[1].requires, vec![0]);
+		assert!(pack.levels[1].rules.partial_pour);
+		assert_eq!(pack.levels[1].level(), generator::generate(57, 4, 4.0, 1, false));
+		assert_eq!(pack.levels[2].requires, vec![0, 1]);
+
+		for (id, s) in BUILTIN_PACKS.iter() {
+			LevelPack::parse(id, s).unwrap();
+		}
+	}
+
+	#[test]
+	fn rejects_invalid_packs() {
+		assert!(pack_error("levels = []").contains("has no levels"));
+		assert!(pack_error("[[levels]]\nseed = 1\nboard = \"tube\"").contains("needs either a seed or a board"));
+		assert!(pack_error("[[levels]]\nseed = 1\ncolors = 13").contains("colors must be from 1 to 12"));
+		assert!(pack_error("[[levels]]\nseed = 1\ncapacity = 2.5").contains("capacity must be a whole number"));
+		assert!(pack_error("[[levels]]\nseed = 1\ncapacity = 9").contains("capacity must be at most 8"));
+		assert!(pack_error("[[levels]]\nseed = 1\nspare_tubes = 1000000").contains("spare_tubes must be at most 8"));
+		assert!(pack_error("[[levels]]\nseed = 1\nrequires = [1]").contains("can not require level 1"));
+		assert!(pack_error("[[levels]]\nseed = 1\nrequires = [3]").contains("can not require level 3"));
+		assert!(pack_error("[[levels]]\nseed = 1\nrequires = [2]\n[[levels]]\nseed = 2").contains("level 1: required levels form a cycle"));
+		assert!(pack_error("generator = 99\n[[levels]]\nseed = 1").contains("level generator version 99"));
+	}
+
+	#[test]
+	fn solves_missing_pars() {
+		let pack = LevelPack::parse("test", PACK).unwrap();
+		assert!(pack.levels[0].solve_par().is_none());
+		let receiver = pack.levels[2].solve_par().unwrap();
+		assert_eq!(receiver.recv().unwrap(), Some(3));
+	}
+
+	#[test]
+	fn completions_keep_the_fewest_moves() {
+		let pack = LevelPack::parse("test", PACK).unwrap();
+		let mut progress = PackProgress::default();
+		assert!(!pack.is_unlocked(1, &progress));
+		assert_eq!(pack.next_unlocked(0, &progress), None);
+
+		assert!(progress.complete(1, 20));
+		assert!(progress.complete(0, 5));
+		assert!(!progress.complete(0, 7));
+		assert!(progress.complete(0, 3));
+		assert_eq!(progress.best_moves(0), Some(3));
+		assert_eq!(progress.best_moves(1), Some(20));
+		assert!(!progress.is_completed(2));
+		assert_eq!(progress.completed.iter().map(|c| c.level).collect::<Vec<_>>(), vec![1, 2]);
+		assert!(pack.is_unlocked(2, &progress));
+		assert_eq!(pack.next_unlocked(0, &progress), Some(1));
+	}
+}
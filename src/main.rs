@@ -5,27 +5,79 @@ extern crate winit;
 mod imgui_wrapper;
 mod colors;
 mod color_tube;
+mod level;
+mod rules;
+mod bindings;
+mod settings;
+mod event_loop;
+mod theme;
+mod layout;
+mod frame_stats;
+mod audio;
+mod particles;
+mod replay;
+mod solver;
+mod render;
+mod export;
+mod board;
+mod net;
+mod spectator;
+mod generator;
+mod rng;
+mod command;
+mod headless;
+mod scripting;
+mod level_pack;
+mod editor;
+mod progression;
+mod game;
 
 use imgui::*;
-use ggez::{input, mint, nalgebra, Context, ContextBuilder, GameResult};
+use ggez::{input, mint, nalgebra, timer, Context, ContextBuilder, GameResult};
 use ggez::conf::{self, NumSamples};
-use ggez::event::{self, EventHandler, KeyCode, KeyMods, MouseButton};
+use ggez::event::{self, Axis, Button, EventHandler, KeyCode, KeyMods, MouseButton};
 use ggez::graphics::{self, Drawable, Font, Scale, DrawParam, Text, TextFragment};
 use nalgebra::Point2;
-use rand::seq::SliceRandom;
-use rand::SeedableRng;
-use rand::rngs::SmallRng;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+use winit::TouchPhase;
 use crate::imgui_wrapper::ImGuiWrapper;
 use crate::colors::*;
-use crate::color_tube::{ColorTube, ColorTubeContent};
+use crate::color_tube::{ColorTube, ColorTubeKind};
+use crate::level::Level;
+use crate::bindings::{is_bindable_key, Action, Bindings, KeyBinding, KEYBOARD_LAYOUTS, TUBE_KEY_COLS, TUBE_KEY_ROWS};
+use crate::settings::Settings;
+use crate::event_loop::ExtraEventHandler;
+use crate::theme::Theme;
+use crate::frame_stats::FrameStats;
+use crate::audio::{Audio, Sound};
+use crate::particles::Particles;
+use crate::replay::{Replay, ReplayMove, ReplayViewer};
+use crate::rules::Rules;
+use crate::board::{Activation, Board};
+use crate::net::{NetEvent, NetSession, DEFAULT_PORT};
+use crate::spectator::{SpectatorEvent, Spectators, TubeState};
+use crate::command::{Command, LevelCode};
+use crate::scripting::Mods;
+use crate::level_pack::{LevelPack, PackLevel, PackProgress};
+use crate::editor::{Editor, EditorAction, KIND_NAMES, MAX_TUBE_CAPACITY};
+use crate::progression::{Grader, LevelParams, Progression};
+use crate::generator::{ShareCode, GENERATOR_VERSION};
 
-// TODO: persist settings on filesystem
 // TODO: persist level on filesystem
 
+fn shortcut_str(settings: &Settings, action: Action) -> ImString {
+	ImString::new(settings.bindings.get(action).map(|b| b.to_string()).unwrap_or_default())
+}
+
 fn smallest_factor(mut n: usize) -> usize {
 	let mut out = vec![];
 	for i in 2..(n+1) {
-		while n % i == 0 {
+		while n.is_multiple_of(i) {
 			out.push(i);
 			n /= i;
 		}
@@ -37,48 +89,135 @@ fn smallest_factor(mut n: usize) -> usize {
 const WINDOW_WIDTH: f32 = 700.0;
 const WINDOW_HEIGHT: f32 = 650.0;
 
-const TUBE_WIDTH: f32 = 50.0;
 const SCREEN_MARGIN: f32 = 50.0;
-const TUBE_MARGIN: f32 = 25.0;
-
-const KEYMAP_COLS: usize = 7;
-const KEYMAP_ROWS: usize = 4;
-const KEYMAP: [KeyCode; KEYMAP_COLS * KEYMAP_ROWS] = [
-	KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4, KeyCode::Key5, KeyCode::Key6, KeyCode::Key7,
-	KeyCode::Q, KeyCode::W, KeyCode::E, KeyCode::R, KeyCode::T, KeyCode::Y, KeyCode::U,
-	KeyCode::A, KeyCode::S, KeyCode::D, KeyCode::F, KeyCode::G, KeyCode::H, KeyCode::J,
-	KeyCode::Z, KeyCode::X, KeyCode::C, KeyCode::V, KeyCode::B, KeyCode::N, KeyCode::M
-];
-
-struct Settings {
-	full_screen: bool,
+const MIN_SCREEN_MARGIN: f32 = 25.0;
+// Space reserved above the tubes for the level text
+const HUD_HEIGHT: f32 = 30.0;
+
+// Ignore mouse clicks this long after a touch, as some platforms
+// emulate mouse input from touch input
+const TOUCH_MOUSE_GRACE: Duration = Duration::from_millis(500);
+
+// Tubes touched are stored as (player, tube index)
+struct TouchState {
+	id: u64,
+	start_tube: Option<(usize, usize)>,
+	target_tube: Option<(usize, usize)>,
 }
 
-impl Settings {
-	fn new() -> Self {
-		Self {
-			full_screen: false
-		}
-	}
+// Player one uses the left half of the tube keys in versus mode,
+// player two the right half
+fn versus_key_cols(player: usize) -> Range<usize> {
+	let split = TUBE_KEY_COLS.div_ceil(2);
+	if player == 0 { 0..split } else { split..TUBE_KEY_COLS }
 }
 
+// Versus levels are limited to the tubes the smaller key half can reach
+const VERSUS_MAX_TUBES: usize = TUBE_KEY_ROWS * (TUBE_KEY_COLS / 2);
+
+// Pack name, description and (label, unlocked) for each level
+type PackListEntry = (ImString, String, Vec<(ImString, bool)>);
+
 pub struct MenuState {
 	settings: Settings,
+	settings_changed: bool,
 	show_settings: bool,
+	show_debug: bool,
+	// Level text being edited in the debug window
+	debug_level: ImString,
+	debug_level_edited: bool,
+	debug_level_error: Option<String>,
+	apply_debug_level: bool,
 	full_screen_changed: bool,
+	theme_changed: bool,
+	rebinding: Option<Action>,
 	restart_level: bool,
 	skip_level: bool,
+	save_level: bool,
+	save_replay: bool,
+	watch_replay: bool,
+	replay_toggle_pause: bool,
+	replay_step: bool,
+	replay_restart: bool,
+	close_replay: bool,
+	replay_speed: f32,
+	toggle_versus: bool,
+	show_multiplayer: bool,
+	net_address: ImString,
+	net_port: i32,
+	net_error: Option<String>,
+	spectator_error: Option<String>,
+	show_console: bool,
+	reload_mods: bool,
+	clear_console: bool,
+	host_game: bool,
+	join_game: bool,
+	leave_game: bool,
+	show_packs: bool,
+	pack_picker: usize,
+	play_pack_level: Option<(usize, usize)>,
+	leave_pack: bool,
+	save_pack_progress: bool,
+	show_share_code: bool,
+	share_code_input: ImString,
+	load_share_code: bool,
+	share_code_error: Option<String>,
+	open_editor: bool,
+	editor_actions: Vec<EditorAction>,
+	editor_path: ImString,
+	undo: bool,
 	quit: bool,
 }
 
 impl MenuState {
-	fn new() -> Self {
+	fn new(settings: Settings) -> Self {
 		Self {
-			settings: Settings::new(),
-			full_screen_changed: false,
+			full_screen_changed: settings.full_screen,
+			settings,
+			settings_changed: false,
 			show_settings: false,
+			show_debug: false,
+			debug_level: ImString::with_capacity(256),
+			debug_level_edited: false,
+			debug_level_error: None,
+			apply_debug_level: false,
+			theme_changed: false,
+			rebinding: None,
 			restart_level: false,
 			skip_level: false,
+			save_level: false,
+			save_replay: false,
+			watch_replay: false,
+			replay_toggle_pause: false,
+			replay_step: false,
+			replay_restart: false,
+			close_replay: false,
+			replay_speed: 1.0,
+			toggle_versus: false,
+			show_multiplayer: false,
+			net_address: ImString::new(format!("127.0.0.1:{}", DEFAULT_PORT)),
+			net_port: DEFAULT_PORT as i32,
+			net_error: None,
+			spectator_error: None,
+			show_console: false,
+			reload_mods: false,
+			clear_console: false,
+			host_game: false,
+			join_game: false,
+			leave_game: false,
+			show_packs: false,
+			pack_picker: 0,
+			play_pack_level: None,
+			leave_pack: false,
+			save_pack_progress: false,
+			show_share_code: false,
+			share_code_input: ImString::with_capacity(32),
+			load_share_code: false,
+			share_code_error: None,
+			open_editor: false,
+			editor_actions: Vec::new(),
+			editor_path: ImString::new("/level-edited.txt"),
+			undo: false,
 			quit: false,
 		}
 	}
@@ -92,25 +231,81 @@ struct MainState {
 	height: f32,
 	mouse_pos: mint::Point2<f32>,
 	menu_state: MenuState,
+	themes: Vec<Rc<Theme>>,
+	theme: Rc<Theme>,
+	frame_stats: FrameStats,
+	audio: Audio,
+	particles: Particles,
 
 	pre_full_screen_pos: winit::dpi::LogicalPosition,
 	pre_full_screen_size: (f32, f32),
 	full_screen_bug_reset_window_scale: bool,
 	full_screen_bug_reset_window_pos: bool,
 
-	tube_capacity: f32,
-	tubes: Vec<ColorTube>,
-	tubes_factor: usize,
-	selected_tube: Option<usize>,
+	// One board per player, two in versus mode
+	boards: Vec<Board>,
+	versus: bool,
+	winner: Option<usize>,
+	// The player who poured last, whose move the undo key takes back
+	last_player: usize,
+	gamepad_stick: (i8, i8),
+	touch: Option<TouchState>,
+	last_touch: Option<Instant>,
 
 	level: usize,
+	custom_level: Option<Level>,
+	// Endless level sizes, and the share code and shortest
+	// solution of the generated level being played
+	progression: Progression,
+	share_code: Option<ShareCode>,
+	solution_moves: Option<usize>,
+	// Grading of generated levels, and whether the level is waiting for it
+	grader: Grader,
+	generating: bool,
+
+	// Level packs and each pack's progress, the pack and level
+	// being played, and the endless level to return to
+	packs: Vec<LevelPack>,
+	pack_progress: Vec<PackProgress>,
+	pack: Option<(usize, usize)>,
+	pre_pack_level: usize,
+	// Par being solved for a pack level, as (pack, level, result)
+	pack_par: Option<(usize, usize, Receiver<Option<usize>>)>,
+
+	// Recording of the current level
+	replay: Replay,
+	level_start: Instant,
+	viewer: Option<ReplayViewer>,
+	// Game to return to when the replay viewer is closed
+	pre_replay: Option<Board>,
+	// Level editor, which uses player one's board to show the level
+	editor: Option<Editor>,
+	pre_editor: Option<Board>,
+
+	// LAN race being hosted or joined
+	net: Option<NetSession>,
+	// Local socket streaming events to spectators, if enabled
+	spectators: Option<Spectators>,
+	// Control commands read from stdin, the ones waiting for a level to
+	// be generated, and whether the last one is still to be answered
+	commands: Option<Receiver<String>>,
+	queued_commands: VecDeque<String>,
+	command_answer_pending: bool,
+	mods: Mods,
 }
 
 impl MainState {
-	fn new(mut ctx: &mut Context, hidpi_factor: f32) -> GameResult<MainState> {
-		let imgui_wrapper = ImGuiWrapper::new(&mut ctx);
+	fn new(ctx: &mut Context, hidpi_factor: f32, custom_level: Option<Level>, replay: Option<Replay>) -> GameResult<MainState> {
+		let imgui_wrapper = ImGuiWrapper::new(ctx);
+		let settings = Settings::load(ctx);
 		let (width, height) = graphics::drawable_size(ctx);
 
+		let themes: Vec<Rc<Theme>> = Theme::load_all(ctx).into_iter().map(Rc::new).collect();
+		let theme = themes[0].clone();
+		let progression = Progression::load(ctx);
+		let packs = LevelPack::load_all(ctx);
+		let pack_progress = packs.iter().map(|p| PackProgress::load(ctx, &p.id)).collect();
+
 		let mut s = MainState {
 			imgui_wrapper,
 			hidpi_factor,
@@ -118,162 +313,784 @@ impl MainState {
 			width,
 			height,
 			mouse_pos: input::mouse::position(ctx),
-			menu_state: MenuState::new(),
+			menu_state: MenuState::new(settings),
+			themes,
+			theme,
+			frame_stats: FrameStats::new(),
+			audio: Audio::new(),
+			particles: Particles::new(),
 
 			pre_full_screen_pos: winit::dpi::LogicalPosition::new(0.0, 0.0),
 			pre_full_screen_size: (WINDOW_WIDTH, WINDOW_HEIGHT),
 			full_screen_bug_reset_window_scale: false,
 			full_screen_bug_reset_window_pos: false,
 
-			tube_capacity: 4.0,
-			tubes: Vec::new(),
-			tubes_factor: 1,
-			selected_tube: None,
+			boards: vec![Board::new(4.0, Vec::new())],
+			versus: false,
+			last_player: 0,
+			winner: None,
+			gamepad_stick: (0, 0),
+			touch: None,
+			last_touch: None,
 
 			level: 1,
+			custom_level,
+			progression,
+			share_code: None,
+			solution_moves: None,
+			grader: Grader::new(),
+			generating: false,
+
+			packs,
+			pack_progress,
+			pack: None,
+			pre_pack_level: 1,
+			pack_par: None,
+
+			replay: Replay::new(Rules::new(), Level { capacity: 4.0, tubes: Vec::new() }),
+			level_start: Instant::now(),
+			viewer: None,
+			pre_replay: None,
+			editor: None,
+			pre_editor: None,
+			net: None,
+			spectators: None,
+			commands: None,
+			queued_commands: VecDeque::new(),
+			command_answer_pending: false,
+			mods: Mods::new(),
 		};
+		s.apply_theme();
+		let mods_folder = s.menu_state.settings.mods_folder.clone();
+		s.mods.load(ctx, &mods_folder);
 		s.new_tubes();
+		if let Some(replay) = replay {
+			s.start_viewer(replay);
+		}
 		Ok(s)
 	}
 
+	fn selected_theme(&self) -> &Rc<Theme> {
+		self.themes
+			.iter()
+			.find(|t| t.name == self.menu_state.settings.theme)
+			.unwrap_or(&self.themes[0])
+	}
+
+	// Uses the selected theme, with the custom palette if any
+	fn apply_theme(&mut self) {
+		let mut theme = self.selected_theme().as_ref().clone();
+		if let Some(palette) = &self.menu_state.settings.custom_palette {
+			theme.palette = palette.clone();
+		}
+		self.imgui_wrapper.set_theme(&theme);
+		self.theme = Rc::new(theme);
+	}
+
+	// Level size from the progression, with the colors limited to the
+	// palette, and in versus mode to the tubes the tube keys can reach
+	fn level_params(&self, level: usize) -> LevelParams {
+		let params = self.progression.params(level, &self.menu_state.settings);
+		let mut max_colors = self.theme.palette.len();
+		if self.versus {
+			let extra_tubes = params.spare_tubes + if params.special_tubes { 2 } else { 0 };
+			max_colors = max_colors.min(VERSUS_MAX_TUBES.saturating_sub(extra_tubes));
+		}
+		game::level_params(&self.progression, level, &self.menu_state.settings, max_colors)
+	}
+
 	fn new_tubes(&mut self) {
-		let mut tubes_src = vec![
-			ColorTube::new(self.tube_capacity, vec![ColorTubeContent::new(COLOR_PINK, self.tube_capacity)], self.font),
-			ColorTube::new(self.tube_capacity, vec![ColorTubeContent::new(COLOR_PURPLE, self.tube_capacity)], self.font),
-			ColorTube::new(self.tube_capacity, vec![ColorTubeContent::new(COLOR_VIOLET, self.tube_capacity)], self.font),
-			ColorTube::new(self.tube_capacity, vec![ColorTubeContent::new(COLOR_BLUE, self.tube_capacity)], self.font),
-			ColorTube::new(self.tube_capacity, vec![ColorTubeContent::new(COLOR_LIGHTBLUE, self.tube_capacity)], self.font),
-			ColorTube::new(self.tube_capacity, vec![ColorTubeContent::new(COLOR_CYAN, self.tube_capacity)], self.font),
-			ColorTube::new(self.tube_capacity, vec![ColorTubeContent::new(COLOR_GREEN, self.tube_capacity)], self.font),
-			ColorTube::new(self.tube_capacity, vec![ColorTubeContent::new(COLOR_LIGHTGREEN, self.tube_capacity)], self.font),
-			ColorTube::new(self.tube_capacity, vec![ColorTubeContent::new(COLOR_OLIVE, self.tube_capacity)], self.font),
-			ColorTube::new(self.tube_capacity, vec![ColorTubeContent::new(COLOR_YELLOW, self.tube_capacity)], self.font),
-			ColorTube::new(self.tube_capacity, vec![ColorTubeContent::new(COLOR_ORANGE, self.tube_capacity)], self.font),
-			ColorTube::new(self.tube_capacity, vec![ColorTubeContent::new(COLOR_RED, self.tube_capacity)], self.font),
-		];
-		let mut rng = SmallRng::seed_from_u64(self.level as u64);
-		tubes_src.shuffle(&mut rng);
-
-		let mut tubes = Vec::<ColorTube>::with_capacity(tubes_src.len());
-		tubes.resize(tubes_src.len(), ColorTube::new(self.tube_capacity, Vec::new(), self.font));
-
-		let mut filled_amount = 0.0;
-		while filled_amount < self.tube_capacity {
-			for i in 0..tubes_src.len() {
-				if let Some(content) = tubes_src[i].drain(1.0) {
-					tubes[i].fill_unchecked(content);
-				} else {
-					panic!("failed to drain from {:?}", tubes_src[i]);
+		self.winner = None;
+
+		let params = self.level_params(self.level);
+		let rules = self.rules();
+		let packs = &self.packs;
+		let pack_level = self.pack.map(|(pack, index)| &packs[pack].levels[index]);
+		let start = game::start_level(self.level, self.custom_level.as_ref(), self.share_code, pack_level, &params, rules, &mut self.mods, &mut self.grader);
+		let start = match start {
+			Some(start) => start,
+			None => {
+				// Started by update once graded, the previous board stays until then
+				self.generating = true;
+				return;
+			},
+		};
+		self.generating = false;
+		if let (Some((pack, index)), None) = (self.pack, &self.custom_level) {
+			// Levels without a par are solved while being played
+			let solving = self.pack_par.as_ref().map(|(p, i, _)| (*p, *i)) == Some((pack, index));
+			if !solving {
+				if let Some(receiver) = self.packs[pack].levels[index].solve_par() {
+					self.pack_par = Some((pack, index, receiver));
 				}
 			}
-			tubes.shuffle(&mut rng);
-			filled_amount += 1.0;
 		}
+		self.share_code = start.share_code;
+		self.solution_moves = start.solution_moves;
+		self.load_level_tubes(&start.level);
+		self.level_started();
+
+		// Grade the next generated level while this one is played
+		if let (None, None, Some(_)) = (&self.custom_level, self.pack, self.share_code) {
+			let params = self.level_params(self.level + 1);
+			self.grader.prefetch(self.level + 1, &params, rules);
+		}
+	}
 
-		tubes.resize(tubes_src.len() + 2, ColorTube::new(self.tube_capacity, Vec::new(), self.font));
+	// Gives every player a copy of the same tubes
+	fn set_tubes(&mut self, tube_capacity: f32, tubes: Vec<ColorTube>) {
+		let players = if self.versus { 2 } else { 1 };
+		self.boards.truncate(players);
+		while self.boards.len() < players {
+			self.boards.push(Board::new(tube_capacity, Vec::new()));
+		}
+		for board in &mut self.boards {
+			board.set_tubes(tube_capacity, tubes.clone());
+		}
+		self.last_player = 0;
+		self.update_layout();
+	}
 
-		self.tubes_factor = smallest_factor(tubes.len());
-		self.tubes = tubes;
+	fn load_level_tubes(&mut self, level: &Level) {
+		let tubes = level.to_tubes(self.font, &self.theme);
+		self.set_tubes(level.capacity, tubes);
 	}
 
-	// returns 0.0 (0%) .. 1.0 (100%)
-	fn complete_pct(&self) -> f32 {
-		let mut empty_tubes = 0;
-		self.tubes
-			.iter()
-			.map(|t| if t.remaining_capacity() == t.capacity {
-				empty_tubes += 1;
-				0.0
-			} else {
-				t.complete_pct()
-			})
-			.sum::<f32>() / (self.tubes.len() - empty_tubes) as f32
+	fn toggle_versus(&mut self) {
+		if self.viewer.is_some() {
+			self.close_viewer();
+		}
+		self.versus = !self.versus;
+		self.new_tubes();
 	}
 
-	fn skip_level(&mut self) {
-		self.level += 1;
+	// Rules of the replay being watched, the host's rules in a
+	// LAN race, the pack level's rules, or the player's rules
+	fn rules(&self) -> Rules {
+		match &self.viewer {
+			Some(viewer) => viewer.replay.rules,
+			None => self.net.as_ref()
+				.and_then(|n| n.rules)
+				.or_else(|| self.pack_level().map(|l| l.rules))
+				.unwrap_or(self.menu_state.settings.rules),
+		}
+	}
+
+	fn pack_level(&self) -> Option<&PackLevel> {
+		self.pack.map(|(pack, index)| &self.packs[pack].levels[index])
+	}
+
+	fn play_pack_level(&mut self, pack: usize, index: usize) {
+		if self.viewer.is_some() {
+			self.close_viewer();
+		}
+		if self.pack.is_none() {
+			self.pre_pack_level = self.level;
+		}
+		self.pack = Some((pack, index));
+		self.level = index + 1;
+		self.custom_level = None;
 		self.new_tubes();
 	}
 
-	fn handle_tube_activation(&mut self, tube_index: usize) {
-		if tube_index >= self.tubes.len() {
+	// Returns to the endless levels
+	fn leave_pack(&mut self) {
+		if self.pack.take().is_some() {
+			self.level = self.pre_pack_level;
+		}
+	}
+
+	// Next unlocked level of the pack being played
+	fn next_pack_level(&self) -> Option<usize> {
+		let (pack, index) = self.pack?;
+		self.packs[pack].next_unlocked(index, &self.pack_progress[pack])
+	}
+
+	fn start_net(&mut self, net: NetSession) {
+		if self.viewer.is_some() {
+			self.close_viewer();
+		}
+		let is_host = net.is_host();
+		self.net = Some(net);
+		self.menu_state.net_error = None;
+		if is_host {
+			// Start the race from the beginning of the level
+			self.new_tubes();
+		}
+	}
+
+	// Records the level just started, and sends it to everyone
+	// in the race if hosting and to spectators
+	fn level_started(&mut self) {
+		self.start_recording();
+		if let Some(net) = &mut self.net {
+			net.send_level(self.level, &Replay::new(self.replay.rules, self.replay.level.clone()));
+		}
+		self.spectate_level_start();
+	}
+
+	// Sends the full board, at the start of the level or with the
+	// current contents when spectating is enabled mid level
+	fn spectate_level_start(&mut self) {
+		let board = &self.boards[0];
+		let event = SpectatorEvent::LevelStart {
+			level: self.level,
+			capacity: board.tube_capacity,
+			rules: self.rules(),
+			tubes: board.tubes.iter().map(TubeState::from_tube).collect(),
+		};
+		self.spectate(event);
+	}
+
+	fn spectate(&mut self, event: SpectatorEvent) {
+		if let (Some(spectators), None) = (&mut self.spectators, &self.viewer) {
+			spectators.send(&event);
+		}
+	}
+
+	// Opens or closes the spectator socket to match the settings
+	fn update_spectators(&mut self) {
+		let settings = &self.menu_state.settings;
+		let wanted_port = if settings.spectators { Some(settings.spectator_port) } else { None };
+		if self.spectators.as_ref().map(|s| s.port()) == wanted_port {
 			return;
 		}
-		if self.menu_state.show_settings {
+		self.spectators = None;
+		self.menu_state.spectator_error = None;
+		if let Some(port) = wanted_port {
+			match Spectators::bind(port) {
+				Ok(spectators) => {
+					self.spectators = Some(spectators);
+					self.spectate_level_start();
+				},
+				Err(e) => {
+					self.menu_state.spectator_error = Some(e.to_string());
+					self.menu_state.settings.spectators = false;
+				},
+			}
+		}
+	}
+
+	fn is_net_client(&self) -> bool {
+		self.net.as_ref().map(|n| !n.is_host()).unwrap_or(false)
+	}
+
+	fn start_recording(&mut self) {
+		if self.viewer.is_some() {
 			return;
 		}
+		let board = &self.boards[0];
+		self.replay = Replay::new(self.rules(), Level::from_tubes(board.tube_capacity, &board.tubes));
+		self.level_start = Instant::now();
+	}
+
+	fn save_replay(&self, ctx: &mut Context) -> GameResult {
+		let mut file = ggez::filesystem::create(ctx, format!("/replay-{}.txt", self.level))?;
+		file.write_all(self.save_header().as_bytes())?;
+		file.write_all(self.replay.to_string().as_bytes())?;
+		Ok(())
+	}
+
+	// Comment saved with levels and replays, naming the generated
+	// level they were made from
+	fn save_header(&self) -> String {
+		match self.share_code {
+			Some(code) => format!("# level generator version {}, share code {}\n", GENERATOR_VERSION, code),
+			None => String::new(),
+		}
+	}
 
-		let (tubes_before, tubes_after) = self.tubes.split_at_mut(tube_index);
-		let tubes_after = tubes_after.split_first_mut();
-		let (tube, tubes_after) = tubes_after.unwrap();
+	fn load_share_code(&mut self) {
+		match ShareCode::parse(self.menu_state.share_code_input.to_str()) {
+			Ok(code) => {
+				self.leave_pack();
+				self.share_code = Some(code);
+				self.custom_level = Some(code.level());
+				self.new_tubes();
+				self.menu_state.share_code_error = None;
+			},
+			Err(e) => self.menu_state.share_code_error = Some(e),
+		}
+	}
 
-		let has_selected_tube = self.selected_tube.is_some();
-		let is_selected_tube = has_selected_tube && self.selected_tube.unwrap() == tube_index;
+	fn start_viewer(&mut self, replay: Replay) {
+		if self.viewer.is_none() {
+			self.pre_replay = Some(self.boards[0].clone());
+		}
+		self.load_level_tubes(&replay.level);
+		self.viewer = Some(ReplayViewer::new(replay));
+	}
 
-		if is_selected_tube {
-			// Deselect current tube
-			self.selected_tube = None;
-		} else if has_selected_tube && !is_selected_tube {
-			// Get previously selected tube
-			let prev_tube_index = self.selected_tube.unwrap();
-			let prev_tube = if prev_tube_index < tube_index {
-				&mut tubes_before[prev_tube_index]
+	fn restart_viewer(&mut self) {
+		if let Some(level) = self.viewer.as_ref().map(|v| v.replay.level.clone()) {
+			self.load_level_tubes(&level);
+		}
+		if let Some(viewer) = &mut self.viewer {
+			viewer.restart();
+		}
+	}
+
+	fn close_viewer(&mut self) {
+		self.viewer = None;
+		match self.pre_replay.take() {
+			Some(mut board) => {
+				board.selected_tube = None;
+				self.boards = vec![board];
+				self.update_layout();
+			},
+			None => self.new_tubes(),
+		}
+	}
+
+	// Edits the current tubes, returning to the game when closed
+	fn open_editor(&mut self) {
+		if self.viewer.is_some() {
+			self.close_viewer();
+		}
+		let board = self.boards[0].clone();
+		let level = Level::from_tubes(board.tube_capacity, &board.tubes);
+		self.pre_editor = Some(board);
+		self.editor = Some(Editor::new(level));
+		self.update_editor_board();
+	}
+
+	fn close_editor(&mut self) {
+		self.editor = None;
+		if let Some(mut board) = self.pre_editor.take() {
+			board.selected_tube = None;
+			self.boards = vec![board];
+			self.update_layout();
+		}
+	}
+
+	// Shows the edited level on the board
+	fn update_editor_board(&mut self) {
+		if let Some(editor) = &self.editor {
+			let (level, selected_tube) = (editor.level.clone(), editor.selected_tube);
+			self.load_level_tubes(&level);
+			self.boards[0].selected_tube = selected_tube;
+		}
+	}
+
+	fn paint_tube(&mut self, tube_index: usize, y: f32) {
+		let slot = editor::slot_at(&self.boards[0].tubes[tube_index], y);
+		if let Some(editor) = &mut self.editor {
+			editor.paint(tube_index, slot);
+		}
+		self.update_editor_board();
+	}
+
+	fn apply_editor_action(&mut self, ctx: &mut Context, action: EditorAction) {
+		let rules = self.menu_state.settings.rules;
+		let editor = match &mut self.editor {
+			Some(editor) => editor,
+			None => return,
+		};
+		match action {
+			EditorAction::AddTube => editor.add_tube(),
+			EditorAction::RemoveTube => editor.remove_tube(),
+			EditorAction::SetCapacity(capacity) => editor.set_capacity(capacity),
+			EditorAction::SetKind(kind) => editor.set_kind(kind),
+			EditorAction::SetPaint(color) => editor.paint = color,
+			EditorAction::Check => editor.check(rules),
+			EditorAction::Save => {
+				let path = self.menu_state.editor_path.to_str().to_string();
+				let result = ggez::filesystem::create(ctx, &path)
+					.and_then(|mut file| Ok(file.write_all(editor.level.to_string().as_bytes())?));
+				editor.status = Some(match result {
+					Ok(()) => format!("Saved to {}", path),
+					Err(e) => e.to_string(),
+				});
+			},
+			EditorAction::Play => {
+				let level = editor.level.clone();
+				self.editor = None;
+				self.pre_editor = None;
+				self.leave_pack();
+				self.custom_level = Some(level);
+				self.new_tubes();
+				return;
+			},
+			EditorAction::Close => {
+				self.close_editor();
+				return;
+			},
+		}
+		self.update_editor_board();
+	}
+
+	fn apply_replay_move(&mut self, action: ReplayMove) {
+		match action {
+			ReplayMove::Pour { from, to, single_unit } => {
+				self.pour(0, from, to, single_unit);
+			},
+			ReplayMove::Undo => self.undo(0),
+		}
+	}
+
+	// Replaces the tubes with the level edited in the debug window
+	fn apply_debug_level(&mut self) {
+		match Level::parse(self.menu_state.debug_level.to_str()) {
+			Ok(level) => {
+				// Keep the previous tubes undoable
+				let histories: Vec<Vec<Vec<ColorTube>>> = self.boards
+					.iter()
+					.map(|board| board.history.iter().cloned().chain(Some(board.tubes.clone())).collect())
+					.collect();
+				self.load_level_tubes(&level);
+				for (board, history) in self.boards.iter_mut().zip(histories) {
+					board.history = history;
+				}
+				self.level_started();
+				self.menu_state.debug_level_edited = false;
+				self.menu_state.debug_level_error = None;
+			},
+			Err(e) => self.menu_state.debug_level_error = Some(e.to_string()),
+		}
+	}
+
+	fn save_level(&self, ctx: &mut Context) -> GameResult {
+		let board = &self.boards[0];
+		let level = Level::from_tubes(board.tube_capacity, &board.tubes);
+		let mut file = ggez::filesystem::create(ctx, format!("/level-{}.txt", self.level))?;
+		file.write_all(self.save_header().as_bytes())?;
+		file.write_all(level.to_string().as_bytes())?;
+		Ok(())
+	}
+
+	// Number of palette colors from the first which the boards and the
+	// editor use, for liquids and for locked and single color tubes
+	fn palette_colors_in_use(&self) -> usize {
+		let board_tubes = self.boards.iter().flat_map(|b| b.tubes.iter().map(|t| (t.kind, t.contents())));
+		let editor_tubes = self.editor.iter().flat_map(|e| e.level.tubes.iter().map(|t| (t.kind, t.contents.as_slice())));
+		board_tubes
+			.chain(editor_tubes)
+			.flat_map(|(kind, contents)| {
+				let kind_color = match kind {
+					ColorTubeKind::Locked(color) | ColorTubeKind::SingleColor(color) => Some(color),
+					_ => None,
+				};
+				contents.iter().map(|c| c.color()).chain(kind_color)
+			})
+			.map(|color| color + 1)
+			.max()
+			.unwrap_or(0)
+	}
+
+	// Whether the level is sorted, by the first player to finish in versus mode
+	fn level_completed(&self) -> bool {
+		self.boards.iter().any(|b| b.complete_pct() == 1.0)
+	}
+
+	fn undo(&mut self, player: usize) {
+		if self.generating || !self.boards[player].undo() {
+			return;
+		}
+		if self.viewer.is_none() && !self.versus {
+			self.replay.record(self.level_start.elapsed().as_secs_f32(), ReplayMove::Undo);
+		}
+		let complete_pct = self.boards[player].complete_pct();
+		self.spectate(SpectatorEvent::Undo { player, complete_pct });
+	}
+
+	fn skip_level(&mut self) {
+		if let Some((pack, _)) = self.pack {
+			if let Some(index) = self.next_pack_level() {
+				self.play_pack_level(pack, index);
+			}
+			return;
+		}
+		self.level += 1;
+		self.custom_level = None;
+		self.new_tubes();
+	}
+
+	// Applies a control command to player one's board
+	fn run_command(&mut self, command: Command) -> Result<(), String> {
+		if self.viewer.is_some() {
+			return Err("a replay is being watched".to_string());
+		}
+		if self.editor.is_some() {
+			return Err("the level editor is open".to_string());
+		}
+		match command {
+			Command::Pour { from, to, single_unit } => {
+				let tube_count = self.boards[0].tubes.len();
+				self.boards[0].selected_tube = None;
+				command::pour_with(from, to, tube_count, |tube| self.handle_tube_activation(0, tube, single_unit))
+			},
+			Command::Undo => if self.boards[0].history.is_empty() {
+				Err("nothing to undo".to_string())
 			} else {
-				&mut tubes_after[prev_tube_index - tube_index - 1]
-			};
+				self.undo(0);
+				Ok(())
+			},
+			Command::Restart => {
+				self.new_tubes();
+				Ok(())
+			},
+			Command::Load(_) if self.is_net_client() => Err("the host picks the levels of the race".to_string()),
+			Command::Load(LevelCode::Number(number)) => {
+				self.leave_pack();
+				self.level = number;
+				self.custom_level = None;
+				self.new_tubes();
+				Ok(())
+			},
+			Command::Load(LevelCode::Share(code)) => {
+				self.leave_pack();
+				self.share_code = Some(code);
+				self.custom_level = Some(code.level());
+				self.new_tubes();
+				Ok(())
+			},
+			Command::Load(LevelCode::Level(level)) => {
+				self.leave_pack();
+				self.custom_level = Some(level);
+				self.new_tubes();
+				Ok(())
+			},
+			Command::State => Ok(()),
+		}
+	}
 
-			// Attempt to move color from previously selected
-			// to newly selected tube
-			if let Some(content) = prev_tube.drain(tube.remaining_capacity()) {
-				// println!("drain {:?}", content);
-				if let Some(content) = tube.fill(content) {
-					// Color doesn't match, put the color back into the previous tube
-					// println!("could not fill {:?} with drained content", tube);
-					prev_tube.fill_unchecked(content);
+	// Selects or pours into a tube of a player's board, returns
+	// None while the board does not accept input
+	fn handle_tube_activation(&mut self, player: usize, tube_index: usize, single_unit: bool) -> Option<Activation> {
+		if player >= self.boards.len() || tube_index >= self.boards[player].tubes.len() {
+			return None;
+		}
+		if self.menu_state.show_settings || self.viewer.is_some() || self.winner.is_some() || self.generating {
+			return None;
+		}
+		if let Some(editor) = &mut self.editor {
+			editor.selected_tube = Some(tube_index);
+			self.boards[0].selected_tube = Some(tube_index);
+			return None;
+		}
+
+		let rules = self.rules();
+		let keep_selection_on_error = self.menu_state.settings.keep_selection_on_error;
+		let was_completed = self.boards[player].complete_pct() == 1.0;
+		let activation = game::activate(&mut self.boards[player], tube_index, single_unit, rules, keep_selection_on_error, &mut self.mods);
+		self.pour_feedback(player, activation, was_completed);
+
+		if let Activation::Poured { from, to, .. } = activation {
+			self.last_player = player;
+			if !self.versus {
+				let time = self.level_start.elapsed().as_secs_f32();
+				self.replay.record(time, ReplayMove::Pour { from, to, single_unit });
+				if self.level_completed() {
+					self.menu_state.save_replay = true;
 				}
 			}
+		}
+		Some(activation)
+	}
+
+	// Attempts to move color from one tube to another on a player's board,
+	// returns whether anything was poured
+	fn pour(&mut self, player: usize, from: usize, to: usize, single_unit: bool) -> bool {
+		let rules = self.rules();
+		if self.generating || from == to || from >= self.boards[player].tubes.len() || to >= self.boards[player].tubes.len() {
+			return false;
+		}
+
+		let was_completed = self.boards[player].complete_pct() == 1.0;
+		let activation = game::pour(&mut self.boards[player], from, to, single_unit, rules, &mut self.mods);
+		self.pour_feedback(player, activation, was_completed);
+		matches!(activation, Activation::Poured { .. })
+	}
+
+	// Plays sounds and effects for a pour or rejected selection,
+	// and reports pours and level completion to spectators
+	fn pour_feedback(&mut self, player: usize, activation: Activation, was_completed: bool) {
+		let board = &self.boards[player];
+		let mut spectator_events = Vec::new();
+		match activation {
+			Activation::Poured { from, to, amount } => {
+				let tube = &board.tubes[to];
+				self.audio.play(Sound::Pour { amount, level: tube.amount() / tube.capacity });
+				let was_tube_completed = board.history.last().map(|tubes| tubes[to].is_completed()).unwrap_or(false);
+				if tube.is_completed() && !was_tube_completed {
+					self.audio.play(Sound::TubeCompleted);
+					if let Some(color) = tube.main_color() {
+						self.particles.burst(tube.dimensions, self.theme.liquid(color), 80);
+					}
+				}
+				spectator_events.push(SpectatorEvent::Pour { player, from, to, amount, complete_pct: board.complete_pct() });
+			},
+			Activation::Rejected { .. } => self.audio.play(Sound::Error),
+			Activation::Selected(_) | Activation::Deselected => {},
+		}
+
+		if !was_completed && board.complete_pct() == 1.0 {
+			self.audio.play(Sound::LevelCompleted);
+			if let (Some((pack, index)), None) = (self.pack, &self.viewer) {
+				if self.pack_progress[pack].complete(index, board.history.len()) {
+					self.menu_state.save_pack_progress = true;
+				}
+			}
+			if self.versus && self.winner.is_none() {
+				self.winner = Some(player);
+			}
+			spectator_events.push(SpectatorEvent::LevelComplete {
+				player,
+				level: self.level,
+				moves: board.history.len(),
+				time: self.level_start.elapsed().as_secs_f32(),
+			});
+		}
+		for event in spectator_events {
+			self.spectate(event);
+		}
+	}
+
+	// The arrow keys and gamepad control player two in versus mode,
+	// as player one's tube keys are on the left of the keyboard
+	fn cursor_player(&self) -> usize {
+		self.boards.len() - 1
+	}
 
-			// Deselect previously selected tube
-			self.selected_tube = None;
-		} else if !has_selected_tube {
-			// Select current tube
-			self.selected_tube = Some(tube_index);
+	fn handle_gamepad_button(&mut self, button: Button) {
+		if button == Button::Start {
+			self.menu_state.show_settings = !self.menu_state.show_settings;
+			return;
+		}
+		if self.menu_state.show_settings {
+			return;
+		}
+		let player = self.cursor_player();
+		match button {
+			Button::DPadLeft => self.boards[player].move_cursor(Action::CursorLeft),
+			Button::DPadRight => self.boards[player].move_cursor(Action::CursorRight),
+			Button::DPadUp => self.boards[player].move_cursor(Action::CursorUp),
+			Button::DPadDown => self.boards[player].move_cursor(Action::CursorDown),
+			Button::South | Button::West => match self.boards[player].cursor {
+				// West pours a single unit
+				Some(tube_index) => {
+					self.handle_tube_activation(player, tube_index, button == Button::West);
+				},
+				None => self.boards[player].cursor = Some(0),
+			},
+			Button::East => self.boards[player].selected_tube = None,
+			Button::LeftTrigger | Button::RightTrigger if self.viewer.is_none() => self.undo(player),
+			_ => {},
+		}
+	}
+
+	fn handle_gamepad_axis(&mut self, axis: Axis, value: f32) {
+		if self.menu_state.show_settings {
+			return;
+		}
+		let dir = if value > 0.5 {
+			1
+		} else if value < -0.5 {
+			-1
+		} else {
+			0
+		};
+		let player = self.cursor_player();
+		// Only move the cursor once each time the stick is pushed
+		match axis {
+			Axis::LeftStickX if dir != self.gamepad_stick.0 => {
+				self.gamepad_stick.0 = dir;
+				match dir {
+					1 => self.boards[player].move_cursor(Action::CursorRight),
+					-1 => self.boards[player].move_cursor(Action::CursorLeft),
+					_ => {},
+				}
+			},
+			Axis::LeftStickY if dir != self.gamepad_stick.1 => {
+				self.gamepad_stick.1 = dir;
+				match dir {
+					1 => self.boards[player].move_cursor(Action::CursorUp),
+					-1 => self.boards[player].move_cursor(Action::CursorDown),
+					_ => {},
+				}
+			},
+			_ => {},
 		}
 	}
 
-	fn cols(&self) -> usize {
-		let tube_count = self.tubes.len();
-		let max_cols = ((self.width - SCREEN_MARGIN * 2.0 + TUBE_MARGIN) / (TUBE_WIDTH + TUBE_MARGIN)).floor();
-		(tube_count as f32 / self.tubes_factor as f32).ceil().min(max_cols).max(1.0) as usize
+	fn recent_touch(&self) -> bool {
+		self.last_touch.map(|t| t.elapsed() < TOUCH_MOUSE_GRACE).unwrap_or(false)
 	}
 
-	fn rows(&self) -> usize {
-		(self.tubes.len() as f32 / self.cols() as f32).ceil() as usize
+	// Finds the player and tube at the given position
+	fn touch_hit_tube(&self, x: f32, y: f32) -> Option<(usize, usize)> {
+		self.boards
+			.iter()
+			.enumerate()
+			.find_map(|(player, board)| board.touch_hit_tube(x, y).map(|i| (player, i)))
 	}
 
-	fn keymap_key_to_index(&self, keycode: KeyCode) -> Option<usize> {
-		if let Some(index) = KEYMAP.iter().position(|&v| v == keycode) {
-			let (cols, rows) = (self.cols(), self.rows());
-			let row = index / KEYMAP_COLS;
-			let col = index % KEYMAP_COLS;
+	fn screen_margin(&self) -> f32 {
+		SCREEN_MARGIN.min(self.width.min(self.height) / 10.0).max(MIN_SCREEN_MARGIN)
+	}
+
+	// Area below the level text for a player's board, boards
+	// are placed side by side and separated by the screen margin
+	fn board_area(&self, player: usize) -> graphics::Rect {
+		let margin = self.screen_margin();
+		let players = self.boards.len() as f32;
+		let board_w = ((self.width - margin * (players + 1.0)) / players).max(0.0);
+		graphics::Rect::new(
+			margin + player as f32 * (board_w + margin),
+			margin + HUD_HEIGHT,
+			board_w,
+			(self.height - margin * 2.0 - HUD_HEIGHT).max(0.0)
+		)
+	}
+
+	fn update_layout(&mut self) {
+		let hidpi_factor = self.hidpi_factor;
+		for player in 0..self.boards.len() {
+			let area = self.board_area(player);
+			self.boards[player].update_layout(area, hidpi_factor);
+		}
+	}
+
+	// Returns the player and tube index bound to the key
+	fn keymap_key_to_index(&self, binding: KeyBinding) -> Option<(usize, usize)> {
+		let index = match self.menu_state.settings.bindings.action(binding) {
+			Some(Action::Tube(index)) => index,
+			_ => return None,
+		};
+		let row = index / TUBE_KEY_COLS;
+		let col = index % TUBE_KEY_COLS;
+		if self.versus {
+			// Each player's keys are assigned to tubes row by row
+			let player = if versus_key_cols(0).contains(&col) { 0 } else { 1 };
+			let key_cols = versus_key_cols(player);
+			let tube_index = row * key_cols.len() + col - key_cols.start;
+			if tube_index < self.boards[player].tubes.len() {
+				Some((player, tube_index))
+			} else {
+				None
+			}
+		} else {
+			let (cols, rows) = (self.boards[0].cols(), self.boards[0].rows());
 			if col >= cols || row >= rows {
 				None
 			} else {
-				Some(row*cols + col)
+				Some((0, row*cols + col))
 			}
-		} else {
-			None
 		}
 	}
 
-	fn keymap_index_to_key(&self, index: usize) -> Option<KeyCode> {
-		let cols = self.cols();
-		let row = index / cols;
-		let col = index % cols;
-		if col >= KEYMAP_COLS || row >= KEYMAP_ROWS {
+	fn keymap_index_to_key(&self, player: usize, index: usize) -> Option<KeyCode> {
+		let (row, col) = if self.versus {
+			let key_cols = versus_key_cols(player);
+			(index / key_cols.len(), key_cols.start + index % key_cols.len())
+		} else {
+			let cols = self.boards[0].cols();
+			(index / cols, index % cols)
+		};
+		if col >= TUBE_KEY_COLS || row >= TUBE_KEY_ROWS {
 			None
 		} else {
-			Some(KEYMAP[row*KEYMAP_COLS + col])
+			self.menu_state.settings.bindings.tube_key(row*TUBE_KEY_COLS + col)
 		}
 	}
 }
@@ -286,6 +1103,39 @@ impl EventHandler for MainState {
 			event::quit(ctx);
 			return Ok(());
 		}
+		if self.viewer.is_some() {
+			// The board belongs to the replay while watching
+			self.menu_state.restart_level = false;
+			self.menu_state.skip_level = false;
+			self.menu_state.undo = false;
+			self.menu_state.apply_debug_level = false;
+		}
+		if self.is_net_client() {
+			// The host picks the levels of the race
+			self.menu_state.skip_level = false;
+			self.menu_state.apply_debug_level = false;
+			self.menu_state.play_pack_level = None;
+			self.menu_state.leave_pack = false;
+			self.menu_state.load_share_code = false;
+		}
+		if self.net.is_some() {
+			self.menu_state.toggle_versus = false;
+			self.menu_state.open_editor = false;
+		}
+		if self.editor.is_some() {
+			// The board shows the level being edited
+			self.menu_state.restart_level = false;
+			self.menu_state.skip_level = false;
+			self.menu_state.undo = false;
+			self.menu_state.watch_replay = false;
+			self.menu_state.apply_debug_level = false;
+			self.menu_state.toggle_versus = false;
+			self.menu_state.play_pack_level = None;
+			self.menu_state.leave_pack = false;
+			self.menu_state.host_game = false;
+			self.menu_state.join_game = false;
+			self.menu_state.load_share_code = false;
+		}
 		if self.menu_state.restart_level {
 			self.menu_state.restart_level = false;
 			self.new_tubes();
@@ -294,6 +1144,110 @@ impl EventHandler for MainState {
 			self.menu_state.skip_level = false;
 			self.skip_level();
 		}
+		if self.menu_state.load_share_code {
+			self.menu_state.load_share_code = false;
+			self.load_share_code();
+		}
+		if self.menu_state.save_level {
+			self.menu_state.save_level = false;
+			self.save_level(ctx)?;
+		}
+		if self.menu_state.undo {
+			self.menu_state.undo = false;
+			self.undo(self.last_player);
+		}
+		if self.menu_state.save_replay {
+			self.menu_state.save_replay = false;
+			self.save_replay(ctx)?;
+		}
+		if self.menu_state.watch_replay {
+			self.menu_state.watch_replay = false;
+			self.start_viewer(self.replay.clone());
+		}
+		if self.menu_state.close_replay {
+			self.menu_state.close_replay = false;
+			self.close_viewer();
+		}
+		if self.menu_state.replay_restart {
+			self.menu_state.replay_restart = false;
+			self.restart_viewer();
+		}
+		if self.menu_state.replay_toggle_pause {
+			self.menu_state.replay_toggle_pause = false;
+			if let Some(viewer) = &mut self.viewer {
+				viewer.paused = !viewer.paused;
+			}
+		}
+		if self.menu_state.apply_debug_level {
+			self.menu_state.apply_debug_level = false;
+			self.apply_debug_level();
+		}
+		if self.menu_state.toggle_versus {
+			self.menu_state.toggle_versus = false;
+			self.toggle_versus();
+		}
+		if self.menu_state.open_editor {
+			self.menu_state.open_editor = false;
+			if self.editor.is_none() && !self.versus {
+				self.open_editor();
+			}
+		}
+		let editor_actions: Vec<EditorAction> = self.menu_state.editor_actions.drain(..).collect();
+		for action in editor_actions {
+			self.apply_editor_action(ctx, action);
+		}
+		if let Some((pack, index)) = self.menu_state.play_pack_level.take() {
+			self.play_pack_level(pack, index);
+		}
+		if self.menu_state.leave_pack {
+			self.menu_state.leave_pack = false;
+			if self.pack.is_some() {
+				self.leave_pack();
+				self.new_tubes();
+			}
+		}
+		if self.menu_state.save_pack_progress {
+			self.menu_state.save_pack_progress = false;
+			if let Some((pack, _)) = self.pack {
+				self.pack_progress[pack].save(ctx, &self.packs[pack].id)?;
+			}
+		}
+		if self.menu_state.host_game {
+			self.menu_state.host_game = false;
+			match NetSession::host(self.menu_state.net_port.max(0).min(u16::MAX as i32) as u16) {
+				Ok(net) => self.start_net(net),
+				Err(e) => self.menu_state.net_error = Some(e.to_string()),
+			}
+		}
+		if self.menu_state.join_game {
+			self.menu_state.join_game = false;
+			match NetSession::join(self.menu_state.net_address.to_str()) {
+				Ok(net) => self.start_net(net),
+				Err(e) => self.menu_state.net_error = Some(e.to_string()),
+			}
+		}
+		if self.menu_state.leave_game {
+			self.menu_state.leave_game = false;
+			self.net = None;
+		}
+		if self.menu_state.theme_changed {
+			self.menu_state.theme_changed = false;
+			self.apply_theme();
+		}
+		self.update_spectators();
+		if self.menu_state.reload_mods {
+			self.menu_state.reload_mods = false;
+			let mods_folder = self.menu_state.settings.mods_folder.clone();
+			self.mods.load(ctx, &mods_folder);
+		}
+		if self.menu_state.clear_console {
+			self.menu_state.clear_console = false;
+			self.mods.log.clear();
+		}
+		if self.menu_state.settings_changed || self.menu_state.full_screen_changed {
+			self.menu_state.settings_changed = false;
+			self.menu_state.settings.save(ctx)?;
+		}
 
 		let win = graphics::window(ctx);
 		let current_monitor = win.get_current_monitor();
@@ -334,81 +1288,288 @@ impl EventHandler for MainState {
 		}
 
 		// Main game logic
-			let (cols, rows) = (self.cols() as f32, self.rows() as f32);
-		let total_w = cols * (TUBE_WIDTH + TUBE_MARGIN) - TUBE_MARGIN;
-		let total_h = rows * (self.tube_capacity * TUBE_WIDTH + TUBE_MARGIN);
+		self.update_layout();
+		self.particles.update(timer::delta(ctx));
 
-		let mousedown = input::mouse::button_pressed(ctx, MouseButton::Left);
+		if self.grader.poll() && self.generating {
+			self.new_tubes();
+		}
+		if let Some((pack, index, receiver)) = &self.pack_par {
+			match receiver.try_recv() {
+				Ok(par) => {
+					self.packs[*pack].levels[*index].par = Some(par);
+					self.pack_par = None;
+				},
+				Err(TryRecvError::Empty) => {},
+				Err(TryRecvError::Disconnected) => self.pack_par = None,
+			}
+		}
 
-		let mut clicked_tube: Option<usize> = None;
-		for i in 0..self.tubes.len() {
-			let keycode = self.keymap_index_to_key(i);
-			let tube = &mut self.tubes[i];
-
-			// Update dimensions
-			tube.dimensions.w = TUBE_WIDTH;
-			tube.dimensions.h = tube.dimensions.w * tube.capacity;
-			tube.dimensions.x = SCREEN_MARGIN + (self.width - SCREEN_MARGIN * 2.0) / 2.0 - total_w / 2.0 + (tube.dimensions.w + TUBE_MARGIN) * (i as f32 % cols).floor();
-			tube.dimensions.y = SCREEN_MARGIN + (self.height - SCREEN_MARGIN * 2.0 + TUBE_MARGIN) / 2.0 - total_h / 2.0 + (tube.dimensions.h + TUBE_MARGIN) * (i as f32 / cols).floor();
-
-			// Update keycode
-			tube.keycode = keycode;
-
-			if !self.menu_state.show_settings {
-				// Detect hover
-				let hovered = self.mouse_pos.x >= tube.dimensions.x &&
-					self.mouse_pos.x <= tube.dimensions.x + tube.dimensions.w &&
-					self.mouse_pos.y >= tube.dimensions.y &&
-					self.mouse_pos.y <= tube.dimensions.y + tube.dimensions.h;
-
-				// Update mouse states
-				let is_selected_tube = self.selected_tube.is_some() && self.selected_tube.unwrap() == i;
-				let tube_clicked = hovered && tube.mousedown && !mousedown;
-				if tube_clicked {
-					clicked_tube = Some(i);
+		// Run control commands, answering each on stdout once the level
+		// it loads has been generated
+		if let Some(commands) = &self.commands {
+			self.queued_commands.extend(commands.try_iter());
+		}
+		while !self.generating {
+			if self.command_answer_pending {
+				self.command_answer_pending = false;
+				println!("{}", command::state_response(self.level, &self.boards[0]));
+			}
+			let line = match self.queued_commands.pop_front() {
+				Some(line) => line,
+				None => break,
+			};
+			match Command::parse(&line).and_then(|c| self.run_command(c)) {
+				Ok(()) => self.command_answer_pending = true,
+				Err(error) => println!("{}", command::error_response(&error)),
+			}
+		}
+
+		// Receive the race level and opponents' progress
+		let net_events = self.net.as_mut().map(|n| n.poll()).unwrap_or_default();
+		for event in net_events {
+			match event {
+				NetEvent::Level { number, replay } => {
+					self.leave_pack();
+					self.level = number;
+					self.custom_level = Some(replay.level);
+					self.new_tubes();
+				},
+				NetEvent::Disconnected => self.menu_state.net_error = Some("Lost connection to the host".to_string()),
+			}
+		}
+
+		// Play back the replay being watched
+		let replay_moves = match &mut self.viewer {
+			Some(viewer) if self.menu_state.replay_step => {
+				viewer.paused = true;
+				viewer.step().into_iter().collect()
+			},
+			Some(viewer) => viewer.advance(timer::delta(ctx).mul_f32(self.menu_state.replay_speed)),
+			None => Vec::new(),
+		};
+		self.menu_state.replay_step = false;
+		for action in replay_moves {
+			self.apply_replay_move(action);
+		}
+
+		let mousedown = input::mouse::button_pressed(ctx, MouseButton::Left);
+		let single_unit = input::keyboard::active_mods(ctx).contains(KeyMods::SHIFT);
+		let recent_touch = self.recent_touch();
+		let touch_target = self.touch.as_ref().and_then(|t| t.target_tube);
+
+		let mut clicked_tube: Option<(usize, usize)> = None;
+		for player in 0..self.boards.len() {
+			for i in 0..self.boards[player].tubes.len() {
+				let keycode = self.keymap_index_to_key(player, i);
+				let board = &mut self.boards[player];
+				let is_selected_tube = board.selected_tube == Some(i);
+				let tube = &mut board.tubes[i];
+
+				// Update keycode, theme and cursor
+				tube.keycode = keycode;
+				tube.theme = self.theme.clone();
+				tube.focused = board.cursor == Some(i);
+
+				if self.touch.is_some() || recent_touch {
+					// Touch has no hover, only highlight the tube being dragged to
+					tube.mousedown = false;
+					tube.clicked = is_selected_tube;
+					tube.hovered = touch_target == Some((player, i));
+				} else if !self.menu_state.show_settings {
+					// Detect hover
+					let hovered = self.mouse_pos.x >= tube.dimensions.x &&
+						self.mouse_pos.x <= tube.dimensions.x + tube.dimensions.w &&
+						self.mouse_pos.y >= tube.dimensions.y &&
+						self.mouse_pos.y <= tube.dimensions.y + tube.dimensions.h;
+
+					// Update mouse states
+					let tube_clicked = hovered && tube.mousedown && !mousedown;
+					if tube_clicked {
+						clicked_tube = Some((player, i));
+					}
+					tube.mousedown = mousedown && (tube.mousedown || tube.hovered);
+					tube.clicked = is_selected_tube || tube_clicked;
+					tube.hovered = !mousedown && hovered;
 				}
-				tube.mousedown = mousedown && (tube.mousedown || tube.hovered);
-				tube.clicked = is_selected_tube || tube_clicked;
-				tube.hovered = !mousedown && hovered;
 			}
 		}
-		if let Some(clicked_tube_index) = clicked_tube {
-			self.handle_tube_activation(clicked_tube_index);
+		if let Some((player, clicked_tube_index)) = clicked_tube {
+			// Hide the keyboard cursor while using the mouse
+			self.boards[player].cursor = None;
+			if self.editor.is_some() {
+				self.paint_tube(clicked_tube_index, self.mouse_pos.y);
+			} else {
+				self.handle_tube_activation(player, clicked_tube_index, single_unit);
+			}
+		}
+
+		if let Some(net) = &mut self.net {
+			let board = &self.boards[0];
+			net.send_progress(board.history.len(), board.complete_pct());
 		}
+		if let Some(spectators) = &mut self.spectators {
+			spectators.poll();
+		}
+
+		// Play sounds queued by this frame's moves
+		let settings = &self.menu_state.settings;
+		let volume = if settings.muted { 0.0 } else { settings.volume };
+		self.audio.play_queued(ctx, volume);
 
 		Ok(())
 	}
 
 	fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
-		graphics::clear(ctx, COLOR_BG);
-
-		let complete_pct = self.complete_pct();
+		graphics::clear(ctx, self.theme.background);
+
+		let level_completed = self.level_completed();
+		let versus = self.versus;
+		let viewing_replay = self.viewer.is_some();
+		// Undo from the menu can not tell which versus player is asking
+		let can_undo = !self.boards[0].history.is_empty() && !viewing_replay && !versus;
+		let can_watch_replay = !viewing_replay && !versus && !self.replay.events.is_empty();
+		let net_status = self.net.as_ref().map(|n| n.status());
+		let opponents = self.net.as_ref().map(|n| n.opponents.clone()).unwrap_or_default();
+		let is_net_client = self.is_net_client();
+		let can_skip = self.pack.is_none() || self.next_pack_level().is_some();
+		let current_pack = self.pack;
+		let pack_list: Vec<PackListEntry> = self.packs
+			.iter()
+			.zip(&self.pack_progress)
+			.map(|(pack, progress)| {
+				let completed = (0..pack.levels.len()).filter(|&i| progress.is_completed(i)).count();
+				let levels = pack.levels.iter().enumerate().map(|(i, level)| {
+					let unlocked = pack.is_unlocked(i, progress);
+					let mut label = level.title(i);
+					match (level.par.flatten(), progress.best_moves(i)) {
+						(Some(par), Some(best)) => label.push_str(&format!(" (par {}, best {})", par, best)),
+						(Some(par), None) => label.push_str(&format!(" (par {})", par)),
+						(None, Some(best)) => label.push_str(&format!(" (best {})", best)),
+						(None, None) => {},
+					}
+					if !unlocked {
+						label.push_str(" - locked");
+					}
+					(ImString::new(label), unlocked)
+				}).collect();
+				let description = format!("By {}, {} / {} completed", pack.author, completed, pack.levels.len());
+				(ImString::new(pack.name.clone()), description, levels)
+			})
+			.collect();
+		let editing = self.editor.is_some();
+		let share_code = self.share_code.map(|c| ImString::new(c.to_string()));
+		let editor_state = self.editor.as_ref().map(|e| {
+			let selected = e.selected_tube.map(|i| {
+				let tube = &e.level.tubes[i];
+				(i, tube.capacity.unwrap_or(e.level.capacity), editor::kind_index(tube.kind))
+			});
+			(e.level.tubes.len(), selected, e.paint, e.status.clone())
+		});
+		let palette = self.theme.palette.clone();
+		let pack_hud = self.pack.map(|(pack, index)| {
+			let pack = &self.packs[pack];
+			(format!("{}: {}", pack.name, pack.levels[index].title(index)), pack.levels[index].par.flatten())
+		});
+		let mod_names = self.mods.script_names();
+		let mod_log = self.mods.log.clone();
+		let spectator_status = self.spectators.as_ref().map(|s| format!("{} spectator(s) on port {}", s.client_count(), s.port()));
+		let replay_status = self.viewer.as_ref().map(|v| (v.paused, v.position(), v.replay.events.len(), v.time(), v.replay.duration()));
+		let theme_names: Vec<ImString> = self.themes.iter().map(|t| ImString::new(t.name.clone())).collect();
+		let theme_palette = self.selected_theme().palette.clone();
+		let palette_colors_in_use = self.palette_colors_in_use();
 		let (width, height) = (self.width, self.height);
+		let screen_margin = self.screen_margin();
 
-		// Draw tubes
+		// Draw tubes, only rebuilding meshes which changed
 		let param = DrawParam::default();
-		for tube in &mut self.tubes {
+		let mut mesh_rebuilds = 0;
+		for tube in self.boards.iter_mut().flat_map(|b| b.tubes.iter_mut()) {
+			if tube.prepare(ctx)? {
+				mesh_rebuilds += 1;
+			}
 			tube.draw(ctx, param)?;
 		}
+		self.particles.draw(ctx)?;
+		self.frame_stats.mesh_rebuilds = mesh_rebuilds;
+		self.frame_stats.record_frame(timer::delta(ctx));
+		let frame_stats = self.frame_stats.clone();
+
+		// Reasons for recent invalid pours, shown below the tubes
+		let mut pour_error_tips = Vec::new();
+		if self.menu_state.settings.pour_error_tips {
+			for (player, board) in self.boards.iter().enumerate() {
+				for (i, tube) in board.tubes.iter().enumerate() {
+					if let Some(error) = tube.recent_error() {
+						let pos = [tube.dimensions.x + tube.dimensions.w / 2.0, tube.dimensions.y + tube.dimensions.h + 4.0];
+						pour_error_tips.push((player, i, ImString::new(error.description()), pos));
+					}
+				}
+			}
+		}
 
-		// Draw total completed text
-		let completed_color = if complete_pct == 1.0 {
-			COLOR_LIGHTGREEN
-		} else if complete_pct >= 0.75 {
-			COLOR_CYAN
-		} else if complete_pct >= 0.5 {
-			COLOR_YELLOW
-		} else if complete_pct >= 0.25 {
-			COLOR_ORANGE
-		} else {
-			COLOR_RED
-		};
-		let mut pcttext = Text::new(format!("Level {} (", self.level));
-		pcttext.add(TextFragment::new(format!("{}% completed", (complete_pct * 100.0).floor())).color(completed_color));
-		pcttext.add(TextFragment::new(")"));
-		pcttext.set_font(self.font, Scale::uniform(18.0));
-		let pcttext_w = pcttext.width(ctx) as f32;
-		graphics::queue_text(ctx, &pcttext, Point2::new(width / 2.0 - pcttext_w / 2.0, SCREEN_MARGIN), Some(graphics::WHITE));
+		// Snapshot of the game state for the debug window
+		let mut debug_info = Vec::new();
+		let mut debug_tubes = Vec::new();
+		if self.menu_state.show_debug {
+			let board = &self.boards[0];
+			let source = match (self.share_code, &self.custom_level, self.pack) {
+				(Some(code), _, _) => format!("share code {}", code),
+				(None, Some(_), _) => "custom level".to_string(),
+				(None, None, Some((pack, _))) => format!("pack {:?}", self.packs[pack].name),
+				(None, None, None) => "level from a mod".to_string(),
+			};
+			debug_info.push(format!("Level: {}, {}", self.level, source));
+			if let (Some(moves), None, None) = (self.solution_moves, &self.custom_level, self.pack) {
+				debug_info.push(format!("Graded with a shortest solution of {} moves", moves));
+			}
+			debug_info.push(format!("Selected tube: {:?}, cursor: {:?}", board.selected_tube, board.cursor));
+			debug_info.push(format!("Cols: {}, rows: {}, tubes factor: {}", board.cols(), board.rows(), board.tubes_factor));
+			debug_info.push(format!("Capacity: {}, history: {}", board.tube_capacity, board.history.len()));
+			if versus {
+				debug_info.push(format!("Versus mode, showing player 1, winner: {:?}", self.winner.map(|p| p + 1)));
+			}
+			for (i, tube) in board.tubes.iter().enumerate() {
+				let contents: Vec<String> = tube.contents()
+					.iter()
+					.map(|c| format!("{}({})*{}", color_name(c.color()), c.color(), c.amount()))
+					.collect();
+				debug_tubes.push(format!("{:2} {:?}{}: [{}]", i + 1, tube.kind, if tube.locked { " locked" } else { "" }, contents.join(", ")));
+			}
+			if !self.menu_state.debug_level_edited {
+				self.menu_state.debug_level = ImString::new(Level::from_tubes(board.tube_capacity, &board.tubes).to_string());
+			}
+		}
+
+		// Draw total completed text, above each board in versus mode
+		for (player, board) in self.boards.iter().enumerate() {
+			let complete_pct = board.complete_pct();
+			let completed_color = self.theme.progress_color(complete_pct);
+			let mut pcttext = match &pack_hud {
+				_ if editing => Text::new(format!("Level editor ({} tubes", board.tubes.len())),
+				_ if versus => Text::new(format!("Player {} (", player + 1)),
+				Some((title, _)) => Text::new(format!("{} (", title)),
+				None => Text::new(format!("Level {} (", self.level)),
+			};
+			if editing {
+				// Edited levels are not played, so there is no progress
+			} else if self.generating {
+				pcttext.add(TextFragment::new("generating..."));
+			} else if self.winner == Some(player) {
+				pcttext.add(TextFragment::new("winner!").color(completed_color));
+			} else {
+				pcttext.add(TextFragment::new(format!("{}% completed", (complete_pct * 100.0).floor())).color(completed_color));
+			}
+			if let (Some((_, Some(par))), false, false) = (&pack_hud, versus, editing) {
+				pcttext.add(TextFragment::new(format!(", {} moves, par {}", board.history.len(), par)));
+			}
+			pcttext.add(TextFragment::new(")"));
+			pcttext.set_font(self.font, Scale::uniform(18.0));
+			let pcttext_w = pcttext.width(ctx) as f32;
+			let area = self.board_area(player);
+			let center_x = if versus { area.x + area.w / 2.0 } else { width / 2.0 };
+			graphics::queue_text(ctx, &pcttext, Point2::new(center_x - pcttext_w / 2.0, screen_margin), Some(self.theme.hud_text));
+		}
 
 		// Draw all queued text
 		graphics::draw_queued_text(ctx, param, None, graphics::FilterMode::Linear)?;
@@ -423,33 +1584,463 @@ impl EventHandler for MainState {
 						state.show_settings = true;
 					}
 
-					let item = MenuItem::new(im_str!("Exit game")).shortcut(im_str!("Ctrl + Q"));
+					let shortcut = shortcut_str(&state.settings, Action::ToggleDebug);
+					let item = MenuItem::new(im_str!("Debug"))
+						.shortcut(&shortcut)
+						.selected(state.show_debug);
+					if item.build(ui) {
+						state.show_debug = !state.show_debug;
+					}
+
+					let item = MenuItem::new(im_str!("Level packs"))
+						.selected(current_pack.is_some())
+						.enabled(!is_net_client);
+					if item.build(ui) {
+						state.show_packs = true;
+						if let Some((pack, _)) = current_pack {
+							state.pack_picker = pack;
+						}
+					}
+
+					let item = MenuItem::new(im_str!("Level editor"))
+						.selected(editing)
+						.enabled(!versus && net_status.is_none());
+					if item.build(ui) {
+						if editing {
+							state.editor_actions.push(EditorAction::Close);
+						} else {
+							state.open_editor = true;
+						}
+					}
+
+					let item = MenuItem::new(im_str!("Two player race"))
+						.selected(versus)
+						.enabled(net_status.is_none());
+					state.toggle_versus = item.build(ui);
+
+					let item = MenuItem::new(im_str!("LAN race"))
+						.selected(net_status.is_some())
+						.enabled(!versus);
+					if item.build(ui) {
+						state.show_multiplayer = true;
+					}
+
+					let item = MenuItem::new(im_str!("Script console"))
+						.selected(state.show_console);
+					if item.build(ui) {
+						state.show_console = !state.show_console;
+					}
+
+					let shortcut = shortcut_str(&state.settings, Action::Quit);
+					let item = MenuItem::new(im_str!("Exit game")).shortcut(&shortcut);
 					state.quit = item.build(ui);
 
 					game_menu.end(ui);
 				}
 
 				if let Some(level_menu) = ui.begin_menu(im_str!("Level"), true) {
+					let shortcut = shortcut_str(&state.settings, Action::Undo);
+					let item = MenuItem::new(im_str!("Undo"))
+						.shortcut(&shortcut)
+						.enabled(can_undo);
+					state.undo = item.build(ui);
+
+					let shortcut = shortcut_str(&state.settings, Action::RestartLevel);
 					let item = MenuItem::new(im_str!("Restart level"))
-						.shortcut(im_str!("Ctrl + R"));
+						.shortcut(&shortcut);
 					state.restart_level = item.build(ui);
 
+					let shortcut = shortcut_str(&state.settings, Action::NextLevel);
 					let item = MenuItem::new(im_str!("Next level"))
-						.shortcut(im_str!("Ctrl + N"))
-						.enabled(complete_pct == 1.0);
+						.shortcut(&shortcut)
+						.enabled(level_completed && can_skip && !is_net_client);
 					let next_level = item.build(ui);
 
-					let item = MenuItem::new(im_str!("Skip level"));
+					let item = MenuItem::new(im_str!("Skip level"))
+						.enabled(can_skip && !is_net_client);
 					let skip_level = item.build(ui);
 
 					state.skip_level = next_level || skip_level;
 
+					let item = MenuItem::new(im_str!("Save level"));
+					state.save_level = item.build(ui);
+
+					let item = MenuItem::new(im_str!("Share code"))
+						.selected(state.show_share_code);
+					if item.build(ui) {
+						state.show_share_code = !state.show_share_code;
+					}
+
+					ui.separator();
+
+					let item = MenuItem::new(im_str!("Save replay"))
+						.enabled(!viewing_replay && !versus);
+					state.save_replay = item.build(ui);
+
+					let item = MenuItem::new(im_str!("Watch replay"))
+						.enabled(can_watch_replay);
+					state.watch_replay = item.build(ui);
+
 					level_menu.end(ui);
 				}
 
 				menu_bar.end(ui);
 			}
 
+			// Replay viewer controls
+			if let Some((paused, position, event_count, time, duration)) = replay_status {
+				let mut opened = true;
+				if let Some(replay_window) = Window::new(im_str!("Replay"))
+					.position([width / 2.0, height - 10.0], Condition::FirstUseEver)
+					.position_pivot([0.5, 1.0])
+					.always_auto_resize(true)
+					.collapsible(false)
+					.opened(&mut opened)
+					.begin(ui)
+				{
+					ui.text(format!("Move {} / {}, {:.1} / {:.1} s", position, event_count, time, duration));
+					if ui.button(if paused { im_str!("Play") } else { im_str!("Pause") }, [60.0, 0.0]) {
+						state.replay_toggle_pause = true;
+					}
+					ui.same_line(0.0);
+					if ui.button(im_str!("Step"), [60.0, 0.0]) {
+						state.replay_step = true;
+					}
+					ui.same_line(0.0);
+					if ui.button(im_str!("Restart"), [60.0, 0.0]) {
+						state.replay_restart = true;
+					}
+					Slider::new(im_str!("Speed"))
+						.range(0.25..=4.0)
+						.display_format(im_str!("%.2fx"))
+						.build(ui, &mut state.replay_speed);
+					replay_window.end(ui);
+				}
+				if !opened {
+					state.close_replay = true;
+				}
+			}
+
+			// Script console
+			if state.show_console {
+				if let Some(console_window) = Window::new(im_str!("Script console"))
+					.size([420.0, 240.0], Condition::FirstUseEver)
+					.position([10.0, height - 10.0], Condition::FirstUseEver)
+					.position_pivot([0.0, 1.0])
+					.opened(&mut state.show_console)
+					.begin(ui)
+				{
+					if mod_names.is_empty() {
+						ui.text(format!("No scripts loaded from {}", state.settings.mods_folder));
+					} else {
+						ui.text(format!("Scripts: {}", mod_names.join(", ")));
+					}
+					if ui.button(im_str!("Reload"), [0.0, 0.0]) {
+						state.reload_mods = true;
+					}
+					ui.same_line(0.0);
+					if ui.button(im_str!("Clear"), [0.0, 0.0]) {
+						state.clear_console = true;
+					}
+					ui.separator();
+					for line in &mod_log {
+						ui.text_wrapped(&ImString::new(line.clone()));
+					}
+					console_window.end(ui);
+				}
+			}
+
+			// Level editor window
+			if let Some((tube_count, selected, paint, status)) = &editor_state {
+				let mut opened = true;
+				if let Some(editor_window) = Window::new(im_str!("Level editor"))
+					.size([300.0, 380.0], Condition::FirstUseEver)
+					.position([width - 10.0, 30.0], Condition::FirstUseEver)
+					.position_pivot([1.0, 0.0])
+					.opened(&mut opened)
+					.begin(ui)
+				{
+					ui.text(format!("{} tubes, click a tube to paint it", tube_count));
+					if ui.button(im_str!("Add tube"), [0.0, 0.0]) {
+						state.editor_actions.push(EditorAction::AddTube);
+					}
+
+					// Paint color, or the eraser
+					ui.separator();
+					for (i, color) in palette.iter().enumerate() {
+						if i % 6 != 0 {
+							ui.same_line(0.0);
+						}
+						if ColorButton::new(&im_str!("{}##paint_color{}", color_name(i), i), [color.r, color.g, color.b, 1.0])
+							.size([24.0, 24.0])
+							.build(ui)
+						{
+							state.editor_actions.push(EditorAction::SetPaint(Some(i)));
+						}
+					}
+					if ui.radio_button_bool(im_str!("Eraser"), paint.is_none()) {
+						state.editor_actions.push(EditorAction::SetPaint(None));
+					}
+					ui.text(match paint {
+						Some(color) => format!("Painting {}", color_name(*color)),
+						None => "Erasing from the clicked unit up".to_string(),
+					});
+
+					// Selected tube
+					ui.separator();
+					match selected {
+						Some((index, capacity, kind)) => {
+							ui.text(format!("Tube {}", index + 1));
+							let mut capacity = *capacity as i32;
+							if Slider::new(im_str!("Capacity")).range(1..=MAX_TUBE_CAPACITY as i32).build(ui, &mut capacity) {
+								state.editor_actions.push(EditorAction::SetCapacity(capacity as f32));
+							}
+							// Colored kinds take the paint color, so the eraser can not set them
+							let kind_names: Vec<ImString> = KIND_NAMES.iter().map(|n| ImString::new(*n)).collect();
+							if let Some(combo) = ComboBox::new(im_str!("Kind")).preview_value(&kind_names[*kind]).begin(ui) {
+								for (i, name) in kind_names.iter().enumerate() {
+									let item = Selectable::new(name)
+										.selected(i == *kind)
+										.disabled(paint.is_none() && editor::kind_uses_color(i));
+									if item.build(ui) && i != *kind {
+										state.editor_actions.push(EditorAction::SetKind(i));
+									}
+								}
+								combo.end(ui);
+							}
+							if ui.button(im_str!("Remove tube"), [0.0, 0.0]) {
+								state.editor_actions.push(EditorAction::RemoveTube);
+							}
+						},
+						None => ui.text("No tube selected"),
+					}
+
+					// Checking, saving and playing the level
+					ui.separator();
+					if ui.button(im_str!("Check solvable"), [0.0, 0.0]) {
+						state.editor_actions.push(EditorAction::Check);
+					}
+					ui.same_line(0.0);
+					if ui.button(im_str!("Play"), [0.0, 0.0]) {
+						state.editor_actions.push(EditorAction::Play);
+					}
+					ui.input_text(im_str!("File"), &mut state.editor_path)
+						.resize_buffer(true)
+						.build();
+					if ui.button(im_str!("Save"), [0.0, 0.0]) {
+						state.editor_actions.push(EditorAction::Save);
+					}
+					if let Some(status) = status {
+						ui.text_wrapped(&ImString::new(status.clone()));
+					}
+					editor_window.end(ui);
+				}
+				if !opened {
+					state.editor_actions.push(EditorAction::Close);
+				}
+			}
+
+			// Share codes of generated levels
+			if state.show_share_code {
+				if let Some(share_window) = Window::new(im_str!("Share code"))
+					.position([width / 2.0, height / 2.0], Condition::Appearing)
+					.position_pivot([0.5, 0.5])
+					.always_auto_resize(true)
+					.collapsible(false)
+					.opened(&mut state.show_share_code)
+					.begin(ui)
+				{
+					match &share_code {
+						Some(code) => {
+							let mut code = code.clone();
+							ui.input_text(im_str!("This level"), &mut code)
+								.read_only(true)
+								.build();
+						},
+						None => ui.text("This level was not generated from a seed"),
+					}
+					ui.text(format!("Level generator version {}", GENERATOR_VERSION));
+					ui.separator();
+					ui.input_text(im_str!("Code"), &mut state.share_code_input)
+						.resize_buffer(true)
+						.build();
+					if ui.button(im_str!("Load"), [0.0, 0.0]) {
+						state.load_share_code = true;
+					}
+					if let Some(error) = &state.share_code_error {
+						ui.text_colored([1.0, 0.3, 0.3, 1.0], error);
+					}
+					share_window.end(ui);
+				}
+			}
+
+			// Level pack picker
+			if state.show_packs {
+				if let Some(packs_window) = Window::new(im_str!("Level packs"))
+					.size([340.0, 360.0], Condition::FirstUseEver)
+					.position([width / 2.0, height / 2.0], Condition::Appearing)
+					.position_pivot([0.5, 0.5])
+					.opened(&mut state.show_packs)
+					.begin(ui)
+				{
+					let pack_names: Vec<&ImString> = pack_list.iter().map(|p| &p.0).collect();
+					ComboBox::new(im_str!("Pack")).build_simple_string(ui, &mut state.pack_picker, &pack_names);
+					if let Some((_, description, levels)) = pack_list.get(state.pack_picker) {
+						ui.text(description);
+						ui.separator();
+						for (i, (label, unlocked)) in levels.iter().enumerate() {
+							let item = Selectable::new(label)
+								.selected(current_pack == Some((state.pack_picker, i)))
+								.disabled(!unlocked);
+							if item.build(ui) {
+								state.play_pack_level = Some((state.pack_picker, i));
+							}
+						}
+					}
+					ui.separator();
+					if ui.button(im_str!("Endless levels"), [0.0, 0.0]) {
+						state.leave_pack = true;
+					}
+					packs_window.end(ui);
+				}
+			}
+
+			// LAN race window
+			if state.show_multiplayer {
+				if let Some(net_window) = Window::new(im_str!("LAN race"))
+					.position([width / 2.0, height / 2.0], Condition::Appearing)
+					.position_pivot([0.5, 0.5])
+					.always_auto_resize(true)
+					.collapsible(false)
+					.opened(&mut state.show_multiplayer)
+					.begin(ui)
+				{
+					match &net_status {
+						Some(status) => {
+							ui.text(status);
+							if ui.button(im_str!("Leave"), [0.0, 0.0]) {
+								state.leave_game = true;
+							}
+						},
+						None => {
+							ui.input_int(im_str!("Port"), &mut state.net_port).build();
+							if ui.button(im_str!("Host"), [0.0, 0.0]) {
+								state.host_game = true;
+							}
+							ui.separator();
+							ui.input_text(im_str!("Address"), &mut state.net_address)
+								.resize_buffer(true)
+								.build();
+							if ui.button(im_str!("Join"), [0.0, 0.0]) {
+								state.join_game = true;
+							}
+						},
+					}
+					if let Some(error) = &state.net_error {
+						ui.text_colored([1.0, 0.3, 0.3, 1.0], error);
+					}
+					net_window.end(ui);
+				}
+			}
+
+			// Opponents' progress in a LAN race
+			if !opponents.is_empty() {
+				if let Some(opponents_window) = Window::new(im_str!("##opponents"))
+					.position([10.0, height - 10.0], Condition::Always)
+					.position_pivot([0.0, 1.0])
+					.title_bar(false)
+					.resizable(false)
+					.movable(false)
+					.always_auto_resize(true)
+					.focus_on_appearing(false)
+					.no_inputs()
+					.begin(ui)
+				{
+					for opponent in &opponents {
+						let label = if opponent.connected {
+							im_str!("Player {}: {} moves", opponent.player + 1, opponent.moves)
+						} else {
+							im_str!("Player {}: left", opponent.player + 1)
+						};
+						ProgressBar::new(opponent.complete_pct)
+							.size([200.0, 0.0])
+							.overlay_text(&label)
+							.build(ui);
+					}
+					opponents_window.end(ui);
+				}
+			}
+
+			// Invalid pour tooltips
+			for (player, i, tip, pos) in &pour_error_tips {
+				if let Some(tip_window) = Window::new(&im_str!("##pour_error{}_{}", player, i))
+					.position(*pos, Condition::Always)
+					.position_pivot([0.5, 0.0])
+					.title_bar(false)
+					.resizable(false)
+					.movable(false)
+					.always_auto_resize(true)
+					.focus_on_appearing(false)
+					.no_inputs()
+					.begin(ui)
+				{
+					ui.text(tip);
+					tip_window.end(ui);
+				}
+			}
+
+			// Debug window
+			if state.show_debug {
+				if let Some(debug_window) = Window::new(im_str!("Debug"))
+					.size([360.0, 420.0], Condition::FirstUseEver)
+					.position([width - 10.0, 30.0], Condition::FirstUseEver)
+					.position_pivot([1.0, 0.0])
+					.opened(&mut state.show_debug)
+					.begin(ui)
+				{
+					ui.text(format!("{:.0} FPS", frame_stats.fps()));
+					ui.text(format!("Frame time: {:.2} ms avg, {:.2} ms max", frame_stats.avg_ms(), frame_stats.max_ms()));
+					ui.text(format!("Tube meshes rebuilt: {}", frame_stats.mesh_rebuilds));
+					let frame_times = frame_stats.frame_times();
+					PlotLines::new(ui, im_str!("##frame_times"), &frame_times)
+						.graph_size([250.0, 40.0])
+						.scale_min(0.0)
+						.build();
+
+					ui.separator();
+					for line in &debug_info {
+						ui.text(line);
+					}
+
+					if CollapsingHeader::new(im_str!("Tube contents")).default_open(true).build(ui) {
+						for line in &debug_tubes {
+							ui.text(line);
+						}
+					}
+
+					if CollapsingHeader::new(im_str!("Edit level")).build(ui) {
+						if ui.input_text_multiline(im_str!("##debug_level"), &mut state.debug_level, [-1.0, 150.0])
+							.resize_buffer(true)
+							.build()
+						{
+							state.debug_level_edited = true;
+						}
+						if ui.button(im_str!("Apply"), [0.0, 0.0]) {
+							state.apply_debug_level = true;
+						}
+						ui.same_line(0.0);
+						if ui.button(im_str!("Revert"), [0.0, 0.0]) {
+							state.debug_level_edited = false;
+							state.debug_level_error = None;
+						}
+						if let Some(error) = &state.debug_level_error {
+							ui.text_colored([1.0, 0.3, 0.3, 1.0], error);
+						}
+					}
+					debug_window.end(ui);
+				}
+			}
+
 			// Settings window
 			if state.show_settings {
 				if let Some(settings_window) = {
@@ -465,6 +2056,137 @@ impl EventHandler for MainState {
 				} {
 					state.full_screen_changed = ui.checkbox(im_str!("Fullscreen"), &mut state.settings.full_screen);
 
+					let theme_name_refs: Vec<&ImString> = theme_names.iter().collect();
+					let mut theme_index = theme_names.iter().position(|n| n.to_str() == state.settings.theme).unwrap_or(0);
+					if ComboBox::new(im_str!("Theme")).build_simple_string(ui, &mut theme_index, &theme_name_refs) {
+						state.settings.theme = theme_names[theme_index].to_str().to_string();
+						state.theme_changed = true;
+						state.settings_changed = true;
+					}
+
+					let max_colors = state.settings.custom_palette.as_ref().unwrap_or(&theme_palette).len() as i32;
+					let mut color_count = state.settings.color_count as i32;
+					state.settings_changed |= ui.checkbox(im_str!("Difficulty progression (from next level)"), &mut state.settings.progression);
+					if Slider::new(im_str!("Colors without progression")).range(2..=max_colors).build(ui, &mut color_count) {
+						state.settings.color_count = color_count as usize;
+						state.settings_changed = true;
+					}
+
+					// Palette editor
+					if CollapsingHeader::new(im_str!("Palette")).build(ui) {
+						let mut use_custom_palette = state.settings.custom_palette.is_some();
+						if ui.checkbox(im_str!("Use custom palette"), &mut use_custom_palette) {
+							state.settings.custom_palette = if use_custom_palette {
+								Some(theme_palette.clone())
+							} else {
+								None
+							};
+							state.theme_changed = true;
+							state.settings_changed = true;
+						}
+
+						if let Some(palette) = &mut state.settings.custom_palette {
+							let mut remove_index = None;
+							for i in 0..palette.len() {
+								let mut rgb = [palette[i].r, palette[i].g, palette[i].b];
+								if ColorEdit::new(&im_str!("{}##palette{}", color_name(i), i), &mut rgb).alpha(false).build(ui) {
+									palette[i] = graphics::Color::new(rgb[0], rgb[1], rgb[2], 1.0);
+									state.theme_changed = true;
+									state.settings_changed = true;
+								}
+								ui.same_line(0.0);
+								// Removing a color renumbers the ones after it,
+								// which would recolor liquids on the boards
+								if i < palette_colors_in_use {
+									ui.text_disabled("In use");
+								} else if ui.small_button(&im_str!("Remove##palette{}", i)) && palette.len() > 2 {
+									remove_index = Some(i);
+								}
+
+								// Warn about colors which are hard to tell apart
+								for (j, delta) in similar_colors(palette, i) {
+									ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("Too close to {} (delta E {:.1})", color_name(j), delta));
+								}
+							}
+							if let Some(i) = remove_index {
+								palette.remove(i);
+								state.theme_changed = true;
+								state.settings_changed = true;
+							}
+							if ui.button(im_str!("Add color"), [0.0, 0.0]) {
+								palette.push(graphics::Color::new(0.5, 0.5, 0.5, 1.0));
+								state.theme_changed = true;
+								state.settings_changed = true;
+							}
+						}
+					}
+
+					state.settings_changed |= ui.checkbox(im_str!("Special tubes (from next level)"), &mut state.settings.special_tubes);
+					state.settings_changed |= ui.checkbox(im_str!("Pour one unit at a time"), &mut state.settings.rules.partial_pour);
+					state.settings_changed |= ui.checkbox(im_str!("Lock completed tubes"), &mut state.settings.rules.lock_completed_tubes);
+					ui.text("Hold Shift to pour a single unit");
+					state.settings_changed |= ui.checkbox(im_str!("Explain invalid pours"), &mut state.settings.pour_error_tips);
+					state.settings_changed |= ui.checkbox(im_str!("Keep selection after invalid pour"), &mut state.settings.keep_selection_on_error);
+
+					// The volume applies while dragging, save it once the slider is released
+					Slider::new(im_str!("Volume"))
+						.range(0.0..=1.0)
+						.display_format(im_str!("%.2f"))
+						.build(ui, &mut state.settings.volume);
+					state.settings_changed |= ui.is_item_deactivated_after_edit();
+					state.settings_changed |= ui.checkbox(im_str!("Mute"), &mut state.settings.muted);
+
+					state.settings_changed |= ui.checkbox(im_str!("Allow local spectators"), &mut state.settings.spectators);
+					let mut spectator_port = state.settings.spectator_port as i32;
+					if ui.input_int(im_str!("Spectator port"), &mut spectator_port).build() {
+						state.settings.spectator_port = spectator_port.max(1).min(u16::MAX as i32) as u16;
+						state.settings_changed = true;
+					}
+					if let Some(status) = &spectator_status {
+						ui.text(status);
+					}
+					if let Some(error) = &state.spectator_error {
+						ui.text_colored([1.0, 0.3, 0.3, 1.0], error);
+					}
+
+					let mut mods_folder = ImString::new(state.settings.mods_folder.clone());
+					if ui.input_text(im_str!("Mods folder"), &mut mods_folder).resize_buffer(true).build() {
+						state.settings.mods_folder = mods_folder.to_str().to_string();
+						state.settings_changed = true;
+					}
+					if ui.button(im_str!("Reload mods"), [0.0, 0.0]) {
+						state.reload_mods = true;
+					}
+
+					// Key bindings
+					if CollapsingHeader::new(im_str!("Key bindings")).build(ui) {
+						let layout_names: Vec<ImString> = KEYBOARD_LAYOUTS.iter().map(|l| ImString::new(l.name())).collect();
+						let layout_names: Vec<&ImString> = layout_names.iter().collect();
+						let mut layout_index = KEYBOARD_LAYOUTS.iter().position(|&l| l == state.settings.keyboard_layout).unwrap_or(0);
+						if ComboBox::new(im_str!("Layout")).build_simple_string(ui, &mut layout_index, &layout_names) {
+							state.settings.keyboard_layout = KEYBOARD_LAYOUTS[layout_index];
+							state.settings.bindings = Bindings::preset(state.settings.keyboard_layout);
+							state.settings_changed = true;
+						}
+
+						let conflicts = state.settings.bindings.conflicts();
+						for action in state.settings.bindings.actions() {
+							let label = match state.rebinding {
+								Some(rebinding) if rebinding == action => "Press a key...".to_string(),
+								_ => state.settings.bindings.get(action).map(|b| b.to_string()).unwrap_or_default(),
+							};
+							if ui.button(&im_str!("{}##{}", label, action), [120.0, 0.0]) {
+								state.rebinding = Some(action);
+							}
+							ui.same_line(0.0);
+							if conflicts.contains(&action) {
+								ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("{} (conflict)", action.label()));
+							} else {
+								ui.text(action.label());
+							}
+						}
+					}
+
 					settings_window.end(ui);
 				}
 			}
@@ -488,21 +2210,54 @@ impl EventHandler for MainState {
 	}
 
 	fn key_down_event(&mut self, _ctx: &mut Context, keycode: KeyCode, keymods: KeyMods, _repeat: bool) {
+		if self.menu_state.rebinding.is_none() && !self.menu_state.show_settings && !self.imgui_wrapper.wants_text_input() {
+			match self.menu_state.settings.bindings.action(KeyBinding::new(keycode, keymods.contains(KeyMods::CTRL))) {
+				Some(action @ Action::CursorLeft) |
+				Some(action @ Action::CursorRight) |
+				Some(action @ Action::CursorUp) |
+				Some(action @ Action::CursorDown) => {
+					let player = self.cursor_player();
+					self.boards[player].move_cursor(action);
+				},
+				_ => {},
+			}
+		}
 		self.imgui_wrapper.update_key_down(keycode, keymods);
 	}
 
 	fn key_up_event(&mut self, _ctx: &mut Context, keycode: KeyCode, keymods: KeyMods) {
-		if keymods.contains(KeyMods::CTRL) {
-			if keycode == KeyCode::Q {
-				self.menu_state.quit = true;
-			} else if keycode == KeyCode::R {
-				self.menu_state.restart_level = true;
-			} else if keycode == KeyCode::N && self.complete_pct() == 1.0 {
-				self.menu_state.skip_level = true;
+		if let Some(action) = self.menu_state.rebinding {
+			// Assign the next key which can be saved to the action being
+			// rebound, modifiers and unnamed keys are ignored
+			if keycode == KeyCode::Escape {
+				self.menu_state.rebinding = None;
+			} else if is_bindable_key(keycode) {
+				self.menu_state.settings.bindings.set(action, KeyBinding::new(keycode, keymods.contains(KeyMods::CTRL)));
+				self.menu_state.settings_changed = true;
+				self.menu_state.rebinding = None;
 			}
-		} else if keymods.is_empty() {
-			if let Some(tube_index) = self.keymap_key_to_index(keycode) {
-				self.handle_tube_activation(tube_index);
+		} else if self.imgui_wrapper.wants_text_input() {
+			// Typing into a text field
+		} else {
+			// Shift pours a single unit
+			let single_unit = keymods.contains(KeyMods::SHIFT);
+			let binding = KeyBinding::new(keycode, keymods.contains(KeyMods::CTRL));
+			match self.menu_state.settings.bindings.action(binding) {
+				Some(Action::Undo) => self.menu_state.undo = true,
+				Some(Action::Quit) => self.menu_state.quit = true,
+				Some(Action::RestartLevel) => self.menu_state.restart_level = true,
+				Some(Action::NextLevel) if self.level_completed() => self.menu_state.skip_level = true,
+				Some(Action::ToggleDebug) => self.menu_state.show_debug = !self.menu_state.show_debug,
+				Some(Action::CursorSelect) | Some(Action::CursorSelectAlt) => {
+					let player = self.cursor_player();
+					if let Some(tube_index) = self.boards[player].cursor {
+						self.handle_tube_activation(player, tube_index, single_unit);
+					}
+				},
+				Some(Action::Tube(_)) => if let Some((player, tube_index)) = self.keymap_key_to_index(binding) {
+					self.handle_tube_activation(player, tube_index, single_unit);
+				},
+				_ => {},
 			}
 		}
 		self.imgui_wrapper.update_key_up(keycode, keymods);
@@ -523,8 +2278,121 @@ impl EventHandler for MainState {
 	}
 }
 
+impl ExtraEventHandler for MainState {
+	fn touch_event(&mut self, _ctx: &mut Context, phase: TouchPhase, id: u64, x: f32, y: f32) {
+		self.last_touch = Some(Instant::now());
+
+		// Only track the first finger
+		if let Some(touch) = &self.touch {
+			if touch.id != id {
+				return;
+			}
+		}
+
+		// Forward touch to imgui as mouse input
+		self.imgui_wrapper.update_mouse_pos(x, y);
+		match phase {
+			TouchPhase::Started => self.imgui_wrapper.update_mouse_down(MouseButton::Left),
+			TouchPhase::Ended | TouchPhase::Cancelled => self.imgui_wrapper.update_mouse_up(MouseButton::Left),
+			TouchPhase::Moved => {},
+		}
+
+		let hit_tube = self.touch_hit_tube(x, y);
+		match phase {
+			TouchPhase::Started => {
+				if let Some((player, _)) = hit_tube {
+					self.boards[player].cursor = None;
+				}
+				self.touch = Some(TouchState {
+					id,
+					start_tube: hit_tube,
+					target_tube: hit_tube,
+				});
+			},
+			TouchPhase::Moved => if let Some(touch) = &mut self.touch {
+				touch.target_tube = hit_tube;
+			},
+			TouchPhase::Ended => if let Some(touch) = self.touch.take() {
+				match (touch.start_tube, hit_tube) {
+					// Tap to select and pour
+					(Some(start), Some(end)) if start == end => if self.editor.is_some() {
+						self.paint_tube(end.1, y);
+					} else {
+						self.handle_tube_activation(end.0, end.1, false);
+					},
+					// Drag to pour, within one player's board
+					(Some(start), Some(end)) if start.0 == end.0 => {
+						self.boards[start.0].selected_tube = None;
+						self.handle_tube_activation(start.0, start.1, false);
+						self.handle_tube_activation(end.0, end.1, false);
+					},
+					_ => {},
+				}
+			},
+			TouchPhase::Cancelled => self.touch = None,
+		}
+	}
+
+	fn gamepad_button_event(&mut self, _ctx: &mut Context, btn: Button, pressed: bool, _id: usize) {
+		if !pressed {
+			self.imgui_wrapper.update_gamepad_button(btn, false);
+			return;
+		}
+		if self.menu_state.show_settings {
+			self.imgui_wrapper.update_gamepad_button(btn, true);
+		}
+		self.handle_gamepad_button(btn);
+	}
+
+	fn gamepad_axis_changed_event(&mut self, _ctx: &mut Context, axis: Axis, value: f32, _id: usize) {
+		self.imgui_wrapper.update_gamepad_axis(axis, if self.menu_state.show_settings { value } else { 0.0 });
+		self.handle_gamepad_axis(axis, value);
+	}
+}
+
 fn main() -> GameResult {
+	let args: Vec<String> = std::env::args().collect();
+
+	// Play by commands on stdin without opening a window, with an
+	// optional level file and mods folder given after --mods
+	if args.get(1).map(|s| s.as_str()) == Some("--headless") {
+		let mut custom_level = None;
+		let mut mods_folder = None;
+		let mut rest = args[2..].iter();
+		while let Some(arg) = rest.next() {
+			match arg.as_str() {
+				"--mods" => match rest.next() {
+					Some(folder) => mods_folder = Some(std::path::PathBuf::from(folder)),
+					None => return Err(ggez::GameError::ConfigError("missing mods folder after --mods".to_string())),
+				},
+				path => custom_level = Some(Level::parse(&std::fs::read_to_string(path)?)?),
+			}
+		}
+		return crate::headless::run(custom_level, mods_folder.as_deref());
+	}
+
+	// Render a level solution or replay to images without opening a window
+	if args.get(1).map(|s| s.as_str()) == Some("--export") {
+		return match (args.get(2), args.get(3)) {
+			(Some(input), Some(output)) => crate::export::export(input, output),
+			_ => Err(ggez::GameError::ConfigError("usage: --export <level or replay file> <output.gif or output prefix>".to_string())),
+		};
+	}
+
+	// Checks the level generator against golden boards, which
+	// must stay the same within a generator version
+	if args.get(1).map(|s| s.as_str()) == Some("--check-generator") {
+		let path = args.get(2).map(|s| s.as_str()).unwrap_or("generator_golden.txt");
+		let count = generator::check_golden(&std::fs::read_to_string(path)?).map_err(ggez::GameError::ConfigError)?;
+		println!("{} generated boards match level generator version {}", count, GENERATOR_VERSION);
+		return Ok(());
+	}
+
+	// Gamepads are off by default on macOS
+	let modules = conf::ModuleConf::default();
+	let gamepad = modules.gamepad;
 	let cb = ContextBuilder::new("Color sorting game", "alexrsagen")
+		.modules(modules)
 		.window_setup(conf::WindowSetup::default()
 			.title("Color sorting game")
 			.srgb(true)
@@ -538,8 +2406,35 @@ fn main() -> GameResult {
 		);
 	let (ref mut ctx, event_loop) = &mut cb.build()?;
 
+	// Optionally load a level file given as the first argument, watch a
+	// replay file given after --replay, host or join a LAN race, or
+	// take control commands on stdin with --commands
+	let (custom_level, replay, net) = match args.get(1).map(|s| s.as_str()) {
+		Some("--replay") => match args.get(2) {
+			Some(path) => (None, Some(Replay::parse(&std::fs::read_to_string(path)?)?), None),
+			None => return Err(ggez::GameError::ConfigError("missing replay file after --replay".to_string())),
+		},
+		Some("--host") => {
+			let port = args.get(2).and_then(|p| p.parse().ok()).unwrap_or(DEFAULT_PORT);
+			(None, None, Some(NetSession::host(port)?))
+		},
+		Some("--commands") => (None, None, None),
+		Some("--join") => match args.get(2) {
+			Some(address) => (None, None, Some(NetSession::join(address)?)),
+			None => return Err(ggez::GameError::ConfigError("missing host address after --join".to_string())),
+		},
+		Some(path) => (Some(Level::parse(&std::fs::read_to_string(path)?)?), None, None),
+		None => (None, None, None),
+	};
+
 	let hidpi_factor = event_loop.get_primary_monitor().get_hidpi_factor() as f32;
-	let state = &mut MainState::new(ctx, hidpi_factor)?;
+	let state = &mut MainState::new(ctx, hidpi_factor, custom_level, replay)?;
+	if let Some(net) = net {
+		state.start_net(net);
+	}
+	if args.get(1).map(|s| s.as_str()) == Some("--commands") {
+		state.commands = Some(crate::command::read_stdin());
+	}
 
-	event::run(ctx, event_loop, state)
+	crate::event_loop::run(ctx, event_loop, state, gamepad)
 }
\ No newline at end of file
@@ -0,0 +1,439 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use crate::replay::Replay;
+use crate::rules::Rules;
+
+pub const DEFAULT_PORT: u16 = 7878;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Line based race protocol over TCP, the host is player 0. Levels are
+// sent as replay text without moves, with newlines replaced by '|':
+//
+//   host -> client   welcome <player>
+//   host -> client   level <number> rules|capacity 4|tube pink blue|tube
+//   host -> client   progress <player> <moves> <completed 0.0 .. 1.0>
+//   host -> client   left <player>
+//   client -> host   progress <moves> <completed 0.0 .. 1.0>
+
+pub enum NetEvent {
+	// Level sent by the host
+	Level { number: usize, replay: Replay },
+	// Connection to the host was lost
+	Disconnected,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Opponent {
+	pub player: usize,
+	pub moves: usize,
+	pub complete_pct: f32,
+	pub connected: bool,
+}
+
+// Non-blocking line based connection
+pub(crate) struct Peer {
+	player: usize,
+	stream: TcpStream,
+	incoming: Vec<u8>,
+	outgoing: Vec<u8>,
+	pub closed: bool,
+}
+
+impl Peer {
+	pub fn new(stream: TcpStream, player: usize) -> io::Result<Self> {
+		stream.set_nonblocking(true)?;
+		stream.set_nodelay(true)?;
+		Ok(Self {
+			player,
+			stream,
+			incoming: Vec::new(),
+			outgoing: Vec::new(),
+			closed: false,
+		})
+	}
+
+	pub fn send(&mut self, line: &str) {
+		self.outgoing.extend_from_slice(line.as_bytes());
+		self.outgoing.push(b'\n');
+	}
+
+	// Writes as much buffered output as possible without blocking
+	pub fn flush(&mut self) {
+		while !self.outgoing.is_empty() && !self.closed {
+			match self.stream.write(&self.outgoing) {
+				Ok(0) => self.closed = true,
+				Ok(n) => { self.outgoing.drain(..n); },
+				Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+				Err(e) if e.kind() == ErrorKind::Interrupted => {},
+				Err(_) => self.closed = true,
+			}
+		}
+	}
+
+	// Returns the complete lines received so far without blocking
+	pub fn read_lines(&mut self) -> Vec<String> {
+		let mut buf = [0u8; 4096];
+		while !self.closed {
+			match self.stream.read(&mut buf) {
+				Ok(0) => self.closed = true,
+				Ok(n) => self.incoming.extend_from_slice(&buf[..n]),
+				Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+				Err(e) if e.kind() == ErrorKind::Interrupted => {},
+				Err(_) => self.closed = true,
+			}
+		}
+		let mut lines = Vec::new();
+		while let Some(end) = self.incoming.iter().position(|&b| b == b'\n') {
+			let line: Vec<u8> = self.incoming.drain(..=end).collect();
+			lines.push(String::from_utf8_lossy(&line).trim().to_string());
+		}
+		lines
+	}
+}
+
+enum Role {
+	Host {
+		listener: TcpListener,
+		clients: Vec<Peer>,
+		next_player: usize,
+		// Last level message, sent to players joining mid level
+		level: Option<String>,
+	},
+	Client {
+		host: Option<Peer>,
+	},
+}
+
+pub struct NetSession {
+	role: Role,
+	pub player: usize,
+	pub opponents: Vec<Opponent>,
+	// Rules of the level sent by the host
+	pub rules: Option<Rules>,
+	// Last progress sent, as (moves, completed)
+	progress: Option<(usize, f32)>,
+}
+
+fn update_opponent(opponents: &mut Vec<Opponent>, player: usize, moves: usize, complete_pct: f32) {
+	match opponents.iter_mut().find(|o| o.player == player) {
+		Some(opponent) => {
+			opponent.moves = moves;
+			opponent.complete_pct = complete_pct;
+			opponent.connected = true;
+		},
+		None => opponents.push(Opponent { player, moves, complete_pct, connected: true }),
+	}
+}
+
+// Clears the race progress when a new level starts
+fn reset_progress(opponents: &mut [Opponent], progress: &mut Option<(usize, f32)>) {
+	for opponent in opponents {
+		opponent.moves = 0;
+		opponent.complete_pct = 0.0;
+	}
+	*progress = None;
+}
+
+fn parse_progress<'a, I: Iterator<Item = &'a str>>(mut words: I) -> Option<(usize, f32)> {
+	let moves = words.next()?.parse::<usize>().ok()?;
+	let complete_pct = words.next()?.parse::<f32>().ok()?;
+	// A NaN from the peer counts as no progress
+	let complete_pct = if complete_pct.is_nan() { 0.0 } else { complete_pct.clamp(0.0, 1.0) };
+	Some((moves, complete_pct))
+}
+
+impl NetSession {
+	pub fn host(port: u16) -> io::Result<Self> {
+		let listener = TcpListener::bind(("0.0.0.0", port))?;
+		listener.set_nonblocking(true)?;
+		Ok(Self {
+			role: Role::Host {
+				listener,
+				clients: Vec::new(),
+				next_player: 1,
+				level: None,
+			},
+			player: 0,
+			opponents: Vec::new(),
+			rules: None,
+			progress: None,
+		})
+	}
+
+	pub fn join(address: &str) -> io::Result<Self> {
+		let mut last_error = io::Error::new(ErrorKind::NotFound, format!("could not resolve {}", address));
+		for addr in address.to_socket_addrs()? {
+			match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+				Ok(stream) => return Ok(Self {
+					role: Role::Client { host: Some(Peer::new(stream, 0)?) },
+					player: 0,
+					opponents: Vec::new(),
+					rules: None,
+					progress: None,
+				}),
+				Err(e) => last_error = e,
+			}
+		}
+		Err(last_error)
+	}
+
+	pub fn is_host(&self) -> bool {
+		match self.role {
+			Role::Host { .. } => true,
+			Role::Client { .. } => false,
+		}
+	}
+
+	// Port the host listens on, 0 when joined to a host
+	pub fn port(&self) -> u16 {
+		match &self.role {
+			Role::Host { listener, .. } => listener.local_addr().map(|a| a.port()).unwrap_or(0),
+			Role::Client { .. } => 0,
+		}
+	}
+
+	pub fn status(&self) -> String {
+		match &self.role {
+			Role::Host { clients, .. } => format!("Hosting on port {}, {} player(s) joined", self.port(), clients.len()),
+			Role::Client { host: Some(_) } => format!("Connected as player {}", self.player + 1),
+			Role::Client { host: None } => "Disconnected from host".to_string(),
+		}
+	}
+
+	// Sends a new level to every client, restarting the race
+	pub fn send_level(&mut self, number: usize, replay: &Replay) {
+		if let Role::Host { clients, level, .. } = &mut self.role {
+			let line = format!("level {} {}", number, replay.to_string().trim_end().replace('\n', "|"));
+			for client in clients.iter_mut() {
+				client.send(&line);
+			}
+			*level = Some(line);
+		}
+		reset_progress(&mut self.opponents, &mut self.progress);
+	}
+
+	// Sends this player's progress if it changed
+	pub fn send_progress(&mut self, moves: usize, complete_pct: f32) {
+		if self.progress == Some((moves, complete_pct)) {
+			return;
+		}
+		self.progress = Some((moves, complete_pct));
+		match &mut self.role {
+			Role::Host { clients, .. } => {
+				let line = format!("progress {} {} {}", self.player, moves, complete_pct);
+				for client in clients.iter_mut() {
+					client.send(&line);
+				}
+			},
+			Role::Client { host: Some(host) } => host.send(&format!("progress {} {}", moves, complete_pct)),
+			Role::Client { host: None } => {},
+		}
+	}
+
+	// Accepts players, exchanges messages and drops closed connections,
+	// never blocks
+	pub fn poll(&mut self) -> Vec<NetEvent> {
+		let mut events = Vec::new();
+		match &mut self.role {
+			Role::Host { listener, clients, next_player, level } => {
+				while let Ok((stream, _)) = listener.accept() {
+					let mut peer = match Peer::new(stream, *next_player) {
+						Ok(peer) => peer,
+						Err(_) => continue,
+					};
+					*next_player += 1;
+
+					// Catch the new player up on the race
+					peer.send(&format!("welcome {}", peer.player));
+					if let Some(level) = level {
+						peer.send(level);
+					}
+					if let Some((moves, complete_pct)) = self.progress {
+						peer.send(&format!("progress {} {} {}", self.player, moves, complete_pct));
+					}
+					for opponent in self.opponents.iter().filter(|o| o.connected) {
+						peer.send(&format!("progress {} {} {}", opponent.player, opponent.moves, opponent.complete_pct));
+					}
+					update_opponent(&mut self.opponents, peer.player, 0, 0.0);
+					clients.push(peer);
+				}
+
+				// Relay progress between clients
+				let mut relayed = Vec::new();
+				for client in clients.iter_mut() {
+					for line in client.read_lines() {
+						let mut words = line.split_whitespace();
+						if words.next() != Some("progress") {
+							continue;
+						}
+						if let Some((moves, complete_pct)) = parse_progress(words) {
+							update_opponent(&mut self.opponents, client.player, moves, complete_pct);
+							relayed.push((client.player, format!("progress {} {} {}", client.player, moves, complete_pct)));
+						}
+					}
+					if client.closed {
+						if let Some(opponent) = self.opponents.iter_mut().find(|o| o.player == client.player) {
+							opponent.connected = false;
+						}
+						relayed.push((client.player, format!("left {}", client.player)));
+					}
+				}
+				clients.retain(|c| !c.closed);
+				for (player, line) in relayed {
+					for client in clients.iter_mut().filter(|c| c.player != player) {
+						client.send(&line);
+					}
+				}
+				for client in clients.iter_mut() {
+					client.flush();
+				}
+			},
+			Role::Client { host } => {
+				if let Some(peer) = host {
+					for line in peer.read_lines() {
+						let mut words = line.splitn(3, ' ');
+						match (words.next(), words.next()) {
+							(Some("welcome"), Some(player)) => if let Ok(player) = player.parse() {
+								self.player = player;
+							},
+							(Some("level"), Some(number)) => {
+								let text = words.next().unwrap_or("").replace('|', "\n");
+								if let (Ok(number), Ok(replay)) = (number.parse(), Replay::parse(&text)) {
+									self.rules = Some(replay.rules);
+									reset_progress(&mut self.opponents, &mut self.progress);
+									events.push(NetEvent::Level { number, replay });
+								}
+							},
+							(Some("progress"), Some(player)) => {
+								let progress = words.next().and_then(|rest| parse_progress(rest.split_whitespace()));
+								if let (Ok(player), Some((moves, complete_pct))) = (player.parse(), progress) {
+									if player != self.player {
+										update_opponent(&mut self.opponents, player, moves, complete_pct);
+									}
+								}
+							},
+							(Some("left"), Some(player)) => if let Ok(player) = player.parse::<usize>() {
+								if let Some(opponent) = self.opponents.iter_mut().find(|o| o.player == player) {
+									opponent.connected = false;
+								}
+							},
+							_ => {},
+						}
+					}
+					peer.flush();
+					if peer.closed {
+						*host = None;
+						for opponent in &mut self.opponents {
+							opponent.connected = false;
+						}
+						events.push(NetEvent::Disconnected);
+					}
+				}
+			},
+		}
+		events
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::thread;
+	use super::*;
+	use crate::level::Level;
+
+	// Polls every session until the condition holds, returning the events
+	// received by the last session
+	fn poll_until<F: Fn(&[NetSession]) -> bool>(sessions: &mut [NetSession], condition: F) -> Vec<NetEvent> {
+		let mut events = Vec::new();
+		let last = sessions.len() - 1;
+		for _ in 0..500 {
+			for (i, session) in sessions.iter_mut().enumerate() {
+				let polled = session.poll();
+				if i == last {
+					events.extend(polled);
+				}
+			}
+			if condition(sessions) {
+				return events;
+			}
+			thread::sleep(Duration::from_millis(5));
+		}
+		panic!("timed out waiting for the sessions");
+	}
+
+	fn join(host: &NetSession) -> NetSession {
+		NetSession::join(&format!("127.0.0.1:{}", host.port())).unwrap()
+	}
+
+	fn opponent(session: &NetSession, player: usize) -> Option<&Opponent> {
+		session.opponents.iter().find(|o| o.player == player)
+	}
+
+	fn replay() -> Replay {
+		let mut rules = Rules::new();
+		rules.partial_pour = true;
+		Replay::new(rules, Level::parse("capacity 4\ntube pink blue\ntube").unwrap())
+	}
+
+	#[test]
+	fn joining_player_receives_welcome_and_level() {
+		let mut host = NetSession::host(0).unwrap();
+		host.send_level(7, &replay());
+		let client = join(&host);
+		let mut sessions = vec![host, client];
+		let events = poll_until(&mut sessions, |s| s[1].rules.is_some());
+		assert_eq!(sessions[1].player, 1);
+		assert_eq!(sessions[1].rules, Some(replay().rules));
+		match events.as_slice() {
+			[NetEvent::Level { number: 7, replay: level }] => assert_eq!(level, &replay()),
+			_ => panic!("expected a single level event"),
+		}
+		assert_eq!(sessions[0].status(), format!("Hosting on port {}, 1 player(s) joined", sessions[0].port()));
+	}
+
+	#[test]
+	fn progress_is_relayed_and_reset_by_new_levels() {
+		let host = NetSession::host(0).unwrap();
+		let first = join(&host);
+		let second = join(&host);
+		let mut sessions = vec![host, first, second];
+		poll_until(&mut sessions, |s| s[1].player != s[2].player && s[1].player != 0 && s[2].player != 0);
+
+		sessions[0].send_progress(2, 0.25);
+		let mover = sessions[1].player;
+		sessions[1].send_progress(3, 0.5);
+		poll_until(&mut sessions, |s| {
+			opponent(&s[2], 0).map(|o| o.moves) == Some(2) && opponent(&s[2], mover).map(|o| o.moves) == Some(3)
+		});
+		assert_eq!(opponent(&sessions[0], mover), Some(&Opponent { player: mover, moves: 3, complete_pct: 0.5, connected: true }));
+		assert_eq!(opponent(&sessions[2], mover).unwrap().complete_pct, 0.5);
+
+		sessions[0].send_level(1, &replay());
+		assert_eq!(opponent(&sessions[0], mover).unwrap().moves, 0);
+		let events = poll_until(&mut sessions, |s| s[2].rules.is_some());
+		assert!(matches!(events.as_slice(), [NetEvent::Level { number: 1, .. }]));
+		assert!(sessions[2].opponents.iter().all(|o| o.moves == 0 && o.complete_pct == 0.0));
+	}
+
+	#[test]
+	fn disconnects_are_reported() {
+		let host = NetSession::host(0).unwrap();
+		let first = join(&host);
+		let second = join(&host);
+		let mut sessions = vec![host, first, second];
+		poll_until(&mut sessions, |s| s[1].player != 0 && s[2].player != 0 && s[0].opponents.len() == 2);
+
+		let leaving = sessions.remove(1);
+		let player = leaving.player;
+		drop(leaving);
+		poll_until(&mut sessions, |s| opponent(&s[1], player).map(|o| o.connected) == Some(false));
+		assert!(!opponent(&sessions[0], player).unwrap().connected);
+
+		sessions.remove(0);
+		let events = poll_until(&mut sessions, |s| s[0].status() == "Disconnected from host");
+		assert!(matches!(events.as_slice(), [NetEvent::Disconnected]));
+		assert!(sessions[0].opponents.iter().all(|o| !o.connected));
+	}
+}
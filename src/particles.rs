@@ -0,0 +1,84 @@
+use std::time::Duration;
+use ggez::{nalgebra, Context, GameResult};
+use ggez::graphics::{Color, DrawMode, DrawParam, Drawable, MeshBuilder, Rect};
+use nalgebra::Point2;
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+
+// Oldest particles are dropped beyond this
+const MAX_PARTICLES: usize = 600;
+const GRAVITY: f32 = 400.0;
+
+#[derive(Debug, Clone)]
+struct Particle {
+	pos: Point2<f32>,
+	vel: (f32, f32),
+	color: Color,
+	size: f32,
+	// Remaining and initial lifetime in seconds
+	life: f32,
+	max_life: f32,
+}
+
+pub struct Particles {
+	particles: Vec<Particle>,
+	rng: SmallRng,
+}
+
+impl Particles {
+	pub fn new() -> Self {
+		Self {
+			particles: Vec::new(),
+			rng: SmallRng::seed_from_u64(0),
+		}
+	}
+
+	// Sprays particles upwards out of the given area
+	pub fn burst(&mut self, area: Rect, color: Color, count: usize) {
+		let scale = area.w / 50.0;
+		for _ in 0..count {
+			let life = self.rng.gen_range(0.6..1.2);
+			self.particles.push(Particle {
+				pos: Point2::new(area.x + self.rng.gen_range(0.0..area.w), area.y + self.rng.gen_range(0.0..area.h.max(1.0))),
+				vel: (self.rng.gen_range(-120.0..120.0) * scale, self.rng.gen_range(-420.0..-160.0) * scale),
+				color,
+				size: self.rng.gen_range(2.0..5.0) * scale,
+				life,
+				max_life: life,
+			});
+		}
+		if self.particles.len() > MAX_PARTICLES {
+			let excess = self.particles.len() - MAX_PARTICLES;
+			self.particles.drain(..excess);
+		}
+	}
+
+	pub fn update(&mut self, delta: Duration) {
+		let dt = delta.as_secs_f32();
+		for particle in &mut self.particles {
+			particle.vel.1 += GRAVITY * dt;
+			particle.pos.x += particle.vel.0 * dt;
+			particle.pos.y += particle.vel.1 * dt;
+			particle.life -= dt;
+		}
+		self.particles.retain(|p| p.life > 0.0);
+	}
+
+	pub fn draw(&self, ctx: &mut Context) -> GameResult {
+		if self.particles.is_empty() {
+			return Ok(());
+		}
+		let mut mb = MeshBuilder::new();
+		for particle in &self.particles {
+			let mut color = particle.color;
+			color.a *= particle.life / particle.max_life;
+			mb.rectangle(DrawMode::fill(), Rect::new(
+				particle.pos.x - particle.size / 2.0,
+				particle.pos.y - particle.size / 2.0,
+				particle.size,
+				particle.size
+			), color);
+		}
+		mb.build(ctx)?.draw(ctx, DrawParam::default())
+	}
+}
@@ -0,0 +1,421 @@
+use std::io::Read;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use ggez::{filesystem, Context, GameError, GameResult};
+use ggez::graphics::Font;
+use serde::Deserialize;
+use crate::colors::LIQUID_COLOR_NAMES;
+use crate::editor::MAX_TUBE_CAPACITY;
+use crate::generator::{self, MAX_SPARE_TUBES};
+use crate::level::Level;
+use crate::rules::Rules;
+use crate::settings::Settings;
+use crate::solver;
+use crate::theme::Theme;
+
+const BUILTIN_PROGRESSION: &str = include_str!("../progression.toml");
+
+// Replaces the built-in progression when present in the ggez filesystem
+const PROGRESSION_PATH: &str = "/progression.toml";
+
+// Size of levels when the progression is turned off
+const FIXED_SPARE_TUBES: usize = 2;
+
+// How long starting a level waits for its grading before
+// the grading is left to finish in the background
+const GRADE_WAIT: Duration = Duration::from_millis(50);
+// Graded levels kept, enough for the current and next level
+const GRADE_CACHE_SIZE: usize = 4;
+
+fn default_capacity() -> f32 {
+	4.0
+}
+
+fn default_candidates() -> usize {
+	1
+}
+
+// Level sizes from a level number on, see progression.toml
+#[derive(Debug, Clone, Deserialize)]
+pub struct Stage {
+	pub from: usize,
+	pub colors: usize,
+	pub spare_tubes: usize,
+	#[serde(default = "default_capacity")]
+	pub capacity: f32,
+	#[serde(default)]
+	pub special_tubes: bool,
+	#[serde(default = "default_candidates")]
+	pub candidates: usize,
+	// Shortest solution lengths aimed for at the start and end of the stage
+	pub moves: Option<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Progression {
+	pub stages: Vec<Stage>,
+}
+
+// How a level is generated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LevelParams {
+	pub colors: usize,
+	pub capacity: f32,
+	pub spare_tubes: usize,
+	pub special_tubes: bool,
+	pub candidates: usize,
+	pub target_moves: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct GeneratedLevel {
+	pub level: Level,
+	pub seed: u64,
+	// Length of the shortest solution, if the level was graded and solved
+	pub moves: Option<usize>,
+}
+
+impl Progression {
+	pub fn parse(s: &str) -> GameResult<Self> {
+		let mut progression: Self = toml::from_str(s).map_err(|e| GameError::ResourceLoadError(e.to_string()))?;
+		if progression.stages.is_empty() {
+			return Err(GameError::ResourceLoadError("progression has no stages".to_string()));
+		}
+		progression.stages.sort_by_key(|s| s.from);
+		for stage in &progression.stages {
+			let error = |message: &str| GameError::ResourceLoadError(format!("progression stage from level {}: {}", stage.from, message));
+			if stage.colors < 1 || stage.colors > LIQUID_COLOR_NAMES.len() {
+				return Err(error(&format!("colors must be from 1 to {}", LIQUID_COLOR_NAMES.len())));
+			}
+			if stage.capacity < 1.0 || stage.capacity > MAX_TUBE_CAPACITY || stage.capacity.fract() != 0.0 {
+				return Err(error(&format!("capacity must be a whole number from 1 to {}", MAX_TUBE_CAPACITY)));
+			}
+			if stage.spare_tubes > MAX_SPARE_TUBES {
+				return Err(error(&format!("spare_tubes must be at most {}", MAX_SPARE_TUBES)));
+			}
+			match (stage.candidates, stage.moves) {
+				(0, _) => return Err(error("candidates must be at least 1")),
+				(1, Some(_)) => return Err(error("moves needs more than one candidate to pick from")),
+				(c, None) if c > 1 => return Err(error("candidates need moves to be graded by")),
+				_ => {},
+			}
+		}
+		Ok(progression)
+	}
+
+	pub fn builtin() -> Self {
+		Self::parse(BUILTIN_PROGRESSION).expect("invalid builtin progression")
+	}
+
+	// Falls back to the built-in progression if the file is missing or invalid
+	pub fn load(ctx: &mut Context) -> Self {
+		let mut s = String::new();
+		match filesystem::open(ctx, PROGRESSION_PATH).and_then(|mut file| Ok(file.read_to_string(&mut s)?)) {
+			Ok(_) => Self::parse(&s).unwrap_or_else(|_| Self::builtin()),
+			Err(_) => Self::builtin(),
+		}
+	}
+
+	// Stage of the level and how far into the stage it is, from 0.0 to 1.0,
+	// the last stage goes on forever at its end
+	fn stage(&self, level: usize) -> (&Stage, f32) {
+		let index = self.stages.iter().rposition(|s| s.from <= level).unwrap_or(0);
+		let stage = &self.stages[index];
+		let progress = match self.stages.get(index + 1) {
+			Some(next) if next.from > stage.from + 1 => (level.saturating_sub(stage.from) as f32 / (next.from - stage.from - 1) as f32).min(1.0),
+			_ => 1.0,
+		};
+		(stage, progress)
+	}
+
+	// Parameters of the level, or the fixed size from the
+	// settings if the progression is turned off
+	pub fn params(&self, level: usize, settings: &Settings) -> LevelParams {
+		if !settings.progression {
+			return LevelParams {
+				colors: settings.color_count,
+				capacity: default_capacity(),
+				spare_tubes: FIXED_SPARE_TUBES,
+				special_tubes: settings.special_tubes,
+				candidates: 1,
+				target_moves: None,
+			};
+		}
+		let (stage, progress) = self.stage(level);
+		LevelParams {
+			colors: stage.colors,
+			capacity: stage.capacity,
+			spare_tubes: stage.spare_tubes,
+			special_tubes: stage.special_tubes || settings.special_tubes,
+			candidates: stage.candidates,
+			target_moves: stage.moves.map(|(start, end)| (start as f32 + (end as f32 - start as f32) * progress).round() as usize),
+		}
+	}
+}
+
+// Generates the candidates of the level and picks the one with the
+// shortest solution closest to the target, unsolved candidates are
+// only picked when none could be solved
+pub fn generate(level: usize, params: &LevelParams, rules: Rules) -> GeneratedLevel {
+	let generate_seed = |seed| generator::generate(seed, params.colors, params.capacity, params.spare_tubes, params.special_tubes);
+	let target = match params.target_moves {
+		Some(target) if params.candidates > 1 => target,
+		_ => {
+			let seed = generator::level_seed(level);
+			return GeneratedLevel { level: generate_seed(seed), seed, moves: None };
+		},
+	};
+
+	let (font, theme) = (Font::default(), Rc::new(Theme::default()));
+	let mut best: Option<GeneratedLevel> = None;
+	for candidate in 0..params.candidates {
+		let seed = generator::candidate_seed(level, candidate);
+		let generated = generate_seed(seed);
+		let moves = solver::solve(&generated.to_tubes(font, &theme), rules).ok().map(|s| s.moves.len());
+		let distance = |moves: Option<usize>| moves.map(|m| (m as isize - target as isize).abs()).unwrap_or(isize::MAX);
+		let better = match &best {
+			Some(best) => distance(moves) < distance(best.moves),
+			None => true,
+		};
+		if better {
+			best = Some(GeneratedLevel { level: generated, seed, moves });
+		}
+		if moves == Some(target) {
+			break;
+		}
+	}
+	best.expect("no level candidates")
+}
+
+// Level and parameters a graded level was generated with
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct GradeKey {
+	level: usize,
+	params: LevelParams,
+	rules: Rules,
+}
+
+fn is_graded(params: &LevelParams) -> bool {
+	params.candidates > 1 && params.target_moves.is_some()
+}
+
+// Grades levels on another thread, as solving the candidates of large
+// boards takes seconds, and keeps the last levels graded
+pub struct Grader {
+	// Waits for every grading, when there is no frame to keep drawing
+	blocking: bool,
+	cache: Vec<(GradeKey, GeneratedLevel)>,
+	pending: Option<(GradeKey, Receiver<GeneratedLevel>)>,
+}
+
+impl Grader {
+	pub fn new() -> Self {
+		Self {
+			blocking: false,
+			cache: Vec::new(),
+			pending: None,
+		}
+	}
+
+	pub fn blocking() -> Self {
+		Self {
+			blocking: true,
+			..Self::new()
+		}
+	}
+
+	fn receive(&mut self, wait: Duration) {
+		let received = match &self.pending {
+			Some((key, receiver)) => match receiver.recv_timeout(wait) {
+				Ok(generated) => Some((*key, generated)),
+				Err(RecvTimeoutError::Timeout) => return,
+				Err(RecvTimeoutError::Disconnected) => None,
+			},
+			None => return,
+		};
+		self.pending = None;
+		if let Some((key, generated)) = received {
+			self.store(key, generated);
+		}
+	}
+
+	fn store(&mut self, key: GradeKey, generated: GeneratedLevel) {
+		if self.cache.len() >= GRADE_CACHE_SIZE {
+			self.cache.remove(0);
+		}
+		self.cache.push((key, generated));
+	}
+
+	fn cached(&self, key: &GradeKey) -> Option<GeneratedLevel> {
+		self.cache.iter().find(|(k, _)| k == key).map(|(_, generated)| generated.clone())
+	}
+
+	// Replaces any other grading in progress, which finishes unused
+	fn start(&mut self, key: GradeKey) {
+		if self.cached(&key).is_some() || self.pending.as_ref().map(|(k, _)| *k) == Some(key) {
+			return;
+		}
+		let (sender, receiver) = mpsc::channel();
+		thread::spawn(move || {
+			let _ = sender.send(generate(key.level, &key.params, key.rules));
+		});
+		self.pending = Some((key, receiver));
+	}
+
+	// The generated level, or None while it is graded in the background
+	pub fn generate(&mut self, level: usize, params: &LevelParams, rules: Rules) -> Option<GeneratedLevel> {
+		if !is_graded(params) {
+			return Some(generate(level, params, rules));
+		}
+		let key = GradeKey { level, params: *params, rules };
+		self.receive(Duration::from_secs(0));
+		if self.cached(&key).is_none() {
+			if self.blocking {
+				self.store(key, generate(level, params, rules));
+			} else {
+				self.start(key);
+				self.receive(GRADE_WAIT);
+			}
+		}
+		self.cached(&key)
+	}
+
+	// Grades a level expected to be played next, unless busy
+	pub fn prefetch(&mut self, level: usize, params: &LevelParams, rules: Rules) {
+		if is_graded(params) && !self.blocking && self.pending.is_none() {
+			self.start(GradeKey { level, params: *params, rules });
+		}
+	}
+
+	// Whether a grading finished since the last call
+	pub fn poll(&mut self) -> bool {
+		let was_pending = self.pending.is_some();
+		self.receive(Duration::from_secs(0));
+		was_pending && self.pending.is_none()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const PROGRESSION: &str = "
+[[stages]]
+from = 11
+colors = 5
+spare_tubes = 1
+capacity = 3
+candidates = 3
+moves = [10, 20]
+
+[[stages]]
+from = 1
+colors = 3
+spare_tubes = 2
+";
+
+	fn parse_error(stage: &str) -> String {
+		match Progression::parse(&format!("[[stages]]\nfrom = 1\n{}", stage)) {
+			Ok(_) => panic!("progression should be invalid"),
+			Err(e) => e.to_string(),
+		}
+	}
+
+	#[test]
+	fn stages_apply_from_their_first_level() {
+		let progression = Progression::parse(PROGRESSION).unwrap();
+		assert_eq!(progression.stages.iter().map(|s| s.from).collect::<Vec<_>>(), vec![1, 11]);
+		let (stage, progress) = progression.stage(1);
+		assert_eq!((stage.from, progress), (1, 0.0));
+		let (stage, progress) = progression.stage(10);
+		assert_eq!((stage.from, progress), (1, 1.0));
+		let (stage, progress) = progression.stage(500);
+		assert_eq!((stage.from, progress), (11, 1.0));
+	}
+
+	#[test]
+	fn params_follow_the_progression() {
+		let progression = Progression::parse(PROGRESSION).unwrap();
+		let mut settings = Settings::new();
+		assert_eq!(progression.params(4, &settings), LevelParams {
+			colors: 3,
+			capacity: 4.0,
+			spare_tubes: 2,
+			special_tubes: false,
+			candidates: 1,
+			target_moves: None,
+		});
+		let targets: Vec<Option<usize>> = [11, 12, 100].iter().map(|&l| progression.params(l, &settings).target_moves).collect();
+		assert_eq!(targets, vec![Some(20), Some(20), Some(20)]);
+
+		settings.special_tubes = true;
+		let params = progression.params(11, &settings);
+		assert_eq!((params.colors, params.capacity, params.spare_tubes, params.special_tubes), (5, 3.0, 1, true));
+
+		settings.progression = false;
+		settings.color_count = 7;
+		assert_eq!(progression.params(11, &settings), LevelParams {
+			colors: 7,
+			capacity: 4.0,
+			spare_tubes: FIXED_SPARE_TUBES,
+			special_tubes: true,
+			candidates: 1,
+			target_moves: None,
+		});
+	}
+
+	#[test]
+	fn targets_rise_through_a_stage() {
+		let progression = Progression::parse("[[stages]]\nfrom = 1\ncolors = 3\nspare_tubes = 2\ncandidates = 2\nmoves = [4, 8]\n[[stages]]\nfrom = 6\ncolors = 4\nspare_tubes = 2").unwrap();
+		let settings = Settings::new();
+		let targets: Vec<Option<usize>> = (1..=6).map(|l| progression.params(l, &settings).target_moves).collect();
+		assert_eq!(targets, vec![Some(4), Some(5), Some(6), Some(7), Some(8), None]);
+	}
+
+	#[test]
+	fn rejects_invalid_stages() {
+		assert!(Progression::parse("stages = []").is_err());
+		assert!(parse_error("colors = 0\nspare_tubes = 2").contains("colors must be from 1 to 12"));
+		assert!(parse_error("colors = 13\nspare_tubes = 2").contains("colors must be from 1 to 12"));
+		assert!(parse_error("colors = 3\nspare_tubes = 2\ncapacity = 2.5").contains("capacity must be a whole number"));
+		assert!(parse_error("colors = 3\nspare_tubes = 2\ncapacity = 9").contains("capacity must be a whole number from 1 to 8"));
+		assert!(parse_error("colors = 3\nspare_tubes = 9").contains("spare_tubes must be at most 8"));
+		assert!(parse_error("colors = 3\nspare_tubes = 2\ncandidates = 0").contains("candidates must be at least 1"));
+		assert!(parse_error("colors = 3\nspare_tubes = 2\nmoves = [4, 8]").contains("moves needs more than one candidate"));
+		assert!(parse_error("colors = 3\nspare_tubes = 2\ncandidates = 4").contains("candidates need moves"));
+	}
+
+	#[test]
+	fn builtin_progression_grades_every_stage() {
+		let progression = Progression::builtin();
+		assert_eq!(progression.stages[0].from, 1);
+		for stage in &progression.stages {
+			assert!(stage.candidates > 1 && stage.moves.is_some(), "stage from level {} is not graded", stage.from);
+		}
+	}
+
+	#[test]
+	fn grader_picks_the_candidate_closest_to_the_target() {
+		let params = LevelParams { colors: 3, capacity: 4.0, spare_tubes: 2, special_tubes: false, candidates: 4, target_moves: Some(6) };
+		let expected = generate(7, &params, Rules::new());
+		assert!(expected.moves.is_some());
+
+		let graded = Grader::blocking().generate(7, &params, Rules::new()).unwrap();
+		assert_eq!((graded.seed, graded.moves), (expected.seed, expected.moves));
+
+		let mut grader = Grader::new();
+		grader.prefetch(7, &params, Rules::new());
+		let mut graded = None;
+		for _ in 0..1000 {
+			graded = grader.generate(7, &params, Rules::new());
+			if graded.is_some() {
+				break;
+			}
+		}
+		let graded = graded.expect("grading did not finish");
+		assert_eq!((graded.seed, graded.moves), (expected.seed, expected.moves));
+		assert_eq!(graded.level, expected.level);
+	}
+}
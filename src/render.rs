@@ -0,0 +1,187 @@
+use ggez::nalgebra;
+use ggez::graphics::Color;
+use nalgebra::Point2;
+use crate::color_tube::{ColorTube, Shape};
+
+// Samples per pixel along each axis, for anti-aliasing
+const SUPERSAMPLE: usize = 3;
+
+// RGBA image drawn on the CPU, so boards can be rendered without a window or GPU
+pub struct Canvas {
+	pub width: u32,
+	pub height: u32,
+	// Straight alpha RGBA, 4 bytes per pixel
+	pub pixels: Vec<u8>,
+}
+
+fn point_in_polygon(x: f32, y: f32, points: &[Point2<f32>]) -> bool {
+	let mut inside = false;
+	let mut j = points.len() - 1;
+	for i in 0..points.len() {
+		let (a, b) = (points[i], points[j]);
+		if (a.y > y) != (b.y > y) && x < (b.x - a.x) * (y - a.y) / (b.y - a.y) + a.x {
+			inside = !inside;
+		}
+		j = i;
+	}
+	inside
+}
+
+fn distance_to_segment(x: f32, y: f32, a: Point2<f32>, b: Point2<f32>) -> f32 {
+	let (dx, dy) = (b.x - a.x, b.y - a.y);
+	let len_sq = dx * dx + dy * dy;
+	let t = if len_sq > 0.0 {
+		(((x - a.x) * dx + (y - a.y) * dy) / len_sq).clamp(0.0, 1.0)
+	} else {
+		0.0
+	};
+	let (px, py) = (a.x + t * dx, a.y + t * dy);
+	((x - px).powi(2) + (y - py).powi(2)).sqrt()
+}
+
+impl Canvas {
+	pub fn new(width: u32, height: u32, background: Color) -> Self {
+		let (r, g, b, a) = background.to_rgba();
+		let pixels = [r, g, b, a].iter().cloned().cycle().take((width * height * 4) as usize).collect();
+		Self { width, height, pixels }
+	}
+
+	// Alpha blends the color over a pixel, coverage is 0.0 .. 1.0
+	fn blend(&mut self, x: usize, y: usize, color: Color, coverage: f32) {
+		let alpha = color.a * coverage;
+		if alpha <= 0.0 {
+			return;
+		}
+		let i = (y * self.width as usize + x) * 4;
+		let dst_a = self.pixels[i + 3] as f32 / 255.0;
+		let out_a = alpha + dst_a * (1.0 - alpha);
+		let (src_r, src_g, src_b, _) = color.to_rgba();
+		for (c, &src) in [src_r, src_g, src_b].iter().enumerate() {
+			let dst = self.pixels[i + c] as f32;
+			let out = if out_a > 0.0 {
+				(src as f32 * alpha + dst * dst_a * (1.0 - alpha)) / out_a
+			} else {
+				0.0
+			};
+			self.pixels[i + c] = out.round() as u8;
+		}
+		self.pixels[i + 3] = (out_a * 255.0).round() as u8;
+	}
+
+	// Calls inside for every sample point in the bounding box of points,
+	// grown by margin, and blends in the covered fraction of each pixel
+	fn rasterize<F: Fn(f32, f32) -> bool>(&mut self, points: &[Point2<f32>], margin: f32, color: Color, inside: F) {
+		if points.is_empty() {
+			return;
+		}
+		let min_x = points.iter().map(|p| p.x).fold(f32::INFINITY, f32::min) - margin;
+		let max_x = points.iter().map(|p| p.x).fold(f32::NEG_INFINITY, f32::max) + margin;
+		let min_y = points.iter().map(|p| p.y).fold(f32::INFINITY, f32::min) - margin;
+		let max_y = points.iter().map(|p| p.y).fold(f32::NEG_INFINITY, f32::max) + margin;
+		let x0 = min_x.floor().max(0.0) as usize;
+		let y0 = min_y.floor().max(0.0) as usize;
+		let x1 = (max_x.ceil().max(0.0) as usize).min(self.width as usize);
+		let y1 = (max_y.ceil().max(0.0) as usize).min(self.height as usize);
+		let samples = (SUPERSAMPLE * SUPERSAMPLE) as f32;
+		for y in y0..y1 {
+			for x in x0..x1 {
+				let mut covered = 0;
+				for sy in 0..SUPERSAMPLE {
+					for sx in 0..SUPERSAMPLE {
+						let px = x as f32 + (sx as f32 + 0.5) / SUPERSAMPLE as f32;
+						let py = y as f32 + (sy as f32 + 0.5) / SUPERSAMPLE as f32;
+						if inside(px, py) {
+							covered += 1;
+						}
+					}
+				}
+				if covered > 0 {
+					self.blend(x, y, color, covered as f32 / samples);
+				}
+			}
+		}
+	}
+
+	pub fn fill_polygon(&mut self, points: &[Point2<f32>], color: Color) {
+		if points.len() < 3 {
+			return;
+		}
+		self.rasterize(points, 0.0, color, |x, y| point_in_polygon(x, y, points));
+	}
+
+	pub fn stroke_polyline(&mut self, points: &[Point2<f32>], width: f32, closed: bool, color: Color) {
+		let mut segments: Vec<(Point2<f32>, Point2<f32>)> = points.windows(2).map(|w| (w[0], w[1])).collect();
+		if closed && points.len() > 2 {
+			segments.push((points[points.len() - 1], points[0]));
+		}
+		let half_width = width / 2.0;
+		self.rasterize(points, half_width, color, |x, y| {
+			segments.iter().any(|&(a, b)| distance_to_segment(x, y, a, b) <= half_width)
+		});
+	}
+
+	pub fn draw_shape(&mut self, shape: &Shape) {
+		match shape {
+			Shape::Fill(points, color) => self.fill_polygon(points, *color),
+			Shape::Stroke(points, width, color) => self.stroke_polyline(points, *width, true, *color),
+			Shape::Line(points, width, color) => self.stroke_polyline(points, *width, false, *color),
+		}
+	}
+
+	// Draws the tube geometry, text labels are not rendered
+	pub fn draw_tube(&mut self, tube: &ColorTube) {
+		for shape in tube.shapes() {
+			self.draw_shape(&shape);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::rc::Rc;
+	use ggez::graphics::{Font, Rect};
+	use super::*;
+	use crate::color_tube::ColorTubeContent;
+	use crate::theme::Theme;
+
+	fn pixel(canvas: &Canvas, x: u32, y: u32) -> [u8; 4] {
+		let i = ((y * canvas.width + x) * 4) as usize;
+		[canvas.pixels[i], canvas.pixels[i + 1], canvas.pixels[i + 2], canvas.pixels[i + 3]]
+	}
+
+	fn rgba(color: Color) -> [u8; 4] {
+		let (r, g, b, a) = color.to_rgba();
+		[r, g, b, a]
+	}
+
+	#[test]
+	fn fills_inside_polygon_only() {
+		let mut canvas = Canvas::new(10, 10, Color::new(0.0, 0.0, 0.0, 1.0));
+		let square = [Point2::new(2.0, 2.0), Point2::new(8.0, 2.0), Point2::new(8.0, 8.0), Point2::new(2.0, 8.0)];
+		canvas.fill_polygon(&square, Color::new(1.0, 0.0, 0.0, 1.0));
+		assert_eq!(pixel(&canvas, 5, 5), [255, 0, 0, 255]);
+		assert_eq!(pixel(&canvas, 0, 0), [0, 0, 0, 255]);
+		assert_eq!(pixel(&canvas, 9, 5), [0, 0, 0, 255]);
+	}
+
+	#[test]
+	fn blends_partial_coverage() {
+		let mut canvas = Canvas::new(4, 4, Color::new(0.0, 0.0, 0.0, 1.0));
+		let half = [Point2::new(0.0, 0.0), Point2::new(4.0, 0.0), Point2::new(4.0, 1.5), Point2::new(0.0, 1.5)];
+		canvas.fill_polygon(&half, Color::new(1.0, 1.0, 1.0, 1.0));
+		let [r, _, _, a] = pixel(&canvas, 1, 1);
+		assert!(r > 0 && r < 255, "edge pixel is {}", r);
+		assert_eq!(a, 255);
+	}
+
+	#[test]
+	fn draws_tube_liquid_in_theme_color() {
+		let theme = Rc::new(Theme::default());
+		let mut tube = ColorTube::new(4.0, vec![ColorTubeContent::new(0, 4.0)], Font::default(), theme.clone());
+		tube.dimensions = Rect::new(20.0, 20.0, 40.0, 160.0);
+		let mut canvas = Canvas::new(80, 200, theme.background);
+		canvas.draw_tube(&tube);
+		assert_eq!(pixel(&canvas, 40, 100), rgba(theme.liquid(0)));
+		assert_eq!(pixel(&canvas, 2, 100), rgba(theme.background));
+	}
+}
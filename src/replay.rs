@@ -0,0 +1,209 @@
+use std::fmt;
+use std::time::Duration;
+use ggez::{GameError, GameResult};
+use crate::level::Level;
+use crate::rules::Rules;
+
+// Replay file format, a level file with the rules in effect and the moves made.
+// Times are seconds since the start of the level, tubes are numbered from 1:
+//
+//   rules partial_pour lock_completed_tubes
+//   capacity 4
+//   tube pink blue
+//   tube
+//   pour 1.250 1 2
+//   pour 2.500 2 1 single
+//   undo 3.000
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplayMove {
+	Pour { from: usize, to: usize, single_unit: bool },
+	Undo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplayEvent {
+	pub time: f32,
+	pub action: ReplayMove,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replay {
+	pub rules: Rules,
+	pub level: Level,
+	pub events: Vec<ReplayEvent>,
+}
+
+fn parse_tube(s: Option<&str>, line_no: usize) -> GameResult<usize> {
+	s.and_then(|s| s.parse::<usize>().ok())
+		.filter(|&n| n >= 1)
+		.map(|n| n - 1)
+		.ok_or_else(|| GameError::ResourceLoadError(format!("line {}: invalid tube number", line_no)))
+}
+
+fn parse_time(s: Option<&str>, line_no: usize) -> GameResult<f32> {
+	s.and_then(|s| s.parse::<f32>().ok())
+		.filter(|&t| t >= 0.0)
+		.ok_or_else(|| GameError::ResourceLoadError(format!("line {}: invalid time", line_no)))
+}
+
+impl Replay {
+	pub fn new(rules: Rules, level: Level) -> Self {
+		Self {
+			rules,
+			level,
+			events: Vec::new(),
+		}
+	}
+
+	pub fn record(&mut self, time: f32, action: ReplayMove) {
+		self.events.push(ReplayEvent { time, action });
+	}
+
+	pub fn duration(&self) -> f32 {
+		self.events.last().map(|e| e.time).unwrap_or(0.0)
+	}
+
+	pub fn parse(s: &str) -> GameResult<Self> {
+		let mut rules = Rules::new();
+		let mut events = Vec::new();
+		// Level lines are passed on, others are blanked to keep line numbers
+		let mut level_lines = Vec::new();
+
+		for (i, line) in s.lines().enumerate() {
+			let line_no = i + 1;
+			let content = line.split('#').next().unwrap_or("").trim();
+			let mut words = content.split_whitespace();
+			match words.next() {
+				Some("rules") => {
+					for word in words {
+						match word {
+							"partial_pour" => rules.partial_pour = true,
+							"lock_completed_tubes" => rules.lock_completed_tubes = true,
+							_ => return Err(GameError::ResourceLoadError(format!("line {}: unknown rule {:?}", line_no, word))),
+						}
+					}
+					level_lines.push("");
+				},
+				Some("pour") => {
+					let time = parse_time(words.next(), line_no)?;
+					let from = parse_tube(words.next(), line_no)?;
+					let to = parse_tube(words.next(), line_no)?;
+					let single_unit = match words.next() {
+						Some("single") => true,
+						None => false,
+						Some(word) => return Err(GameError::ResourceLoadError(format!("line {}: unknown pour attribute {:?}", line_no, word))),
+					};
+					events.push(ReplayEvent { time, action: ReplayMove::Pour { from, to, single_unit } });
+					level_lines.push("");
+				},
+				Some("undo") => {
+					let time = parse_time(words.next(), line_no)?;
+					events.push(ReplayEvent { time, action: ReplayMove::Undo });
+					level_lines.push("");
+				},
+				_ => level_lines.push(line),
+			}
+		}
+
+		let level = Level::parse(&level_lines.join("\n"))?;
+		for event in &events {
+			if let ReplayMove::Pour { from, to, .. } = event.action {
+				if from >= level.tubes.len() || to >= level.tubes.len() {
+					return Err(GameError::ResourceLoadError(format!("pour at {:.3} refers to a missing tube", event.time)));
+				}
+			}
+		}
+		events.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(std::cmp::Ordering::Equal));
+
+		Ok(Self { rules, level, events })
+	}
+}
+
+impl fmt::Display for Replay {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "rules")?;
+		if self.rules.partial_pour {
+			write!(f, " partial_pour")?;
+		}
+		if self.rules.lock_completed_tubes {
+			write!(f, " lock_completed_tubes")?;
+		}
+		writeln!(f)?;
+		write!(f, "{}", self.level)?;
+		for event in &self.events {
+			match event.action {
+				ReplayMove::Pour { from, to, single_unit } => {
+					write!(f, "pour {:.3} {} {}", event.time, from + 1, to + 1)?;
+					if single_unit {
+						write!(f, " single")?;
+					}
+					writeln!(f)?;
+				},
+				ReplayMove::Undo => writeln!(f, "undo {:.3}", event.time)?,
+			}
+		}
+		Ok(())
+	}
+}
+
+pub struct ReplayViewer {
+	pub replay: Replay,
+	pub paused: bool,
+	time: f32,
+	next_event: usize,
+}
+
+impl ReplayViewer {
+	pub fn new(replay: Replay) -> Self {
+		Self {
+			replay,
+			paused: false,
+			time: 0.0,
+			next_event: 0,
+		}
+	}
+
+	pub fn restart(&mut self) {
+		self.time = 0.0;
+		self.next_event = 0;
+	}
+
+	pub fn time(&self) -> f32 {
+		self.time
+	}
+
+	// Number of moves played so far
+	pub fn position(&self) -> usize {
+		self.next_event
+	}
+
+	pub fn is_finished(&self) -> bool {
+		self.next_event >= self.replay.events.len()
+	}
+
+	// Advances playback unless paused, returning the moves which became due
+	pub fn advance(&mut self, delta: Duration) -> Vec<ReplayMove> {
+		if self.paused || self.is_finished() {
+			return Vec::new();
+		}
+		self.time += delta.as_secs_f32();
+		let mut moves = Vec::new();
+		while let Some(event) = self.replay.events.get(self.next_event) {
+			if event.time > self.time {
+				break;
+			}
+			moves.push(event.action);
+			self.next_event += 1;
+		}
+		moves
+	}
+
+	// Plays the next move right away
+	pub fn step(&mut self) -> Option<ReplayMove> {
+		let event = self.replay.events.get(self.next_event)?;
+		self.time = event.time;
+		self.next_event += 1;
+		Some(event.action)
+	}
+}
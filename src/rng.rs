@@ -0,0 +1,103 @@
+// Random numbers for level generation, fully specified here so that a seed
+// gives the same levels in every build and on every platform. Any change
+// to this file, or to how the generator uses it, changes the levels and
+// needs a new generator::GENERATOR_VERSION.
+
+// SplitMix64, by Steele, Lea and Flood
+pub struct LevelRng {
+	state: u64,
+}
+
+impl LevelRng {
+	pub fn new(seed: u64) -> Self {
+		Self { state: seed }
+	}
+
+	pub fn next_u64(&mut self) -> u64 {
+		self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		let mut z = self.state;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+		z ^ (z >> 31)
+	}
+
+	// Uniform in 0..n, values past the largest multiple of n are
+	// drawn again so that every result is equally likely
+	pub fn below(&mut self, n: usize) -> usize {
+		assert!(n > 0, "empty range");
+		let n = n as u64;
+		let limit = u64::MAX - (u64::MAX % n + 1) % n;
+		loop {
+			let value = self.next_u64();
+			if value <= limit {
+				return (value % n) as usize;
+			}
+		}
+	}
+
+	// Fisher-Yates, swapping each item from the last down
+	// with one at or before it
+	pub fn shuffle<T>(&mut self, items: &mut [T]) {
+		for i in (1..items.len()).rev() {
+			let j = self.below(i + 1);
+			items.swap(i, j);
+		}
+	}
+
+	pub fn choose<'a, T>(&mut self, items: &'a [T]) -> Option<&'a T> {
+		if items.is_empty() {
+			None
+		} else {
+			Some(&items[self.below(items.len())])
+		}
+	}
+
+	// Up to count different items in random order, by shuffling
+	// the first count positions of the indices
+	pub fn choose_multiple<T: Clone>(&mut self, items: &[T], count: usize) -> Vec<T> {
+		let count = count.min(items.len());
+		let mut indices: Vec<usize> = (0..items.len()).collect();
+		for i in 0..count {
+			let j = i + self.below(items.len() - i);
+			indices.swap(i, j);
+		}
+		indices[..count].iter().map(|&i| items[i].clone()).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn matches_splitmix64_reference_values() {
+		let mut rng = LevelRng::new(1234567);
+		let values: Vec<u64> = (0..5).map(|_| rng.next_u64()).collect();
+		assert_eq!(values, vec![6457827717110365317, 3203168211198807973, 9817491932198370423, 4593380528125082431, 16408922859458223821]);
+		let mut rng = LevelRng::new(0);
+		assert_eq!(rng.next_u64(), 16294208416658607535);
+	}
+
+	#[test]
+	fn below_reduces_values_into_range() {
+		let mut rng = LevelRng::new(1234567);
+		let values: Vec<usize> = (0..5).map(|_| rng.below(10)).collect();
+		assert_eq!(values, vec![7, 3, 3, 1, 1]);
+		let mut rng = LevelRng::new(42);
+		assert!((0..1000).all(|_| rng.below(1) == 0));
+		assert!((0..1000).all(|_| rng.below(3) < 3));
+	}
+
+	#[test]
+	fn shuffles_from_the_last_item_down() {
+		let mut rng = LevelRng::new(1234567);
+		let mut items = [0, 1, 2, 3, 4];
+		rng.shuffle(&mut items);
+		assert_eq!(items, [4, 3, 0, 1, 2]);
+
+		let mut rng = LevelRng::new(1234567);
+		let mut empty: [usize; 0] = [];
+		rng.shuffle(&mut empty);
+		assert_eq!(rng.next_u64(), 6457827717110365317);
+	}
+}
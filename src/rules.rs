@@ -0,0 +1,25 @@
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rules {
+	// Move a single unit per pour instead of the whole top color
+	pub partial_pour: bool,
+	// Completed tubes can not be poured from or into
+	pub lock_completed_tubes: bool,
+}
+
+impl Rules {
+	pub fn new() -> Self {
+		Self {
+			partial_pour: false,
+			lock_completed_tubes: false,
+		}
+	}
+}
+
+impl Default for Rules {
+	fn default() -> Self {
+		Self::new()
+	}
+}
@@ -0,0 +1,296 @@
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use ggez::{filesystem, Context};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, ImmutableString, Map, Scope, AST};
+use crate::board::PourLimit;
+use crate::colors::color_name;
+use crate::color_tube::{self, ColorTube};
+use crate::level::Level;
+use crate::spectator::TubeState;
+
+// Lines kept in the console
+const MAX_LOG_LINES: usize = 200;
+
+// Limits of a single hook call, so a looping or deeply recursive
+// script fails with an error instead of freezing the game
+const MAX_OPERATIONS: u64 = 1_000_000;
+const MAX_CALL_LEVELS: usize = 32;
+
+// Rhai scripts (*.rhai) loaded from the mods folder may define hooks:
+//
+//   // Replaces the level generator, returns a level in the level file
+//   // format, see level.rs
+//   fn generate_level(level, color_count, capacity) {
+//       "capacity 2\ntube red blue\ntube blue red\ntube"
+//   }
+//
+//   // Called before each pour with the tubes as maps of index (from 1),
+//   // kind, capacity and contents (maps of color and amount, bottom to
+//   // top), the color poured and the amount which would be poured.
+//   // Returns true to allow, false or a reason to veto, or the amount
+//   // to pour instead
+//   fn fill(from, to, color, amount) {
+//       if to.index == 1 { "tube 1 is off limits" } else { true }
+//   }
+//
+// The first script defining generate_level is used, every fill hook has
+// to allow a pour and the smallest amount is poured.
+
+struct Script {
+	name: String,
+	ast: AST,
+}
+
+pub struct Mods {
+	engine: Engine,
+	scripts: Vec<Script>,
+	pub log: Vec<String>,
+}
+
+fn tube_map(index: usize, tube: &ColorTube) -> Map {
+	let state = TubeState::from_tube(tube);
+	let contents: Array = state.contents
+		.into_iter()
+		.map(|content| {
+			let mut map = Map::new();
+			map.insert("color".into(), Dynamic::from(content.color));
+			map.insert("amount".into(), Dynamic::from(content.amount as f64));
+			Dynamic::from(map)
+		})
+		.collect();
+	let mut map = Map::new();
+	map.insert("index".into(), Dynamic::from((index + 1) as i64));
+	map.insert("kind".into(), Dynamic::from(state.kind));
+	map.insert("locked".into(), Dynamic::from(state.locked));
+	map.insert("capacity".into(), Dynamic::from(tube.capacity as f64));
+	map.insert("contents".into(), Dynamic::from(contents));
+	map
+}
+
+impl Mods {
+	pub fn new() -> Self {
+		let mut engine = Engine::new();
+		engine.set_max_operations(MAX_OPERATIONS);
+		engine.set_max_call_levels(MAX_CALL_LEVELS);
+		Self {
+			engine,
+			scripts: Vec::new(),
+			log: Vec::new(),
+		}
+	}
+
+	pub fn log(&mut self, line: String) {
+		self.log.push(line);
+		if self.log.len() > MAX_LOG_LINES {
+			self.log.remove(0);
+		}
+	}
+
+	pub fn script_names(&self) -> Vec<String> {
+		self.scripts.iter().map(|s| s.name.clone()).collect()
+	}
+
+	// Compiles every script in the folder, in name order
+	pub fn load(&mut self, ctx: &mut Context, folder: &str) {
+		self.scripts.clear();
+		let mut paths: Vec<PathBuf> = match filesystem::read_dir(ctx, folder) {
+			Ok(paths) => paths.filter(|p| p.extension().map(|e| e == "rhai").unwrap_or(false)).collect(),
+			Err(_) => {
+				self.log(format!("Mods folder {} not found", folder));
+				return;
+			},
+		};
+		paths.sort();
+
+		for path in paths {
+			let mut source = String::new();
+			let read = filesystem::open(ctx, &path).and_then(|mut file| Ok(file.read_to_string(&mut source)?));
+			self.add_script(&path, read.map(|_| source).map_err(|e| e.to_string()));
+		}
+	}
+
+	// Same as load, for a folder outside of the ggez filesystem
+	pub fn load_dir(&mut self, folder: &Path) {
+		self.scripts.clear();
+		let mut paths: Vec<PathBuf> = match fs::read_dir(folder) {
+			Ok(entries) => entries
+				.filter_map(|e| e.ok().map(|e| e.path()))
+				.filter(|p| p.extension().map(|e| e == "rhai").unwrap_or(false))
+				.collect(),
+			Err(_) => {
+				self.log(format!("Mods folder {} not found", folder.display()));
+				return;
+			},
+		};
+		paths.sort();
+
+		for path in paths {
+			self.add_script(&path, fs::read_to_string(&path).map_err(|e| e.to_string()));
+		}
+	}
+
+	fn add_script(&mut self, path: &Path, source: Result<String, String>) {
+		let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+		let compiled = source.and_then(|source| self.engine.compile(&source).map_err(|e| e.to_string()));
+		match compiled {
+			Ok(ast) => {
+				self.log(format!("Loaded {}", name));
+				self.scripts.push(Script { name, ast });
+			},
+			Err(e) => self.log(format!("{}: {}", name, e)),
+		}
+	}
+
+	// Calls the hook in a script, returns None if the script does not define it
+	fn call(&self, script: &Script, hook: &str, args: Vec<Dynamic>) -> Option<Result<Dynamic, String>> {
+		let result: Result<Dynamic, Box<EvalAltResult>> = self.engine.call_fn_dynamic(&mut Scope::new(), &script.ast, false, hook, None, args);
+		match result {
+			Ok(value) => Some(Ok(value)),
+			Err(e) => match *e {
+				EvalAltResult::ErrorFunctionNotFound(ref signature, _) if signature.starts_with(hook) => None,
+				ref e => Some(Err(format!("{}: {}: {}", script.name, hook, e))),
+			},
+		}
+	}
+
+	// Level from the first script defining generate_level, errors
+	// are logged and fall back to the built in generator
+	pub fn generate_level(&mut self, level: usize, color_count: usize, capacity: f32) -> Option<Level> {
+		let mut result = None;
+		for script in &self.scripts {
+			let args = vec![Dynamic::from(level as i64), Dynamic::from(color_count as i64), Dynamic::from(capacity as f64)];
+			if let Some(value) = self.call(script, "generate_level", args) {
+				result = Some((script.name.clone(), value));
+				break;
+			}
+		}
+		let (name, value) = result?;
+		let text = match value.and_then(|v| v.try_cast::<ImmutableString>().ok_or_else(|| format!("{}: generate_level must return a string", name))) {
+			Ok(text) => text,
+			Err(e) => {
+				self.log(e);
+				return None;
+			},
+		};
+		match Level::parse(&text) {
+			Ok(level) => Some(level),
+			Err(e) => {
+				self.log(format!("{}: generated level is invalid: {}", name, e));
+				None
+			},
+		}
+	}
+
+	// Asks every fill hook about a pour, which the built in rules allow
+	pub fn fill_rule(&mut self, tubes: &[ColorTube], from: usize, to: usize, single_unit: bool) -> PourLimit {
+		if self.scripts.is_empty() {
+			return PourLimit::Unlimited;
+		}
+		let mut after = tubes.to_vec();
+		let amount = match color_tube::pour(&mut after, from, to, single_unit) {
+			Ok(amount) => amount,
+			Err(_) => return PourLimit::Unlimited,
+		};
+		let color = match tubes[from].contents().last() {
+			Some(content) => color_name(content.color()),
+			None => return PourLimit::Unlimited,
+		};
+
+		let mut limit = PourLimit::Unlimited;
+		let mut messages = Vec::new();
+		for script in &self.scripts {
+			let args = vec![
+				Dynamic::from(tube_map(from, &tubes[from])),
+				Dynamic::from(tube_map(to, &tubes[to])),
+				Dynamic::from(color.clone()),
+				Dynamic::from(amount as f64),
+			];
+			let value = match self.call(script, "fill", args) {
+				None => continue,
+				Some(Ok(value)) => value,
+				Some(Err(e)) => {
+					messages.push(e);
+					continue;
+				},
+			};
+			let decision = if let Some(allowed) = value.clone().try_cast::<bool>() {
+				if allowed { PourLimit::Unlimited } else { PourLimit::Vetoed }
+			} else if let Some(amount) = value.clone().try_cast::<f64>() {
+				if amount.is_finite() {
+					PourLimit::AtMost(amount as f32)
+				} else {
+					messages.push(format!("{}: fill returned {}", script.name, amount));
+					PourLimit::Vetoed
+				}
+			} else if let Some(amount) = value.clone().try_cast::<i64>() {
+				PourLimit::AtMost(amount as f32)
+			} else if let Some(reason) = value.try_cast::<ImmutableString>() {
+				messages.push(format!("{}: {}", script.name, reason));
+				PourLimit::Vetoed
+			} else {
+				messages.push(format!("{}: fill must return a bool, number or string", script.name));
+				PourLimit::Unlimited
+			};
+			limit = match (limit, decision) {
+				(PourLimit::Vetoed, _) | (_, PourLimit::Vetoed) => PourLimit::Vetoed,
+				(PourLimit::AtMost(a), PourLimit::AtMost(b)) => PourLimit::AtMost(a.min(b)),
+				(PourLimit::AtMost(a), PourLimit::Unlimited) | (PourLimit::Unlimited, PourLimit::AtMost(a)) => PourLimit::AtMost(a),
+				(PourLimit::Unlimited, PourLimit::Unlimited) => PourLimit::Unlimited,
+			};
+		}
+		for message in messages {
+			self.log(message);
+		}
+
+		match limit {
+			PourLimit::AtMost(amount) if amount <= 0.0 => PourLimit::Vetoed,
+			limit => limit,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::rc::Rc;
+	use ggez::graphics::Font;
+	use super::*;
+	use crate::theme::Theme;
+
+	fn mods(source: &str) -> Mods {
+		let mut mods = Mods::new();
+		mods.add_script(Path::new("test.rhai"), Ok(source.to_string()));
+		mods.log.clear();
+		mods
+	}
+
+	fn tubes() -> Vec<ColorTube> {
+		Level::parse("capacity 4\ntube red*2\ntube red").unwrap().to_tubes(Font::default(), &Rc::new(Theme::default()))
+	}
+
+	#[test]
+	fn fill_limits_pours() {
+		assert_eq!(mods("fn fill(from, to, color, amount) { 1 }").fill_rule(&tubes(), 0, 1, false), PourLimit::AtMost(1.0));
+		assert_eq!(mods("fn fill(from, to, color, amount) { amount / 4.0 }").fill_rule(&tubes(), 0, 1, false), PourLimit::AtMost(0.5));
+		assert_eq!(mods("fn fill(from, to, color, amount) { to.index != 2 }").fill_rule(&tubes(), 0, 1, false), PourLimit::Vetoed);
+		assert_eq!(mods("fn fill(from, to, color, amount) { 0 }").fill_rule(&tubes(), 0, 1, false), PourLimit::Vetoed);
+	}
+
+	#[test]
+	fn non_finite_fill_amounts_veto() {
+		let mut mods = mods("fn fill(from, to, color, amount) { if to.index == 2 { 0.0 / 0.0 } else { 1.0 / 0.0 } }");
+		assert_eq!(mods.fill_rule(&tubes(), 0, 1, false), PourLimit::Vetoed);
+		assert_eq!(mods.fill_rule(&tubes(), 1, 0, false), PourLimit::Vetoed);
+		assert_eq!(mods.log, vec!["test.rhai: fill returned NaN".to_string(), "test.rhai: fill returned inf".to_string()]);
+	}
+
+	#[test]
+	fn runaway_scripts_are_stopped_and_logged() {
+		let mut mods = mods("fn fill(from, to, color, amount) { let n = 0; loop { n += 1; } }\nfn deep(n) { deep(n + 1) }\nfn generate_level(level, colors, capacity) { deep(0) }");
+		assert_eq!(mods.fill_rule(&tubes(), 0, 1, false), PourLimit::Unlimited);
+		assert_eq!(mods.generate_level(1, 2, 4.0), None);
+		assert_eq!(mods.log.len(), 2);
+		assert!(mods.log[0].starts_with("test.rhai: fill: Too many operations"), "{}", mods.log[0]);
+		assert!(mods.log[1].starts_with("test.rhai: generate_level: "), "{}", mods.log[1]);
+	}
+}
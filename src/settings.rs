@@ -0,0 +1,121 @@
+use std::io::{Read, Write};
+use ggez::{filesystem, Context, GameError, GameResult};
+use ggez::graphics::Color;
+use serde::{Serialize, Deserialize};
+use crate::bindings::{Bindings, KeyboardLayout};
+use crate::rules::Rules;
+use crate::theme::hex_colors_opt;
+
+const SETTINGS_PATH: &str = "/settings.toml";
+
+// Plain values must be declared before tables (rules, bindings),
+// otherwise the settings can not be serialized to TOML
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+	pub full_screen: bool,
+	pub special_tubes: bool,
+	pub keyboard_layout: KeyboardLayout,
+	pub theme: String,
+	// Replaces the palette of the theme when set
+	#[serde(with = "hex_colors_opt")]
+	pub custom_palette: Option<Vec<Color>>,
+	// Endless levels follow the difficulty progression,
+	// otherwise they all have color_count colors
+	pub progression: bool,
+	// Number of colors used by the level generator
+	pub color_count: usize,
+	// Sound effect volume from 0.0 to 1.0
+	pub volume: f32,
+	pub muted: bool,
+	// Show why a pour was not possible
+	pub pour_error_tips: bool,
+	// Keep the source tube selected after an invalid pour
+	pub keep_selection_on_error: bool,
+	// Stream game events to local spectator clients
+	pub spectators: bool,
+	pub spectator_port: u16,
+	// Folder in the user data directory with *.rhai mod scripts
+	pub mods_folder: String,
+	pub rules: Rules,
+	#[serde(default = "Bindings::empty")]
+	pub bindings: Bindings,
+}
+
+impl Settings {
+	pub fn new() -> Self {
+		Self {
+			full_screen: false,
+			special_tubes: false,
+			keyboard_layout: KeyboardLayout::Qwerty,
+			theme: "Dark".to_string(),
+			custom_palette: None,
+			progression: true,
+			color_count: 12,
+			volume: 0.8,
+			muted: false,
+			pour_error_tips: true,
+			keep_selection_on_error: false,
+			spectators: false,
+			spectator_port: 7879,
+			mods_folder: "/mods".to_string(),
+			rules: Rules::new(),
+			bindings: Bindings::preset(KeyboardLayout::Qwerty),
+		}
+	}
+
+	// Key bindings missing from the settings use the saved keyboard layout
+	pub fn parse(s: &str) -> GameResult<Self> {
+		let mut settings: Self = toml::from_str(s).map_err(|e| GameError::ResourceLoadError(e.to_string()))?;
+		settings.bindings.fill_missing(settings.keyboard_layout);
+		Ok(settings)
+	}
+
+	// Falls back to default settings if the settings file is missing or invalid
+	pub fn load(ctx: &mut Context) -> Self {
+		let mut s = String::new();
+		match filesystem::open(ctx, SETTINGS_PATH).and_then(|mut file| Ok(file.read_to_string(&mut s)?)) {
+			Ok(_) => Self::parse(&s).unwrap_or_else(|_| Self::new()),
+			Err(_) => Self::new(),
+		}
+	}
+
+	pub fn save(&self, ctx: &mut Context) -> GameResult {
+		let s = toml::to_string(self).map_err(|e| GameError::ConfigError(e.to_string()))?;
+		let mut file = filesystem::create(ctx, SETTINGS_PATH)?;
+		file.write_all(s.as_bytes())?;
+		Ok(())
+	}
+}
+
+impl Default for Settings {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ggez::event::KeyCode;
+	use super::*;
+	use crate::bindings::{Action, KeyBinding};
+
+	#[test]
+	fn missing_bindings_follow_keyboard_layout() {
+		let settings = Settings::parse("keyboard_layout = \"Azerty\"\n[bindings]\nundo = \"Ctrl + U\"\n").unwrap();
+		let mut expected = Bindings::preset(KeyboardLayout::Azerty);
+		expected.set(Action::Undo, KeyBinding::new(KeyCode::U, true));
+		assert_eq!(settings.bindings, expected);
+	}
+
+	#[test]
+	fn saved_settings_load_unchanged() {
+		let mut settings = Settings::new();
+		settings.keyboard_layout = KeyboardLayout::Dvorak;
+		settings.bindings = Bindings::preset(KeyboardLayout::Dvorak);
+		settings.bindings.set(Action::Tube(0), KeyBinding::new(KeyCode::F5, true));
+		let loaded = Settings::parse(&toml::to_string(&settings).unwrap()).unwrap();
+		assert_eq!(loaded.bindings, settings.bindings);
+		assert_eq!(loaded.keyboard_layout, KeyboardLayout::Dvorak);
+	}
+}
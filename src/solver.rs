@@ -0,0 +1,376 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use crate::color_tube::{ColorTube, ColorTubeKind};
+use crate::rules::Rules;
+
+// Upper bound on board states kept by each search before giving up
+const MAX_STATES: usize = 200_000;
+
+// Amounts are searched in quarter units
+const UNITS_PER_AMOUNT: f32 = 4.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Solution {
+	// Pours as (from, to) tube indices
+	pub moves: Vec<(usize, usize)>,
+	pub states_explored: usize,
+	// Whether no shorter solution exists, solutions found after
+	// the shortest one could not be searched for are not
+	pub optimal: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SolveError {
+	// Every reachable board was searched
+	Unsolvable,
+	// Gave up before finding a solution, the board may still be solvable
+	LimitReached,
+}
+
+impl SolveError {
+	pub fn description(&self) -> &'static str {
+		match self {
+			SolveError::Unsolvable => "no solution exists",
+			SolveError::LimitReached => "search limit reached",
+		}
+	}
+}
+
+// Contents of each tube as (color, amount in quarter units) from the
+// bottom, with the tubes which are still locked
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct State {
+	contents: Vec<Vec<(u8, u8)>>,
+	locked: Vec<bool>,
+}
+
+// A searched state with the state it was reached from, the pour and the moves made
+type SearchNode = (State, Option<(usize, (usize, usize))>, usize);
+
+// The parts of the board which never change during a search
+struct Board {
+	kinds: Vec<ColorTubeKind>,
+	kind_keys: Vec<(u8, u8)>,
+	capacities: Vec<u8>,
+	// Fewest separate amounts the colors can end up in, if known
+	min_amounts: Option<usize>,
+	rules: Rules,
+}
+
+fn kind_key(kind: ColorTubeKind) -> (u8, u8) {
+	match kind {
+		ColorTubeKind::Normal => (0, 0),
+		ColorTubeKind::Locked(color) => (1, color as u8),
+		ColorTubeKind::PourIn => (2, 0),
+		ColorTubeKind::PourOut => (3, 0),
+		ColorTubeKind::SingleColor(color) => (4, color as u8),
+	}
+}
+
+fn units(amount: f32) -> Option<u8> {
+	let units = amount * UNITS_PER_AMOUNT;
+	if units.fract() == 0.0 && units >= 0.0 && units <= u8::MAX as f32 {
+		Some(units as u8)
+	} else {
+		None
+	}
+}
+
+impl Board {
+	// None if the board does not fit the compact state
+	fn new(tubes: &[ColorTube], rules: Rules) -> Option<(Self, State)> {
+		let mut contents = Vec::with_capacity(tubes.len());
+		let mut totals: HashMap<u8, usize> = HashMap::new();
+		for tube in tubes {
+			let mut tube_contents = Vec::with_capacity(tube.contents().len());
+			for content in tube.contents() {
+				if content.color() > u8::MAX as usize {
+					return None;
+				}
+				let amount = units(content.amount())?;
+				*totals.entry(content.color() as u8).or_insert(0) += amount as usize;
+				tube_contents.push((content.color() as u8, amount));
+			}
+			contents.push(tube_contents);
+		}
+		let capacities = tubes.iter().map(|t| units(t.capacity)).collect::<Option<Vec<u8>>>()?;
+		// With equal tubes each color ends up filling a known number of them
+		let min_amounts = match capacities.first() {
+			Some(&capacity) if capacity > 0 && capacities.iter().all(|&c| c == capacity) => {
+				Some(totals.values().map(|&total| total.div_ceil(capacity as usize)).sum())
+			},
+			_ => None,
+		};
+		let board = Self {
+			kinds: tubes.iter().map(|t| t.kind).collect(),
+			kind_keys: tubes.iter().map(|t| kind_key(t.kind)).collect(),
+			capacities,
+			min_amounts,
+			rules,
+		};
+		let state = State {
+			contents,
+			locked: tubes.iter().map(|t| t.locked).collect(),
+		};
+		Some((board, state))
+	}
+
+	fn amount(contents: &[(u8, u8)]) -> u8 {
+		contents.iter().map(|&(_, amount)| amount).sum()
+	}
+
+	fn is_completed(&self, state: &State, index: usize) -> bool {
+		match state.contents[index].as_slice() {
+			[(_, amount)] => *amount >= self.capacities[index],
+			_ => false,
+		}
+	}
+
+	fn is_solved(&self, state: &State) -> bool {
+		(0..state.contents.len()).all(|i| state.contents[i].is_empty() || self.is_completed(state, i))
+	}
+
+	// Same checks as color_tube::pour and update_locks
+	fn pour(&self, state: &State, from: usize, to: usize) -> Option<State> {
+		if state.locked[from] || state.locked[to] {
+			return None;
+		}
+		if self.kinds[from] == ColorTubeKind::PourIn || self.kinds[to] == ColorTubeKind::PourOut {
+			return None;
+		}
+		if self.rules.lock_completed_tubes && self.is_completed(state, from) {
+			return None;
+		}
+		let &(color, top_amount) = state.contents[from].last()?;
+		let remaining = self.capacities[to].saturating_sub(Self::amount(&state.contents[to]));
+		if remaining == 0 {
+			return None;
+		}
+		match self.kinds[to] {
+			ColorTubeKind::SingleColor(only_color) if only_color != color as usize => return None,
+			_ => {},
+		}
+		match state.contents[to].last() {
+			Some(&(top_color, _)) if top_color != color => return None,
+			// Moving a whole tube of one color into an empty tube just like it
+			// gives the same board with two tubes swapped
+			None if state.contents[from].len() == 1
+				&& self.kinds[from] == self.kinds[to]
+				&& self.capacities[from] == self.capacities[to] => return None,
+			_ => {},
+		}
+
+		let mut max_amount = remaining;
+		if self.rules.partial_pour {
+			max_amount = max_amount.min(UNITS_PER_AMOUNT as u8);
+		}
+		let amount = top_amount.min(max_amount);
+		let mut next = state.clone();
+		if amount == top_amount {
+			next.contents[from].pop();
+		} else {
+			next.contents[from].last_mut().unwrap().1 -= amount;
+		}
+		match next.contents[to].last_mut() {
+			Some(top) => top.1 += amount,
+			None => next.contents[to].push((color, amount)),
+		}
+
+		if self.is_completed(&next, to) {
+			for (i, &kind) in self.kinds.iter().enumerate() {
+				if kind == ColorTubeKind::Locked(color as usize) {
+					next.locked[i] = false;
+				}
+			}
+		}
+		Some(next)
+	}
+
+	// Lower bound on the moves left: a pour takes at most one color off
+	// a tube and joins at most two separate amounts of a color
+	fn heuristic(&self, state: &State) -> usize {
+		let mut separated = 0;
+		let mut amounts = 0;
+		for contents in &state.contents {
+			separated += contents.len().saturating_sub(1);
+			amounts += contents.len();
+		}
+		match self.min_amounts {
+			Some(min_amounts) => separated.max(amounts.saturating_sub(min_amounts)),
+			None => separated,
+		}
+	}
+
+	// Tubes of the same kind and capacity are interchangeable, so states
+	// are deduplicated by their tubes in sorted order, written as bytes
+	fn key(&self, state: &State) -> Vec<u8> {
+		let mut order: Vec<usize> = (0..state.contents.len()).collect();
+		order.sort_by_key(|&i| (self.kind_keys[i], self.capacities[i], state.locked[i], &state.contents[i]));
+		let mut key = Vec::with_capacity(order.len() * 8);
+		for i in order {
+			let (kind, color) = self.kind_keys[i];
+			key.extend_from_slice(&[kind, color, self.capacities[i], state.locked[i] as u8, state.contents[i].len() as u8]);
+			for &(color, amount) in &state.contents[i] {
+				key.extend_from_slice(&[color, amount]);
+			}
+		}
+		key
+	}
+
+	// Best first search ordered by moves made times g_weight plus the
+	// heuristic times h_weight, with weights of 1 this is A* and finds
+	// the shortest solution
+	fn search(&self, start: &State, g_weight: usize, h_weight: usize) -> Result<Solution, SolveError> {
+		let mut states: Vec<SearchNode> = vec![(start.clone(), None, 0)];
+		let mut best_moves: HashMap<Vec<u8>, usize> = HashMap::new();
+		best_moves.insert(self.key(start), 0);
+		let mut queue = BinaryHeap::new();
+		queue.push(Reverse((self.heuristic(start) * h_weight, 0)));
+
+		while let Some(Reverse((_, state_index))) = queue.pop() {
+			let moves = states[state_index].2;
+			if best_moves.get(&self.key(&states[state_index].0)).map(|&best| best < moves).unwrap_or(false) {
+				// Reached again with fewer moves since it was queued
+				continue;
+			}
+			if self.is_solved(&states[state_index].0) {
+				let mut pours = Vec::new();
+				let mut index = state_index;
+				while let Some((parent, pour)) = states[index].1 {
+					pours.push(pour);
+					index = parent;
+				}
+				pours.reverse();
+				return Ok(Solution { moves: pours, states_explored: states.len(), optimal: g_weight == h_weight });
+			}
+			if states.len() >= MAX_STATES {
+				return Err(SolveError::LimitReached);
+			}
+
+			let count = self.kinds.len();
+			for from in 0..count {
+				for to in 0..count {
+					if from == to {
+						continue;
+					}
+					let next = match self.pour(&states[state_index].0, from, to) {
+						Some(next) => next,
+						None => continue,
+					};
+					let key = self.key(&next);
+					if best_moves.get(&key).map(|&best| best <= moves + 1).unwrap_or(false) {
+						continue;
+					}
+					best_moves.insert(key, moves + 1);
+					let priority = (moves + 1) * g_weight + self.heuristic(&next) * h_weight;
+					queue.push(Reverse((priority, states.len())));
+					states.push((next, Some((state_index, (from, to))), moves + 1));
+				}
+			}
+		}
+		Err(SolveError::Unsolvable)
+	}
+}
+
+pub fn is_solved(tubes: &[ColorTube]) -> bool {
+	tubes.iter().all(|t| t.contents().is_empty() || t.is_completed())
+}
+
+// Searches for the shortest solution, and for any solution if the
+// board is too large to find the shortest one
+pub fn solve(tubes: &[ColorTube], rules: Rules) -> Result<Solution, SolveError> {
+	if is_solved(tubes) {
+		return Ok(Solution { moves: Vec::new(), states_explored: 0, optimal: true });
+	}
+	let (board, start) = Board::new(tubes, rules).ok_or(SolveError::LimitReached)?;
+	match board.search(&start, 1, 1) {
+		Err(SolveError::LimitReached) => find_solution(tubes, rules),
+		result => result,
+	}
+}
+
+// Searches for any solution, which is quicker than the shortest one
+pub fn find_solution(tubes: &[ColorTube], rules: Rules) -> Result<Solution, SolveError> {
+	if is_solved(tubes) {
+		return Ok(Solution { moves: Vec::new(), states_explored: 0, optimal: true });
+	}
+	let (board, start) = Board::new(tubes, rules).ok_or(SolveError::LimitReached)?;
+	board.search(&start, 1, 4)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::rc::Rc;
+	use ggez::graphics::Font;
+	use super::*;
+	use crate::color_tube;
+	use crate::generator;
+	use crate::level::Level;
+	use crate::theme::Theme;
+
+	fn tubes(board: &str) -> Vec<ColorTube> {
+		Level::parse(board).unwrap().to_tubes(Font::default(), &Rc::new(Theme::default()))
+	}
+
+	// Plays the solution and checks that it solves the board
+	fn check_solution(mut tubes: Vec<ColorTube>, rules: Rules, solution: &Solution) {
+		for &(from, to) in &solution.moves {
+			assert!(color_tube::pour(&mut tubes, from, to, rules.partial_pour).is_ok(), "invalid pour {} to {}", from, to);
+			color_tube::update_locks(&mut tubes);
+		}
+		assert!(is_solved(&tubes));
+	}
+
+	#[test]
+	fn is_solved_needs_full_single_color_tubes() {
+		assert!(is_solved(&tubes("tube red*4\ntube blue*4\ntube")));
+		assert!(!is_solved(&tubes("tube red*4\ntube blue*3 red")));
+		assert!(!is_solved(&tubes("tube red*2\ntube red*2")));
+	}
+
+	#[test]
+	fn solves_small_board_in_fewest_moves() {
+		let board = tubes("tube red red blue blue\ntube blue blue red red\ntube");
+		let solution = solve(&board, Rules::new()).unwrap();
+		assert_eq!(solution.moves.len(), 3);
+		assert!(solution.optimal);
+		check_solution(board, Rules::new(), &solution);
+	}
+
+	#[test]
+	fn solved_board_needs_no_moves() {
+		let solution = solve(&tubes("tube red*4\ntube"), Rules::new()).unwrap();
+		assert!(solution.moves.is_empty());
+	}
+
+	#[test]
+	fn partial_pour_moves_single_units() {
+		let rules = Rules { partial_pour: true, ..Rules::new() };
+		let board = tubes("capacity 2\ntube red blue\ntube blue red\ntube");
+		let solution = solve(&board, rules).unwrap();
+		check_solution(board, rules, &solution);
+	}
+
+	#[test]
+	fn locked_tube_opens_after_its_color() {
+		let board = tubes("tube locked:blue red*4\ntube blue*2 green*2\ntube blue*2\ntube green*2");
+		let solution = solve(&board, Rules::new()).unwrap();
+		check_solution(board, Rules::new(), &solution);
+	}
+
+	#[test]
+	fn detects_unsolvable_boards() {
+		assert_eq!(solve(&tubes("tube red blue red blue\ntube blue red blue red"), Rules::new()), Err(SolveError::Unsolvable));
+		// Three units of red never fill a tube
+		assert_eq!(solve(&tubes("tube blue red*3\ntube blue*3\ntube"), Rules::new()), Err(SolveError::Unsolvable));
+	}
+
+	#[test]
+	fn solves_default_size_boards() {
+		for seed in 1..=3 {
+			let board = generator::generate(seed, 12, 4.0, 2, false).to_tubes(Font::default(), &Rc::new(Theme::default()));
+			let solution = solve(&board, Rules::new()).unwrap();
+			check_solution(board, Rules::new(), &solution);
+		}
+	}
+}
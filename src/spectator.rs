@@ -0,0 +1,212 @@
+use std::io;
+use std::net::TcpListener;
+use serde::Serialize;
+use crate::colors::color_name;
+use crate::color_tube::{ColorTube, ColorTubeKind};
+use crate::net::Peer;
+use crate::rules::Rules;
+
+// Spectator stream, one JSON object per line with the event name in
+// "event". Tubes are numbered from 0, levels from 1, contents are listed
+// bottom to top and amounts are in units:
+//
+//   {"event":"level_start","level":1,"capacity":4.0,"rules":{...},"tubes":[{"kind":"normal","locked":false,"contents":[{"color":"pink","amount":2.0}]}]}
+//   {"event":"pour","player":0,"from":0,"to":3,"amount":2.0,"complete_pct":0.25}
+//   {"event":"undo","player":0,"complete_pct":0.0}
+//   {"event":"level_complete","player":0,"level":1,"moves":21,"time":48.2}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ContentState {
+	pub color: String,
+	pub amount: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TubeState {
+	// Same names as in level files: normal, in, out, only:<color>, locked:<color>
+	pub kind: String,
+	pub locked: bool,
+	pub contents: Vec<ContentState>,
+}
+
+impl TubeState {
+	pub fn from_tube(tube: &ColorTube) -> Self {
+		Self {
+			kind: match tube.kind {
+				ColorTubeKind::Normal => "normal".to_string(),
+				ColorTubeKind::Locked(color) => format!("locked:{}", color_name(color)),
+				ColorTubeKind::PourIn => "in".to_string(),
+				ColorTubeKind::PourOut => "out".to_string(),
+				ColorTubeKind::SingleColor(color) => format!("only:{}", color_name(color)),
+			},
+			locked: tube.locked,
+			contents: tube.contents()
+				.iter()
+				.map(|c| ContentState { color: color_name(c.color()), amount: c.amount() })
+				.collect(),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SpectatorEvent {
+	LevelStart { level: usize, capacity: f32, rules: Rules, tubes: Vec<TubeState> },
+	Pour { player: usize, from: usize, to: usize, amount: f32, complete_pct: f32 },
+	Undo { player: usize, complete_pct: f32 },
+	LevelComplete { player: usize, level: usize, moves: usize, time: f32 },
+}
+
+// Local socket streaming game events to read-only clients
+pub struct Spectators {
+	listener: TcpListener,
+	clients: Vec<Peer>,
+	// Last level start, sent to clients connecting mid level
+	level_start: Option<String>,
+}
+
+impl Spectators {
+	pub fn bind(port: u16) -> io::Result<Self> {
+		let listener = TcpListener::bind(("127.0.0.1", port))?;
+		listener.set_nonblocking(true)?;
+		Ok(Self {
+			listener,
+			clients: Vec::new(),
+			level_start: None,
+		})
+	}
+
+	pub fn port(&self) -> u16 {
+		self.listener.local_addr().map(|a| a.port()).unwrap_or(0)
+	}
+
+	pub fn client_count(&self) -> usize {
+		self.clients.len()
+	}
+
+	pub fn send(&mut self, event: &SpectatorEvent) {
+		let line = match serde_json::to_string(event) {
+			Ok(line) => line,
+			Err(_) => return,
+		};
+		for client in &mut self.clients {
+			client.send(&line);
+		}
+		if let SpectatorEvent::LevelStart { .. } = event {
+			self.level_start = Some(line);
+		}
+	}
+
+	// Accepts clients, writes queued events and drops closed
+	// connections, never blocks
+	pub fn poll(&mut self) {
+		while let Ok((stream, _)) = self.listener.accept() {
+			if let Ok(mut peer) = Peer::new(stream, 0) {
+				if let Some(line) = &self.level_start {
+					peer.send(line);
+				}
+				self.clients.push(peer);
+			}
+		}
+		for client in &mut self.clients {
+			// Anything sent by clients is ignored, reading detects disconnects
+			client.read_lines();
+			client.flush();
+		}
+		self.clients.retain(|c| !c.closed);
+	}
+}
+
+// Unit tests of the stream, reading it over loopback with plain
+// TcpStreams. The crate is a single binary, so there are no
+// integration tests, and examples/spectate.rs is not run by them.
+#[cfg(test)]
+mod tests {
+	use std::io::{BufRead, BufReader};
+	use std::net::TcpStream;
+	use std::thread;
+	use std::time::Duration;
+	use super::*;
+
+	fn connect(spectators: &mut Spectators) -> BufReader<TcpStream> {
+		let stream = TcpStream::connect(("127.0.0.1", spectators.port())).unwrap();
+		stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+		let count = spectators.client_count();
+		for _ in 0..500 {
+			spectators.poll();
+			if spectators.client_count() > count {
+				return BufReader::new(stream);
+			}
+			thread::sleep(Duration::from_millis(5));
+		}
+		panic!("spectator was not accepted");
+	}
+
+	fn read_line(client: &mut BufReader<TcpStream>) -> String {
+		let mut line = String::new();
+		client.read_line(&mut line).unwrap();
+		line.trim_end().to_string()
+	}
+
+	fn level_start() -> SpectatorEvent {
+		SpectatorEvent::LevelStart {
+			level: 3,
+			capacity: 4.0,
+			rules: Rules::new(),
+			tubes: vec![
+				TubeState {
+					kind: "locked:red".to_string(),
+					locked: false,
+					contents: vec![ContentState { color: "pink".to_string(), amount: 2.0 }],
+				},
+				TubeState { kind: "normal".to_string(), locked: true, contents: Vec::new() },
+			],
+		}
+	}
+
+	const LEVEL_START: &str = r#"{"event":"level_start","level":3,"capacity":4.0,"rules":{"partial_pour":false,"lock_completed_tubes":false},"tubes":[{"kind":"locked:red","locked":false,"contents":[{"color":"pink","amount":2.0}]},{"kind":"normal","locked":true,"contents":[]}]}"#;
+
+	#[test]
+	fn streams_events_as_json_lines() {
+		let mut spectators = Spectators::bind(0).unwrap();
+		let mut client = connect(&mut spectators);
+
+		spectators.send(&level_start());
+		spectators.send(&SpectatorEvent::Pour { player: 1, from: 0, to: 3, amount: 2.0, complete_pct: 0.25 });
+		spectators.send(&SpectatorEvent::Undo { player: 0, complete_pct: 0.0 });
+		spectators.send(&SpectatorEvent::LevelComplete { player: 0, level: 3, moves: 21, time: 48.5 });
+		spectators.poll();
+
+		assert_eq!(read_line(&mut client), LEVEL_START);
+		assert_eq!(read_line(&mut client), r#"{"event":"pour","player":1,"from":0,"to":3,"amount":2.0,"complete_pct":0.25}"#);
+		assert_eq!(read_line(&mut client), r#"{"event":"undo","player":0,"complete_pct":0.0}"#);
+		assert_eq!(read_line(&mut client), r#"{"event":"level_complete","player":0,"level":3,"moves":21,"time":48.5}"#);
+	}
+
+	#[test]
+	fn late_spectators_receive_the_level_start() {
+		let mut spectators = Spectators::bind(0).unwrap();
+		spectators.send(&level_start());
+		spectators.send(&SpectatorEvent::Pour { player: 0, from: 0, to: 1, amount: 1.0, complete_pct: 0.5 });
+		let mut client = connect(&mut spectators);
+
+		spectators.send(&SpectatorEvent::Undo { player: 0, complete_pct: 0.0 });
+		spectators.poll();
+		assert_eq!(read_line(&mut client), LEVEL_START);
+		assert_eq!(read_line(&mut client), r#"{"event":"undo","player":0,"complete_pct":0.0}"#);
+	}
+
+	#[test]
+	fn closed_spectators_are_dropped() {
+		let mut spectators = Spectators::bind(0).unwrap();
+		drop(connect(&mut spectators));
+		for _ in 0..500 {
+			spectators.poll();
+			if spectators.client_count() == 0 {
+				return;
+			}
+			thread::sleep(Duration::from_millis(5));
+		}
+		panic!("closed spectator was kept");
+	}
+}
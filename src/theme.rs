@@ -0,0 +1,221 @@
+use std::io::Read;
+use ggez::{filesystem, Context, GameError, GameResult};
+use ggez::graphics::Color;
+use imgui::StyleColor;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error;
+use crate::colors::*;
+
+const BUILTIN_THEMES: [&str; 3] = [
+	include_str!("../themes/dark.toml"),
+	include_str!("../themes/light.toml"),
+	include_str!("../themes/high_contrast.toml"),
+];
+
+// Directory of additional theme files in the ggez filesystem
+const THEMES_DIR: &str = "/themes";
+
+// Color fields are written as "#rrggbb" or "#rrggbbaa" in theme files
+pub mod hex_color {
+	use super::*;
+
+	pub fn serialize<S: Serializer>(color: &Color, serializer: S) -> Result<S::Ok, S::Error> {
+		color_to_hex(*color).serialize(serializer)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Color, D::Error> {
+		let s = String::deserialize(deserializer)?;
+		color_from_hex(&s).ok_or_else(|| D::Error::custom(format!("invalid color {:?}", s)))
+	}
+}
+
+pub mod hex_colors {
+	use super::*;
+
+	pub fn serialize<S: Serializer>(colors: &[Color], serializer: S) -> Result<S::Ok, S::Error> {
+		colors.iter().map(|&c| color_to_hex(c)).collect::<Vec<String>>().serialize(serializer)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Color>, D::Error> {
+		Vec::<String>::deserialize(deserializer)?
+			.iter()
+			.map(|s| color_from_hex(s).ok_or_else(|| D::Error::custom(format!("invalid color {:?}", s))))
+			.collect()
+	}
+}
+
+pub mod hex_colors_opt {
+	use super::*;
+
+	pub fn serialize<S: Serializer>(colors: &Option<Vec<Color>>, serializer: S) -> Result<S::Ok, S::Error> {
+		colors.as_ref().map(|colors| colors.iter().map(|&c| color_to_hex(c)).collect::<Vec<String>>()).serialize(serializer)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<Color>>, D::Error> {
+		match Option::<Vec<String>>::deserialize(deserializer)? {
+			Some(colors) => colors
+				.iter()
+				.map(|s| color_from_hex(s).ok_or_else(|| D::Error::custom(format!("invalid color {:?}", s))))
+				.collect::<Result<Vec<Color>, D::Error>>()
+				.map(Some),
+			None => Ok(None),
+		}
+	}
+}
+
+mod hex_color_opt {
+	use super::*;
+
+	pub fn serialize<S: Serializer>(color: &Option<Color>, serializer: S) -> Result<S::Ok, S::Error> {
+		color.map(color_to_hex).serialize(serializer)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Color>, D::Error> {
+		match Option::<String>::deserialize(deserializer)? {
+			Some(s) => color_from_hex(&s).map(Some).ok_or_else(|| D::Error::custom(format!("invalid color {:?}", s))),
+			None => Ok(None),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImGuiBaseStyle {
+	Dark,
+	Light,
+	Classic,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImGuiTheme {
+	pub base: ImGuiBaseStyle,
+	#[serde(default, with = "hex_color_opt")]
+	pub window_bg: Option<Color>,
+	#[serde(default, with = "hex_color_opt")]
+	pub text: Option<Color>,
+	#[serde(default, with = "hex_color_opt")]
+	pub accent: Option<Color>,
+}
+
+impl ImGuiTheme {
+	pub fn apply(&self, style: &mut imgui::Style) {
+		match self.base {
+			ImGuiBaseStyle::Dark => style.use_dark_colors(),
+			ImGuiBaseStyle::Light => style.use_light_colors(),
+			ImGuiBaseStyle::Classic => style.use_classic_colors(),
+		};
+		let to_rgba = |c: Color| [c.r, c.g, c.b, c.a];
+		if let Some(color) = self.window_bg {
+			style[StyleColor::WindowBg] = to_rgba(color);
+			style[StyleColor::PopupBg] = to_rgba(color);
+			style[StyleColor::MenuBarBg] = to_rgba(color);
+		}
+		if let Some(color) = self.text {
+			style[StyleColor::Text] = to_rgba(color);
+		}
+		if let Some(color) = self.accent {
+			let hovered = [color.r, color.g, color.b, color.a * 0.8];
+			style[StyleColor::Button] = to_rgba(color);
+			style[StyleColor::ButtonHovered] = hovered;
+			style[StyleColor::Header] = to_rgba(color);
+			style[StyleColor::HeaderHovered] = hovered;
+			style[StyleColor::CheckMark] = to_rgba(color);
+			style[StyleColor::SliderGrab] = to_rgba(color);
+			style[StyleColor::TitleBgActive] = to_rgba(color);
+		}
+	}
+}
+
+fn default_tube_border_error() -> Color {
+	Color::from_rgb(255, 48, 48)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Theme {
+	pub name: String,
+	#[serde(with = "hex_colors")]
+	pub palette: Vec<Color>,
+	#[serde(with = "hex_color")]
+	pub background: Color,
+	#[serde(with = "hex_color")]
+	pub tube_border: Color,
+	#[serde(with = "hex_color")]
+	pub tube_border_hover: Color,
+	#[serde(with = "hex_color")]
+	pub tube_border_focus: Color,
+	#[serde(with = "hex_color")]
+	pub tube_border_cursor: Color,
+	// Flashed on a tube after an invalid pour
+	#[serde(default = "default_tube_border_error", with = "hex_color")]
+	pub tube_border_error: Color,
+	#[serde(with = "hex_color")]
+	pub key_label: Color,
+	#[serde(with = "hex_color")]
+	pub hud_text: Color,
+	// Completion colors from 0% to 100%
+	#[serde(with = "hex_colors")]
+	pub hud_progress: Vec<Color>,
+	pub imgui: ImGuiTheme,
+}
+
+impl Theme {
+	pub fn parse(s: &str) -> GameResult<Self> {
+		let theme: Self = toml::from_str(s).map_err(|e| GameError::ResourceLoadError(e.to_string()))?;
+		if theme.palette.len() < LIQUID_COLOR_NAMES.len() {
+			return Err(GameError::ResourceLoadError(format!("theme {:?} must have at least {} palette colors", theme.name, LIQUID_COLOR_NAMES.len())));
+		}
+		if theme.hud_progress.is_empty() {
+			return Err(GameError::ResourceLoadError(format!("theme {:?} has no progress colors", theme.name)));
+		}
+		Ok(theme)
+	}
+
+	pub fn builtin() -> Vec<Self> {
+		BUILTIN_THEMES
+			.iter()
+			.map(|s| Self::parse(s).expect("invalid builtin theme"))
+			.collect()
+	}
+
+	// Built-in themes followed by any valid theme files in the themes directory
+	pub fn load_all(ctx: &mut Context) -> Vec<Self> {
+		let mut themes = Self::builtin();
+		if let Ok(paths) = filesystem::read_dir(ctx, THEMES_DIR) {
+			let mut paths: Vec<_> = paths
+				.filter(|p| p.extension().map(|e| e == "toml").unwrap_or(false))
+				.collect();
+			paths.sort();
+			for path in paths {
+				let mut s = String::new();
+				if filesystem::open(ctx, &path).and_then(|mut file| Ok(file.read_to_string(&mut s)?)).is_err() {
+					continue;
+				}
+				if let Ok(theme) = Self::parse(&s) {
+					themes.retain(|t| t.name != theme.name);
+					themes.push(theme);
+				}
+			}
+		}
+		themes
+	}
+
+	pub fn liquid(&self, index: usize) -> Color {
+		self.palette.get(index).cloned().unwrap_or(self.tube_border)
+	}
+
+	// pct is 0.0 (0%) .. 1.0 (100%)
+	pub fn progress_color(&self, pct: f32) -> Color {
+		let last = self.hud_progress.len() - 1;
+		if pct >= 1.0 {
+			self.hud_progress[last]
+		} else {
+			self.hud_progress[((pct * last as f32).floor() as usize).min(last)]
+		}
+	}
+}
+
+impl Default for Theme {
+	fn default() -> Self {
+		Self::parse(BUILTIN_THEMES[0]).expect("invalid builtin theme")
+	}
+}